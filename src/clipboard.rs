@@ -0,0 +1,497 @@
+//! The stack operations that change the clipboard, written against `ClipboardBackend` rather than the Windows
+//! clipboard so that they don't depend on the platform (and can be tested without one)
+
+use crate::config::{Config, DuplicateAction, ThrottleMode};
+use crate::log_target;
+use crate::stack::{ClipboardItem, ClipboardStack};
+use crate::win::{ClipboardEntry, ClipboardText};
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+/// Somewhere the top of the stack can be shown. Each call is expected to leave the clipboard closed again
+pub trait ClipboardBackend {
+   type Error;
+
+   fn get_text(&mut self) -> Result<ClipboardText, Self::Error>;
+
+   /// Replaces everything on the clipboard with the entry
+   fn set(&mut self, entry: ClipboardEntry) -> Result<(), Self::Error>;
+
+   fn empty(&mut self) -> Result<(), Self::Error>;
+}
+
+/// What became of something that was copied
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capture {
+   /// A duplicate of the top of the stack, which was left as it was
+   Skipped,
+   /// A duplicate of the top of the stack, which took the place of the top (`duplicate_refreshes_position`)
+   Refreshed,
+   Pushed,
+   /// Took the place of the top of the stack, as there were too many copies in the last second
+   Replaced,
+   /// Left off the stack, as there were too many copies in the last second
+   Dropped,
+}
+
+/// What the last pop or clear took off the stack, so that it can be put back
+pub enum Undo {
+   Pop(ClipboardItem),
+   Clear(VecDeque<ClipboardItem>),
+}
+
+/// Shows the entry, or empties the clipboard if there isn't one
+fn show<C: ClipboardBackend>(clipboard: &mut C, entry: Option<ClipboardEntry>) -> Result<(), C::Error> {
+   match entry {
+      Some(entry) => clipboard.set(entry),
+      None => clipboard.empty(),
+   }
+}
+
+// Each of these sets the clipboard before touching the stack, so that nothing is lost if the clipboard can't be
+// opened, and trying again does the same thing
+
+pub fn pop<C: ClipboardBackend>(
+   clipboard: &mut C,
+   clipboard_stack: &mut ClipboardStack,
+   undo: &mut Option<Undo>,
+) -> Result<(), C::Error> {
+   let managing_clipboard = clipboard_stack.managing_clipboard();
+   let top_depth = if managing_clipboard { 1 } else { 0 };
+   let top = clipboard_stack.get(top_depth).map(|x| x.entry.clone());
+   let placed_top = top.is_some();
+   show(clipboard, top)?;
+   let popped = if managing_clipboard {
+      clipboard_stack.peek().cloned()
+   } else {
+      None
+   };
+   clipboard_stack.pop();
+   if let Some(popped) = popped {
+      *undo = Some(Undo::Pop(popped));
+      trace!(target: log_target::POP, "Popped element off clipboard stack")
+   }
+   if placed_top {
+      trace!(target: log_target::POP, "Placed top of stack in clipboard");
+   } else {
+      trace!(target: log_target::POP, "Nothing on stack to place in clipboard");
+   }
+   Ok(())
+}
+
+/// Places the top of the stack in the clipboard, leaving it on the stack
+pub fn paste_top<C: ClipboardBackend>(clipboard: &mut C, clipboard_stack: &mut ClipboardStack) -> Result<(), C::Error> {
+   let top = match clipboard_stack.peek() {
+      Some(top) => top.entry.clone(),
+      None => {
+         trace!(target: log_target::POP, "Nothing on stack to place in clipboard");
+         return Ok(());
+      }
+   };
+   clipboard.set(top)?;
+   clipboard_stack.set_managing_clipboard(true);
+   trace!(target: log_target::POP, "Placed top of stack in clipboard without popping");
+   Ok(())
+}
+
+/// Pushes the text on the clipboard, the same as if it had just been copied. The clipboard is left as it is
+pub fn push<C: ClipboardBackend>(
+   clipboard: &mut C,
+   clipboard_stack: &mut ClipboardStack,
+   config: &Config,
+) -> Result<(), C::Error> {
+   let text = clipboard.get_text()?;
+   let mut item = ClipboardItem::new(ClipboardEntry::Text(text, None));
+   if config.show_timestamps {
+      item.captured_at = Some(SystemTime::now());
+   }
+   if clipboard_stack.push(item, config.duplicate_action()) {
+      trace!(target: log_target::PUSH, "Pushed clipboard contents onto stack");
+   } else {
+      trace!(target: log_target::PUSH, "Ignoring push because it was a duplicate");
+   }
+   Ok(())
+}
+
+/// Puts something that was just copied (and is still on the clipboard) on the stack. `throttle` says whether there
+/// have been too many copies to push another. It isn't asked about skipped duplicates, so they don't count towards
+/// `max_pushes_per_second`
+pub fn capture(
+   clipboard_stack: &mut ClipboardStack,
+   config: &Config,
+   item: ClipboardItem,
+   throttle: impl FnOnce() -> bool,
+) -> Capture {
+   let duplicate_action = config.duplicate_action();
+   let duplicate = clipboard_stack.is_duplicate(&item.entry);
+   if duplicate && duplicate_action == DuplicateAction::Skip {
+      clipboard_stack.set_managing_clipboard(true);
+      trace!(target: log_target::PUSH, "Ignoring push because it was a duplicate");
+      return Capture::Skipped;
+   }
+   if throttle() {
+      if config.throttle_mode == ThrottleMode::Drop {
+         clipboard_stack.set_managing_clipboard(false);
+         return Capture::Dropped;
+      }
+      clipboard_stack.replace_top(item);
+      trace!(target: log_target::PUSH, "Replaced top of stack with clipboard contents");
+      return Capture::Replaced;
+   }
+   clipboard_stack.push(item, duplicate_action);
+   if duplicate && duplicate_action == DuplicateAction::Refresh {
+      trace!(target: log_target::PUSH, "Moved the duplicate to the top of the stack");
+      Capture::Refreshed
+   } else {
+      trace!(target: log_target::PUSH, "Pushed clipboard contents onto stack");
+      Capture::Pushed
+   }
+}
+
+pub fn clear<C: ClipboardBackend>(
+   clipboard: &mut C,
+   clipboard_stack: &mut ClipboardStack,
+   undo: &mut Option<Undo>,
+) -> Result<(), C::Error> {
+   clipboard.empty()?;
+   let cleared = clipboard_stack.clear();
+   if !cleared.is_empty() {
+      *undo = Some(Undo::Clear(cleared));
+   }
+   trace!(target: log_target::CLEAR, "Cleared stack");
+   Ok(())
+}
+
+/// Empties the stack except for its top, leaving the clipboard as it is
+pub fn clear_but_top(clipboard_stack: &mut ClipboardStack, undo: &mut Option<Undo>) {
+   let mut cleared = clipboard_stack.clear_but_top();
+   if cleared.is_empty() {
+      trace!(target: log_target::CLEAR, "Nothing below the top of the stack to clear");
+      return;
+   }
+   // Undoing puts the whole stack back, so the top goes back on above what was cleared
+   cleared.extend(clipboard_stack.peek().cloned());
+   *undo = Some(Undo::Clear(cleared));
+   trace!(target: log_target::CLEAR, "Cleared stack except for its top");
+}
+
+/// Puts back whatever the last pop or clear removed, and shows the top of the stack
+pub fn undo<C: ClipboardBackend>(
+   clipboard: &mut C,
+   clipboard_stack: &mut ClipboardStack,
+   undo: &mut Option<Undo>,
+) -> Result<(), C::Error> {
+   let top = match undo.as_ref() {
+      Some(Undo::Pop(item)) => Some(item),
+      Some(Undo::Clear(items)) => items.back(),
+      None => {
+         trace!(target: log_target::POP, "Nothing to undo");
+         return Ok(());
+      }
+   };
+   show(clipboard, top.map(|x| x.entry.clone()))?;
+   match undo.take() {
+      Some(Undo::Pop(item)) => {
+         // Pushed back even if it's a duplicate, since it was on the stack before
+         clipboard_stack.push(item, DuplicateAction::Push);
+         trace!(target: log_target::POP, "Undid pop");
+      }
+      Some(Undo::Clear(items)) => {
+         clipboard_stack.restore(items);
+         trace!(target: log_target::CLEAR, "Undid clear");
+      }
+      None => (),
+   }
+   Ok(())
+}
+
+pub fn swap<C: ClipboardBackend>(clipboard: &mut C, clipboard_stack: &mut ClipboardStack) -> Result<(), C::Error> {
+   if !clipboard_stack.managing_clipboard() {
+      trace!(target: log_target::SWAP, "Can't swap when the clipboard is not being managed by ripclip (the clipboard holds something that isn't on the stack)");
+      return Ok(());
+   }
+
+   // The item that will be on top after the swap
+   match clipboard_stack.get(1).map(|x| x.entry.clone()) {
+      Some(second) => {
+         clipboard.set(second)?;
+         clipboard_stack.swap_top();
+         trace!(target: log_target::SWAP, "Swapped top 2 elements of stack");
+      }
+      None => trace!(target: log_target::SWAP, "Stack too small to swap"),
+   }
+   Ok(())
+}
+
+/// Whether a clipboard update at `sequence_number` is the one caused by our own last write, which left the sequence
+/// number at `last_write`. Anything that changed the clipboard after us moves the number on, and is captured
+pub fn is_own_write(last_write: Option<u32>, sequence_number: u32) -> bool {
+   last_write == Some(sequence_number)
+}
+
+#[cfg(test)]
+mod test {
+   use super::*;
+
+   /// Holds whatever was last set. While `locked`, every call fails as if another application had the clipboard open.
+   /// `sequence_number` counts changes, like the Windows clipboard sequence number
+   #[derive(Default)]
+   struct MockClipboard {
+      contents: Option<ClipboardEntry>,
+      locked: bool,
+      sequence_number: u32,
+   }
+
+   impl ClipboardBackend for MockClipboard {
+      type Error = ();
+
+      fn get_text(&mut self) -> Result<ClipboardText, ()> {
+         if self.locked {
+            return Err(());
+         }
+         match &self.contents {
+            Some(ClipboardEntry::Text(text, _)) => Ok(text.clone()),
+            _ => Err(()),
+         }
+      }
+
+      fn set(&mut self, entry: ClipboardEntry) -> Result<(), ()> {
+         if self.locked {
+            return Err(());
+         }
+         self.contents = Some(entry);
+         self.sequence_number += 1;
+         Ok(())
+      }
+
+      fn empty(&mut self) -> Result<(), ()> {
+         if self.locked {
+            return Err(());
+         }
+         self.contents = None;
+         self.sequence_number += 1;
+         Ok(())
+      }
+   }
+
+   fn text(s: &str) -> ClipboardText {
+      ClipboardText::from_str(s)
+   }
+
+   /// Pushes the items, bottom first, as if each had just been copied
+   fn stack_of(items: &[&str]) -> ClipboardStack {
+      let mut stack = ClipboardStack::new(None);
+      for s in items.iter() {
+         stack.push(
+            ClipboardItem::new(ClipboardEntry::Text(text(s), None)),
+            DuplicateAction::Push,
+         );
+      }
+      stack.set_managing_clipboard(true);
+      stack
+   }
+
+   fn contents(stack: &ClipboardStack) -> Vec<String> {
+      stack
+         .iter()
+         .map(|(_, x)| x.entry.as_text().unwrap().to_string())
+         .collect()
+   }
+
+   fn clipboard_text(clipboard: &mut MockClipboard) -> Option<String> {
+      clipboard.get_text().ok().map(|x| x.to_string())
+   }
+
+   #[test]
+   fn pop_shows_the_next_item() {
+      let mut clipboard = MockClipboard::default();
+      let mut stack = stack_of(&["a", "b"]);
+      pop(&mut clipboard, &mut stack, &mut None).unwrap();
+      assert_eq!(contents(&stack), vec!["a"]);
+      assert_eq!(clipboard_text(&mut clipboard).as_deref(), Some("a"));
+      pop(&mut clipboard, &mut stack, &mut None).unwrap();
+      assert!(stack.is_empty());
+      assert!(clipboard.contents.is_none());
+   }
+
+   #[test]
+   fn pop_restores_the_top_when_not_managing() {
+      let mut clipboard = MockClipboard::default();
+      let mut stack = stack_of(&["a", "b"]);
+      stack.set_managing_clipboard(false);
+      pop(&mut clipboard, &mut stack, &mut None).unwrap();
+      assert_eq!(contents(&stack), vec!["b", "a"]);
+      assert_eq!(clipboard_text(&mut clipboard).as_deref(), Some("b"));
+      assert!(stack.managing_clipboard());
+   }
+
+   #[test]
+   fn paste_top_leaves_the_stack_alone() {
+      let mut clipboard = MockClipboard::default();
+      let mut stack = stack_of(&["a", "b"]);
+      stack.set_managing_clipboard(false);
+      paste_top(&mut clipboard, &mut stack).unwrap();
+      assert_eq!(contents(&stack), vec!["b", "a"]);
+      assert_eq!(clipboard_text(&mut clipboard).as_deref(), Some("b"));
+      assert!(stack.managing_clipboard());
+   }
+
+   #[test]
+   fn the_update_caused_by_a_clear_is_not_captured() {
+      let mut clipboard = MockClipboard::default();
+      let mut stack = stack_of(&["a", "b"]);
+      clear(&mut clipboard, &mut stack, &mut None).unwrap();
+      let last_write = Some(clipboard.sequence_number);
+      // The update for the clear arrives after it, with the clipboard still empty
+      if !is_own_write(last_write, clipboard.sequence_number) {
+         capture(&mut stack, &Config::default(), item_from("", "ripclip.exe"), || false);
+      }
+      assert!(stack.is_empty());
+      // A copy after the clear has moved the sequence number on
+      clipboard.set(ClipboardEntry::Text(text("c"), None)).unwrap();
+      assert!(!is_own_write(last_write, clipboard.sequence_number));
+   }
+
+   #[test]
+   fn swap_shows_the_second_item() {
+      let mut clipboard = MockClipboard::default();
+      let mut stack = stack_of(&["a", "b"]);
+      swap(&mut clipboard, &mut stack).unwrap();
+      assert_eq!(contents(&stack), vec!["a", "b"]);
+      assert_eq!(clipboard_text(&mut clipboard).as_deref(), Some("a"));
+   }
+
+   #[test]
+   fn push_takes_the_clipboard() {
+      let mut clipboard = MockClipboard::default();
+      clipboard.set(ClipboardEntry::Text(text("c"), None)).unwrap();
+      let mut stack = stack_of(&["a", "b"]);
+      stack.set_managing_clipboard(false);
+      let config = Config {
+         prevent_duplicate_push: true,
+         ..Config::default()
+      };
+      push(&mut clipboard, &mut stack, &config).unwrap();
+      assert_eq!(contents(&stack), vec!["c", "b", "a"]);
+      assert!(stack.managing_clipboard());
+      push(&mut clipboard, &mut stack, &config).unwrap();
+      assert_eq!(contents(&stack), vec!["c", "b", "a"]);
+   }
+
+   fn item_from(s: &str, source: &str) -> ClipboardItem {
+      let mut item = ClipboardItem::new(ClipboardEntry::Text(text(s), None));
+      item.source = Some(source.to_string());
+      item
+   }
+
+   #[test]
+   fn capture_skips_or_refreshes_duplicates() {
+      let skip = Config {
+         prevent_duplicate_push: true,
+         ..Config::default()
+      };
+      let mut stack = stack_of(&["a", "b"]);
+      stack.set_managing_clipboard(false);
+      let result = capture(&mut stack, &skip, item_from("b", "second.exe"), || unreachable!());
+      assert_eq!(result, Capture::Skipped);
+      assert_eq!(contents(&stack), vec!["b", "a"]);
+      assert_eq!(stack.peek().unwrap().source, None);
+      assert!(stack.managing_clipboard());
+
+      let refresh = Config {
+         duplicate_refreshes_position: true,
+         ..Config::default()
+      };
+      let mut stack = stack_of(&["a", "b"]);
+      let result = capture(&mut stack, &refresh, item_from("b", "second.exe"), || false);
+      assert_eq!(result, Capture::Refreshed);
+      assert_eq!(contents(&stack), vec!["b", "a"]);
+      assert_eq!(stack.peek().unwrap().source.as_deref(), Some("second.exe"));
+
+      let result = capture(&mut stack, &refresh, item_from("c", "second.exe"), || false);
+      assert_eq!(result, Capture::Pushed);
+      assert_eq!(contents(&stack), vec!["c", "b", "a"]);
+   }
+
+   #[test]
+   fn capture_throttles() {
+      let mut config = Config::default();
+      let mut stack = stack_of(&["a", "b"]);
+      let result = capture(&mut stack, &config, item_from("c", "x.exe"), || true);
+      assert_eq!(result, Capture::Replaced);
+      assert_eq!(contents(&stack), vec!["c", "a"]);
+
+      config.throttle_mode = ThrottleMode::Drop;
+      let result = capture(&mut stack, &config, item_from("d", "x.exe"), || true);
+      assert_eq!(result, Capture::Dropped);
+      assert_eq!(contents(&stack), vec!["c", "a"]);
+      assert!(!stack.managing_clipboard());
+   }
+
+   #[test]
+   fn clear_empties_both() {
+      let mut clipboard = MockClipboard::default();
+      clipboard.set(ClipboardEntry::Text(text("b"), None)).unwrap();
+      let mut stack = stack_of(&["a", "b"]);
+      clear(&mut clipboard, &mut stack, &mut None).unwrap();
+      assert!(stack.is_empty());
+      assert!(clipboard.contents.is_none());
+   }
+
+   #[test]
+   fn clear_but_top_can_be_undone() {
+      let mut clipboard = MockClipboard::default();
+      let mut stack = stack_of(&["a", "b", "c"]);
+      let mut removed = None;
+      clear_but_top(&mut stack, &mut removed);
+      assert_eq!(contents(&stack), vec!["c"]);
+      assert!(stack.managing_clipboard());
+      undo(&mut clipboard, &mut stack, &mut removed).unwrap();
+      assert_eq!(contents(&stack), vec!["c", "b", "a"]);
+      assert_eq!(clipboard_text(&mut clipboard).as_deref(), Some("c"));
+   }
+
+   #[test]
+   fn undo_restores_the_last_pop() {
+      let mut clipboard = MockClipboard::default();
+      let mut stack = stack_of(&["a", "b", "c"]);
+      let mut removed = None;
+      pop(&mut clipboard, &mut stack, &mut removed).unwrap();
+      pop(&mut clipboard, &mut stack, &mut removed).unwrap();
+      undo(&mut clipboard, &mut stack, &mut removed).unwrap();
+      assert_eq!(contents(&stack), vec!["b", "a"]);
+      assert_eq!(clipboard_text(&mut clipboard).as_deref(), Some("b"));
+      assert!(removed.is_none());
+      undo(&mut clipboard, &mut stack, &mut removed).unwrap();
+      assert_eq!(contents(&stack), vec!["b", "a"]);
+   }
+
+   #[test]
+   fn undo_restores_a_clear() {
+      let mut clipboard = MockClipboard::default();
+      let mut stack = stack_of(&["a", "b"]);
+      let mut removed = None;
+      clear(&mut clipboard, &mut stack, &mut removed).unwrap();
+      // Clearing an empty stack doesn't lose what the first clear removed
+      clear(&mut clipboard, &mut stack, &mut removed).unwrap();
+      undo(&mut clipboard, &mut stack, &mut removed).unwrap();
+      assert_eq!(contents(&stack), vec!["b", "a"]);
+      assert_eq!(clipboard_text(&mut clipboard).as_deref(), Some("b"));
+      assert!(stack.managing_clipboard());
+   }
+
+   #[test]
+   fn failures_leave_the_stack_alone() {
+      let mut clipboard = MockClipboard::default();
+      clipboard.set(ClipboardEntry::Text(text("b"), None)).unwrap();
+      clipboard.locked = true;
+      let mut stack = stack_of(&["a", "b"]);
+      assert!(pop(&mut clipboard, &mut stack, &mut None).is_err());
+      assert!(swap(&mut clipboard, &mut stack).is_err());
+      assert!(clear(&mut clipboard, &mut stack, &mut None).is_err());
+      assert_eq!(contents(&stack), vec!["b", "a"]);
+      assert!(stack.managing_clipboard());
+      clipboard.locked = false;
+      assert_eq!(clipboard_text(&mut clipboard).as_deref(), Some("b"));
+   }
+}
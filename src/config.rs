@@ -1,42 +1,653 @@
 use crate::win;
 use dirs;
+use std::error;
 use std::fmt;
 use std::fs::{self, File};
 use std::io::{self, BufRead, BufReader, Write};
 use std::num::ParseIntError;
+use std::path::{Path, PathBuf};
 use std::usize;
 
+/// When a file with this name is next to the executable, ripclip runs in portable mode
+const PORTABLE_MARKER: &str = "ripclip.portable";
+
+const BUNDLE_HEADER: &str = "# ripclip bundle";
+const BUNDLE_CONFIG_SECTION: &str = "[config]";
+
 const DEFAULT_CONFIG: &[u8] = b"\
+# ripclip configuration. Lines starting with # are ignored.
+# Keybindings are modifiers and a key joined with +, like Control + Shift + C, or None.
+# Add [norepeat] to the end of a keybinding to stop it repeating while held.
+
+# The most items the stack holds before the oldest is dropped, or None for no limit
 max_stack_size = 100
+# Copies (text or images) bigger than this many bytes are not pushed, or None for no limit
+max_item_bytes = 50000000
+# Clipboard data bigger than this many bytes is never read, or None for no limit. Guards against running out of memory
+max_read_bytes = 268435456
 show_tray_icon = true
+# An .ico file to use for the tray icon instead of the built in one, or None
+tray_icon_path = None
+
 pop_keybinding = Control + Shift + C
+# When false, the pop keybinding places the top of the stack in the clipboard without removing it
+pop_consumes = true
 swap_keybinding = None
 clear_keybinding = None
+# Alternates the clipboard between the top two items, without reordering the stack
+pingpong_keybinding = None
+# Removes duplicate items from the stack, keeping the most recent copy of each
+dedupe_keybinding = None
+# Places the next item down the stack in the clipboard, without removing anything, wrapping around at the bottom
+peek_keybinding = None
+# Places the item this far down the stack (1 is the top) in the clipboard, without removing anything
+select_1_keybinding = None
+select_2_keybinding = None
+select_3_keybinding = None
+select_4_keybinding = None
+select_5_keybinding = None
+select_6_keybinding = None
+select_7_keybinding = None
+select_8_keybinding = None
+select_9_keybinding = None
+# Pops into the window whose title contains pop_to_window_title, bringing it to the front and pasting there
+pop_to_window_keybinding = None
+pop_to_window_title = None
+# Pushes the text on the clipboard onto the stack, for when it wasn't captured as it was copied
+push_keybinding = None
+# Puts back whatever the last pop or clear removed, until something new is copied
+undo_keybinding = None
+# Opens a list of the whole stack to pick an item from
+browse_keybinding = None
+# Pauses capturing copies until it's pressed again
+pause_keybinding = None
+# Clears everything except the top of the stack, leaving the clipboard as it is
+clear_but_top_keybinding = None
+
+# Ignore copies of the item already on top of the stack
 prevent_duplicate_push = false
+# Copying the top item again replaces it, refreshing its source and timestamp
+duplicate_refreshes_position = false
+# none, adjacent (the same as prevent_duplicate_push) or global, where copying anything already on the stack
+# moves it to the top
+dedup_mode = none
+
+# Remember which application each item was copied from, and show it in the History submenu
+track_source = false
+# Show how long ago each item was copied in the History submenu
+show_timestamps = false
+# Preview multi-line items as their first line and a count of the rest
+multiline_preview_summary = true
+# Show the number of lines and characters of each text item in the History submenu
+show_entry_stats = false
+# Order the History submenu by most recently copied (recent) or most often used (frequent)
+history_order = recent
+
+# Don't push text that is empty or only whitespace, like an accidental copy of a blank selection
+ignore_whitespace_only = false
+# Don't push text matching this regular expression, or None. Unlike other values, this is case sensitive
+ignore_pattern = None
+# Don't push text that looks like binary data, according to the two thresholds below (either can be None)
+skip_binary_like = false
+# The percentage of control characters above which text looks binary
+binary_control_percent = 10
+# The length of a run without whitespace above which text looks binary
+binary_token_length = 1000
+
+# Comma separated executable names, like notepad.exe. If any are listed, only copies from them are pushed
+capture_only_from = None
+# Comma separated executable names whose copies are never pushed
+ignore_processes = None
+# Copies made while this modifier (alt, control, shift or win) is held are not pushed, or None.
+# Watching the modifier adds a little latency to every key press, see the Readme
+skip_stack_modifier = None
+
+# Switch the tray icon to a warning icon while the stack is at max_stack_size
+indicate_full = false
+# What double-clicking the tray icon does: pop, swap, menu or nothing
+tray_double_click = nothing
+
+# Put the top of the stack back if remote desktop empties the clipboard right after ripclip sets it
+rdp_resilience = false
+# Clear the stack when another application empties the clipboard, like clearing the Win + V history does (best effort)
+respect_os_history = false
+# Comma separated Windows error codes that mean another application has the clipboard open, so opening it is retried
+clipboard_retry_errors = 5, 170, 1460
+# The monitor number (from 1) to always show the menu on, or None for the monitor under the cursor
+popup_monitor = None
+
+# The most copies pushed in any one second, or None for no limit.
+# Extra copies either replace the top of the stack (coalesce) or are not pushed (drop)
+max_pushes_per_second = None
+throttle_mode = coalesce
+
+# When monitoring resumes, push whatever was copied last while it was paused
+capture_current_on_resume = false
+# Empty the clipboard (but not the stack) after this many seconds without a push or pop, or None to never
+clipboard_auto_clear_seconds = None
+# Keep everything copied alongside the text (like formatting) on the clipboard after capturing it
+preserve_other_formats = false
+# Serve GET /stack and POST /push, /pop and /clear on 127.0.0.1 at http_port. Needs a build with the http feature.
+# Requests must send http_token in an X-Ripclip-Token header, and the server doesn't start without one.
+# Only takes effect when ripclip starts
+enable_http = false
+http_port = 7171
+http_token = None
+# message_only or hidden. A hidden window receives system notifications that a message-only one misses.
+# Only takes effect when ripclip starts
+window_type = message_only
+# Start ripclip when you log in to Windows. Only takes effect when ripclip starts
+start_with_windows = false
 ";
 
 #[derive(Debug, PartialEq)]
 pub struct Config {
    pub max_stack_size: Option<usize>,
+   /// Compared against the size of the item as it is stored, so text counts 2 bytes per UTF-16 unit
+   pub max_item_bytes: Option<usize>,
+   /// Checked against the size of the clipboard's memory before anything is copied out of it
+   pub max_read_bytes: Option<usize>,
    pub show_tray_icon: bool,
+   /// Lowercase, like every other value
+   pub tray_icon_path: Option<PathBuf>,
    pub pop_keybinding: Option<Hotkey>,
+   pub pop_consumes: bool,
    pub clear_keybinding: Option<Hotkey>,
    pub swap_keybinding: Option<Hotkey>,
+   pub pingpong_keybinding: Option<Hotkey>,
+   pub dedupe_keybinding: Option<Hotkey>,
+   pub peek_keybinding: Option<Hotkey>,
+   /// `select_1_keybinding` through `select_9_keybinding`, for the items at depth 0 through 8
+   pub select_keybindings: [Option<Hotkey>; 9],
+   pub pop_to_window_keybinding: Option<Hotkey>,
+   /// Lowercase, since the whole configuration file is. Matched against part of the window title, ignoring case
+   pub pop_to_window_title: Option<String>,
+   pub push_keybinding: Option<Hotkey>,
+   pub undo_keybinding: Option<Hotkey>,
+   pub browse_keybinding: Option<Hotkey>,
+   pub pause_keybinding: Option<Hotkey>,
+   pub clear_but_top_keybinding: Option<Hotkey>,
    pub prevent_duplicate_push: bool,
+   /// Copying the top of the stack again replaces it, refreshing its source and timestamp
+   pub duplicate_refreshes_position: bool,
+   pub dedup_mode: DedupMode,
+   pub track_source: bool,
+   pub show_timestamps: bool,
+   pub multiline_preview_summary: bool,
+   pub show_entry_stats: bool,
+   pub history_order: HistoryOrder,
+   pub ignore_whitespace_only: bool,
+   pub ignore_pattern: Option<IgnorePattern>,
+   pub skip_binary_like: bool,
+   /// Text with more than this percentage of control characters is considered binary
+   pub binary_control_percent: Option<usize>,
+   /// Text containing a run of non-whitespace longer than this many characters is considered binary
+   pub binary_token_length: Option<usize>,
+   /// Lowercase executable names. If not empty, copies from any other process are not pushed
+   pub capture_only_from: Vec<String>,
+   /// Lowercase executable names. Copies from these processes are not pushed (unless also in `capture_only_from`)
+   pub ignore_processes: Vec<String>,
+   /// Copies made while this is held are not pushed. Watched with a low level keyboard hook
+   pub skip_stack_modifier: Option<win::Modifiers>,
+   /// Show a different tray icon while the stack is at `max_stack_size`
+   pub indicate_full: bool,
+   pub tray_double_click: TrayAction,
+   /// Put the top of the stack back if the clipboard is emptied right after ripclip sets it
+   pub rdp_resilience: bool,
+   /// Clear the stack when another application empties the clipboard, as clearing the system clipboard history does
+   pub respect_os_history: bool,
+   /// Windows error codes from opening the clipboard that are worth retrying. Others fail immediately
+   pub clipboard_retry_errors: Vec<u32>,
+   /// 1-based index of the monitor to always show the menu on. Otherwise, the menu shows up on the monitor under the cursor
+   pub popup_monitor: Option<usize>,
+   pub max_pushes_per_second: Option<usize>,
+   /// What happens to pushes beyond `max_pushes_per_second`
+   pub throttle_mode: ThrottleMode,
+   /// When monitoring resumes, push whatever was copied last while it was paused
+   pub capture_current_on_resume: bool,
+   /// Empty the clipboard after this long without a push or pop. The stack is left alone, so popping restores the top
+   pub clipboard_auto_clear_seconds: Option<usize>,
+   /// When capturing text, put back everything else that was on the clipboard with it
+   pub preserve_other_formats: bool,
+   /// Only read at startup, and only used when built with the `http` feature
+   pub enable_http: bool,
+   pub http_port: u16,
+   /// The secret every control request must carry. Case sensitive
+   pub http_token: Option<String>,
+   /// Only read at startup
+   pub window_type: WindowType,
+   /// Only read at startup
+   pub start_with_windows: bool,
+}
+
+/// The kind of window ripclip receives messages with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WindowType {
+   /// Can't be found by other applications, but doesn't receive broadcasts
+   /// (like the taskbar being recreated, or settings changing)
+   MessageOnly,
+   /// A top-level window that is never shown, which does receive broadcasts
+   Hidden,
+}
+
+impl fmt::Display for WindowType {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self {
+         WindowType::MessageOnly => write!(f, "message_only"),
+         WindowType::Hidden => write!(f, "hidden"),
+      }
+   }
+}
+
+/// What clicking the tray icon does
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrayAction {
+   Pop,
+   Swap,
+   Menu,
+   Nothing,
+}
+
+impl fmt::Display for TrayAction {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self {
+         TrayAction::Pop => write!(f, "pop"),
+         TrayAction::Swap => write!(f, "swap"),
+         TrayAction::Menu => write!(f, "menu"),
+         TrayAction::Nothing => write!(f, "nothing"),
+      }
+   }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HistoryOrder {
+   /// Top of the stack first
+   Recent,
+   /// Most popped, cut and swapped to the top first. Ties are broken by recency
+   Frequent,
+}
+
+impl fmt::Display for HistoryOrder {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self {
+         HistoryOrder::Recent => write!(f, "recent"),
+         HistoryOrder::Frequent => write!(f, "frequent"),
+      }
+   }
+}
+
+/// A regular expression for text that shouldn't be pushed. Patterns are equal if they were written the same way
+#[derive(Debug)]
+pub struct IgnorePattern(regex::Regex);
+
+impl IgnorePattern {
+   pub fn new(pattern: &str) -> Result<IgnorePattern, regex::Error> {
+      regex::Regex::new(pattern).map(IgnorePattern)
+   }
+
+   pub fn is_match(&self, text: &str) -> bool {
+      self.0.is_match(text)
+   }
+}
+
+impl PartialEq for IgnorePattern {
+   fn eq(&self, other: &IgnorePattern) -> bool {
+      self.0.as_str() == other.0.as_str()
+   }
+}
+
+impl fmt::Display for IgnorePattern {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "{}", self.0.as_str())
+   }
+}
+
+/// Which items on the stack a copy is checked against for being a duplicate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DedupMode {
+   /// Duplicates are pushed like anything else (unless `prevent_duplicate_push` is set)
+   None,
+   /// Only the top of the stack
+   Adjacent,
+   /// Every item, and the old copy is removed
+   Global,
+}
+
+impl fmt::Display for DedupMode {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self {
+         DedupMode::None => write!(f, "none"),
+         DedupMode::Adjacent => write!(f, "adjacent"),
+         DedupMode::Global => write!(f, "global"),
+      }
+   }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThrottleMode {
+   /// The item replaces the top of the stack
+   Coalesce,
+   /// The item isn't pushed at all
+   Drop,
+}
+
+impl fmt::Display for ThrottleMode {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self {
+         ThrottleMode::Coalesce => write!(f, "coalesce"),
+         ThrottleMode::Drop => write!(f, "drop"),
+      }
+   }
+}
+
+/// What to do when the clipboard is set to the same text as the top of the stack
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DuplicateAction {
+   Push,
+   Skip,
+   /// Remove the top of the stack and push the copy in its place
+   Refresh,
+   /// Remove the same item from anywhere in the stack, not just the top, and push the copy
+   MoveToTop,
+}
+
+impl Config {
+   pub fn duplicate_action(&self) -> DuplicateAction {
+      if self.dedup_mode == DedupMode::Global {
+         DuplicateAction::MoveToTop
+      } else if self.duplicate_refreshes_position {
+         DuplicateAction::Refresh
+      } else if self.dedup_mode == DedupMode::Adjacent || self.prevent_duplicate_push {
+         DuplicateAction::Skip
+      } else {
+         DuplicateAction::Push
+      }
+   }
+
+   /// `process` is the lowercase executable name of the process a copy came from, if it could be determined
+   pub fn should_capture_from(&self, process: Option<&str>) -> bool {
+      let allowed = process.is_some_and(|p| self.capture_only_from.iter().any(|x| x == p));
+      if allowed {
+         return true;
+      }
+      if !self.capture_only_from.is_empty() {
+         return false;
+      }
+      !process.is_some_and(|p| self.ignore_processes.iter().any(|x| x == p))
+   }
+
+   /// Every keybinding, by its option name
+   pub fn keybindings_mut(&mut self) -> Vec<(String, &mut Option<Hotkey>)> {
+      let mut keybindings = vec![
+         ("pop_keybinding".to_owned(), &mut self.pop_keybinding),
+         ("swap_keybinding".to_owned(), &mut self.swap_keybinding),
+         ("clear_keybinding".to_owned(), &mut self.clear_keybinding),
+         ("pingpong_keybinding".to_owned(), &mut self.pingpong_keybinding),
+         ("dedupe_keybinding".to_owned(), &mut self.dedupe_keybinding),
+         ("peek_keybinding".to_owned(), &mut self.peek_keybinding),
+         (
+            "pop_to_window_keybinding".to_owned(),
+            &mut self.pop_to_window_keybinding,
+         ),
+         ("push_keybinding".to_owned(), &mut self.push_keybinding),
+         ("undo_keybinding".to_owned(), &mut self.undo_keybinding),
+         ("browse_keybinding".to_owned(), &mut self.browse_keybinding),
+         ("pause_keybinding".to_owned(), &mut self.pause_keybinding),
+         (
+            "clear_but_top_keybinding".to_owned(),
+            &mut self.clear_but_top_keybinding,
+         ),
+      ];
+      for (i, keybinding) in self.select_keybindings.iter_mut().enumerate() {
+         keybindings.push((format!("select_{}_keybinding", i + 1), keybinding));
+      }
+      keybindings
+   }
+
+   /// Replaces every keybinding with the one from `other`, along with `pop_to_window_title` (which says what
+   /// `pop_to_window_keybinding` does), leaving the rest of the configuration alone
+   pub fn take_keybindings(&mut self, mut other: Config) {
+      for ((_, keybinding), (_, new_keybinding)) in self.keybindings_mut().into_iter().zip(other.keybindings_mut()) {
+         *keybinding = new_keybinding.take();
+      }
+      self.pop_to_window_title = other.pop_to_window_title;
+   }
+}
+
+fn optional_usize_string(value: Option<usize>) -> String {
+   match value {
+      Some(value) => value.to_string(),
+      None => "None".to_owned(),
+   }
+}
+
+fn hotkey_string(hotkey: &Option<Hotkey>) -> String {
+   match hotkey {
+      Some(hotkey) => hotkey.to_string(),
+      None => "None".to_owned(),
+   }
+}
+
+fn list_string(list: &[String]) -> String {
+   if list.is_empty() {
+      "None".to_owned()
+   } else {
+      list.join(", ")
+   }
+}
+
+fn error_codes_string(codes: &[u32]) -> String {
+   list_string(&codes.iter().map(u32::to_string).collect::<Vec<_>>())
+}
+
+impl Config {
+   /// Every option in the `key = value` form that `parse_config` accepts
+   pub fn to_config_string(&self) -> String {
+      let options = [
+         ("max_stack_size", optional_usize_string(self.max_stack_size)),
+         ("max_item_bytes", optional_usize_string(self.max_item_bytes)),
+         ("max_read_bytes", optional_usize_string(self.max_read_bytes)),
+         ("show_tray_icon", self.show_tray_icon.to_string()),
+         (
+            "tray_icon_path",
+            self
+               .tray_icon_path
+               .as_ref()
+               .map_or_else(|| "None".to_owned(), |x| x.display().to_string()),
+         ),
+         ("pop_keybinding", hotkey_string(&self.pop_keybinding)),
+         ("pop_consumes", self.pop_consumes.to_string()),
+         ("swap_keybinding", hotkey_string(&self.swap_keybinding)),
+         ("clear_keybinding", hotkey_string(&self.clear_keybinding)),
+         ("pingpong_keybinding", hotkey_string(&self.pingpong_keybinding)),
+         ("dedupe_keybinding", hotkey_string(&self.dedupe_keybinding)),
+         ("peek_keybinding", hotkey_string(&self.peek_keybinding)),
+         (
+            "pop_to_window_keybinding",
+            hotkey_string(&self.pop_to_window_keybinding),
+         ),
+         (
+            "pop_to_window_title",
+            self.pop_to_window_title.clone().unwrap_or_else(|| "None".to_owned()),
+         ),
+         ("push_keybinding", hotkey_string(&self.push_keybinding)),
+         ("undo_keybinding", hotkey_string(&self.undo_keybinding)),
+         ("browse_keybinding", hotkey_string(&self.browse_keybinding)),
+         ("pause_keybinding", hotkey_string(&self.pause_keybinding)),
+         (
+            "clear_but_top_keybinding",
+            hotkey_string(&self.clear_but_top_keybinding),
+         ),
+         ("prevent_duplicate_push", self.prevent_duplicate_push.to_string()),
+         (
+            "duplicate_refreshes_position",
+            self.duplicate_refreshes_position.to_string(),
+         ),
+         ("dedup_mode", self.dedup_mode.to_string()),
+         ("track_source", self.track_source.to_string()),
+         ("show_timestamps", self.show_timestamps.to_string()),
+         ("multiline_preview_summary", self.multiline_preview_summary.to_string()),
+         ("show_entry_stats", self.show_entry_stats.to_string()),
+         ("history_order", self.history_order.to_string()),
+         ("ignore_whitespace_only", self.ignore_whitespace_only.to_string()),
+         (
+            "ignore_pattern",
+            self
+               .ignore_pattern
+               .as_ref()
+               .map_or_else(|| "None".to_owned(), |x| x.to_string()),
+         ),
+         ("skip_binary_like", self.skip_binary_like.to_string()),
+         (
+            "binary_control_percent",
+            optional_usize_string(self.binary_control_percent),
+         ),
+         ("binary_token_length", optional_usize_string(self.binary_token_length)),
+         ("capture_only_from", list_string(&self.capture_only_from)),
+         ("ignore_processes", list_string(&self.ignore_processes)),
+         (
+            "skip_stack_modifier",
+            self
+               .skip_stack_modifier
+               .map_or_else(|| "None".to_owned(), |x| x.to_string()),
+         ),
+         ("indicate_full", self.indicate_full.to_string()),
+         ("tray_double_click", self.tray_double_click.to_string()),
+         ("rdp_resilience", self.rdp_resilience.to_string()),
+         ("respect_os_history", self.respect_os_history.to_string()),
+         (
+            "clipboard_retry_errors",
+            error_codes_string(&self.clipboard_retry_errors),
+         ),
+         ("popup_monitor", optional_usize_string(self.popup_monitor)),
+         (
+            "max_pushes_per_second",
+            optional_usize_string(self.max_pushes_per_second),
+         ),
+         ("throttle_mode", self.throttle_mode.to_string()),
+         ("capture_current_on_resume", self.capture_current_on_resume.to_string()),
+         (
+            "clipboard_auto_clear_seconds",
+            optional_usize_string(self.clipboard_auto_clear_seconds),
+         ),
+         ("preserve_other_formats", self.preserve_other_formats.to_string()),
+         ("enable_http", self.enable_http.to_string()),
+         ("http_port", self.http_port.to_string()),
+         (
+            "http_token",
+            self.http_token.clone().unwrap_or_else(|| "None".to_owned()),
+         ),
+         ("window_type", self.window_type.to_string()),
+         ("start_with_windows", self.start_with_windows.to_string()),
+      ];
+      let mut config_string = String::new();
+      for (key, value) in options.iter() {
+         config_string.push_str(&format!("{} = {}\n", key, value));
+      }
+      for (i, hotkey) in self.select_keybindings.iter().enumerate() {
+         config_string.push_str(&format!("select_{}_keybinding = {}\n", i + 1, hotkey_string(hotkey)));
+      }
+      config_string
+   }
+
+   /// Writes every option to `path`, replacing whatever is there.
+   /// Comments and the order of options in an existing file are not kept. Keybindings set through environment
+   /// variables are written as the file already had them, so that saving doesn't make an override permanent
+   pub fn write_to(&self, path: &Path) -> io::Result<()> {
+      // If the file can't be read, overridden keybindings are saved as unset
+      let file = read_config(path).ok().flatten().unwrap_or_default();
+      fs::write(path, self.to_saved_string(&file, |name| std::env::var(name).ok()))?;
+      info!("Wrote configuration to {:#?}", path);
+      Ok(())
+   }
+
+   /// `to_config_string`, with the keybindings that `var` overrides taken from `file` instead
+   fn to_saved_string<F>(&self, file: &Config, var: F) -> String
+   where
+      F: Fn(&str) -> Option<String>,
+   {
+      let file_string = file.to_config_string();
+      let mut config_string = String::new();
+      for line in self.to_config_string().lines() {
+         let option = line.split(" = ").next().unwrap_or("");
+         // Each keybinding's variable is its option in capitals, as in `apply_keybinding_overrides`
+         let overridden =
+            option.ends_with("_keybinding") && var(&format!("RIPCLIP_{}", option.to_ascii_uppercase())).is_some();
+         let line = match file_string.lines().find(|x| x.split(" = ").next() == Some(option)) {
+            Some(file_line) if overridden => file_line,
+            _ => line,
+         };
+         config_string.push_str(line);
+         config_string.push('\n');
+      }
+      config_string
+   }
 }
 
 impl Default for Config {
    fn default() -> Config {
       Config {
          max_stack_size: Some(100),
+         max_item_bytes: Some(50_000_000),
+         max_read_bytes: Some(256 * 1024 * 1024),
          show_tray_icon: true,
+         tray_icon_path: None,
          pop_keybinding: Some(Hotkey {
             key: win::VirtualKey::C,
             modifiers: win::Modifiers::CONTROL | win::Modifiers::SHIFT,
+            repeat: true,
          }),
+         pop_consumes: true,
          clear_keybinding: None,
          swap_keybinding: None,
+         pingpong_keybinding: None,
+         dedupe_keybinding: None,
+         peek_keybinding: None,
+         select_keybindings: Default::default(),
+         pop_to_window_keybinding: None,
+         pop_to_window_title: None,
+         push_keybinding: None,
+         undo_keybinding: None,
+         browse_keybinding: None,
+         pause_keybinding: None,
+         clear_but_top_keybinding: None,
          prevent_duplicate_push: false,
+         duplicate_refreshes_position: false,
+         dedup_mode: DedupMode::None,
+         track_source: false,
+         show_timestamps: false,
+         multiline_preview_summary: true,
+         show_entry_stats: false,
+         history_order: HistoryOrder::Recent,
+         ignore_whitespace_only: false,
+         ignore_pattern: None,
+         skip_binary_like: false,
+         binary_control_percent: Some(10),
+         binary_token_length: Some(1000),
+         capture_only_from: Vec::new(),
+         ignore_processes: Vec::new(),
+         skip_stack_modifier: None,
+         indicate_full: false,
+         tray_double_click: TrayAction::Nothing,
+         rdp_resilience: false,
+         respect_os_history: false,
+         // Access denied, busy and timed out
+         clipboard_retry_errors: vec![
+            win::ERROR_ACCESS_DENIED.code(),
+            win::ERROR_BUSY.code(),
+            win::ERROR_TIMEOUT.code(),
+         ],
+         popup_monitor: None,
+         max_pushes_per_second: None,
+         throttle_mode: ThrottleMode::Coalesce,
+         capture_current_on_resume: false,
+         clipboard_auto_clear_seconds: None,
+         preserve_other_formats: false,
+         enable_http: false,
+         http_port: 7171,
+         http_token: None,
+         window_type: WindowType::MessageOnly,
+         start_with_windows: false,
       }
    }
 }
@@ -47,8 +658,16 @@ pub enum LineError {
    UnknownOption(String),
    UnknownModifier(String),
    UnknownKey(String),
+   UnknownHotkeyFlag(String),
+   UnknownThrottleMode(String),
+   UnknownDedupMode(String),
+   UnknownHistoryOrder(String),
+   UnknownTrayAction(String),
+   UnknownWindowType(String),
    ExpectedBool(String),
-   ExpectedInt(ParseIntError),
+   ExpectedInt(String, ParseIntError),
+   ExpectedPort(String),
+   InvalidPattern(String, regex::Error),
    ModifierWithNoKey,
 }
 
@@ -62,13 +681,42 @@ impl fmt::Display for LineError {
          LineError::UnknownOption(got) => write!(f, "Unknown option `{}`", got),
          LineError::UnknownModifier(got) => write!(f, "Unknown modifier `{}`", got),
          LineError::UnknownKey(got) => write!(f, "Unknown key `{}`", got),
+         LineError::UnknownThrottleMode(got) => {
+            write!(f, "Expected value to be one of `coalesce` or `drop`, got {}", got)
+         }
+         LineError::UnknownDedupMode(got) => {
+            write!(
+               f,
+               "Expected value to be one of `none`, `adjacent` or `global`, got {}",
+               got
+            )
+         }
+         LineError::UnknownHistoryOrder(got) => {
+            write!(f, "Expected value to be one of `recent` or `frequent`, got {}", got)
+         }
+         LineError::UnknownTrayAction(got) => {
+            write!(
+               f,
+               "Expected value to be one of `pop`, `swap`, `menu` or `nothing`, got {}",
+               got
+            )
+         }
+         LineError::UnknownWindowType(got) => {
+            write!(f, "Expected value to be one of `message_only` or `hidden`, got {}", got)
+         }
+         LineError::UnknownHotkeyFlag(got) => write!(f, "Unknown hotkey flag `{}`, expected `[repeat]` or `[norepeat]`", got),
          LineError::ExpectedBool(got) => write!(f, "Expected value to be one of `true` or `false`, got {}", got),
-         LineError::ExpectedInt(err) => write!(
+         LineError::ExpectedInt(got, err) => write!(
             f,
-            "Expected value to be a positive integer less than or equal to {}, but failed to parse: {}",
+            "Expected value to be `None` or a whole number no larger than {} without separators or units (like `100`), got `{}` ({})",
             usize::MAX,
+            got,
             err
          ),
+         LineError::ExpectedPort(got) => write!(f, "Expected value to be a port number from 1 to 65535, got `{}`", got),
+         LineError::InvalidPattern(got, err) => {
+            write!(f, "Expected value to be `None` or a regular expression, got `{}` ({})", got, err)
+         }
          LineError::ModifierWithNoKey => write!(
             f,
             "It doesn't make sense to have an empty key (None) with any modifiers, or other tokens"
@@ -77,6 +725,16 @@ impl fmt::Display for LineError {
    }
 }
 
+impl error::Error for LineError {
+   fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+      match self {
+         LineError::ExpectedInt(_, e) => Some(e),
+         LineError::InvalidPattern(_, e) => Some(e),
+         _ => None,
+      }
+   }
+}
+
 impl From<win::ParseVirtualKeyError> for LineError {
    fn from(e: win::ParseVirtualKeyError) -> LineError {
       match e {
@@ -114,13 +772,105 @@ impl fmt::Display for ParseError {
    }
 }
 
+impl error::Error for ParseError {
+   fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+      match self {
+         ParseError::Io(e) => Some(e),
+         ParseError::Line(e, _) => Some(e),
+      }
+   }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Hotkey {
    pub key: win::VirtualKey,
    pub modifiers: win::Modifiers,
+   /// Whether holding the keys down triggers the hotkey repeatedly
+   pub repeat: bool,
+}
+
+impl fmt::Display for Hotkey {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      if !self.modifiers.is_empty() {
+         write!(f, "{} + ", self.modifiers)?;
+      }
+      write!(f, "{}", self.key)?;
+      if !self.repeat {
+         write!(f, " [norepeat]")?;
+      }
+      Ok(())
+   }
+}
+
+impl Hotkey {
+   /// The modifiers to register the hotkey with, including the repeat behavior
+   pub fn registration_modifiers(&self) -> win::Modifiers {
+      if self.repeat {
+         self.modifiers
+      } else {
+         self.modifiers | win::Modifiers::NO_REPEAT
+      }
+   }
+}
+
+/// A comma separated list, where `None` (or nothing) is an empty list
+fn parse_list(value: &str) -> Vec<String> {
+   if value == "none" {
+      return Vec::new();
+   }
+   value
+      .split(',')
+      .map(str::trim)
+      .filter(|x| !x.is_empty())
+      .map(str::to_owned)
+      .collect()
+}
+
+/// Comma separated numbers, or `None` for an empty list
+fn parse_error_codes(value: &str) -> Result<Vec<u32>, LineError> {
+   parse_list(value)
+      .into_iter()
+      .map(|x| x.parse::<u32>().map_err(|e| LineError::ExpectedInt(x, e)))
+      .collect()
+}
+
+/// `None` is accepted in place of a number
+fn parse_optional_usize(value: &str) -> Result<Option<usize>, LineError> {
+   if value == "none" {
+      return Ok(None);
+   }
+   match value.parse::<usize>() {
+      Ok(value) => Ok(Some(value)),
+      Err(e) => Err(LineError::ExpectedInt(value.to_owned(), e)),
+   }
+}
+
+/// The index into `select_keybindings` of an option like `select_3_keybinding`
+fn select_keybinding_index(option: &str) -> Option<usize> {
+   let number = option.strip_prefix("select_")?.strip_suffix("_keybinding")?;
+   match number.parse::<usize>() {
+      Ok(n) if number.len() == 1 && n >= 1 => Some(n - 1),
+      _ => None,
+   }
 }
 
 fn parse_hotkey(hotkey: &str) -> Result<Option<Hotkey>, LineError> {
+   // A flag is a [ followed by a word, so that [ can still be used as a key
+   let flag_start = hotkey
+      .match_indices('[')
+      .map(|(i, _)| i)
+      .find(|&i| hotkey[i + 1..].starts_with(|c: char| c.is_ascii_alphabetic()));
+   let (hotkey, repeat) = match flag_start {
+      Some(i) => {
+         let repeat = match hotkey[i..].trim() {
+            "[repeat]" => true,
+            "[norepeat]" => false,
+            x => return Err(LineError::UnknownHotkeyFlag(x.to_owned())),
+         };
+         (&hotkey[..i], repeat)
+      }
+      None => (hotkey, true),
+   };
    let mut tokens_iter = hotkey.split('+').rev();
    let raw_key = tokens_iter.next().unwrap().trim();
    if raw_key == "none" {
@@ -141,7 +891,7 @@ fn parse_hotkey(hotkey: &str) -> Result<Option<Hotkey>, LineError> {
       let modifier: win::Modifiers = modifier.trim().parse()?;
       modifiers |= modifier;
    }
-   Ok(Some(Hotkey { key, modifiers }))
+   Ok(Some(Hotkey { key, modifiers, repeat }))
 }
 
 pub fn parse_config<R>(input: R) -> Result<Config, ParseError>
@@ -150,29 +900,40 @@ where
 {
    let mut config = Config::default();
    for (i, line) in input.lines().enumerate() {
-      let mut line = line?;
-      line.make_ascii_lowercase();
-      let line = line.trim();
+      let line = line?;
+      // Everything after a # is a comment
+      let line = line.split('#').next().unwrap_or("").trim();
       if line.is_empty() {
          continue;
       }
-      let pieces: Vec<_> = line.split('=').collect();
-      if pieces.len() != 2 {
-         return Err(ParseError::Line(LineError::Malformed, i));
-      }
-      match pieces[0].trim() {
+      // Only the first = separates the option from its value
+      let (option, raw_value) = match line.split_once('=') {
+         Some((option, value)) => (option.trim().to_ascii_lowercase(), value.trim()),
+         None => return Err(ParseError::Line(LineError::Malformed, i)),
+      };
+      // Case only matters to ignore_pattern and http_token
+      let value = raw_value.to_ascii_lowercase();
+      let value = value.as_str();
+      match option.as_str() {
          "max_stack_size" => {
-            let opt_value = pieces[1].trim();
-            config.max_stack_size = if opt_value == "none" {
-               None
-            } else {
-               match opt_value.parse::<usize>() {
-                  Ok(value) => Some(value),
-                  Err(e) => return Err(ParseError::Line(LineError::ExpectedInt(e), i)),
-               }
+            config.max_stack_size = match parse_optional_usize(value) {
+               Ok(value) => value,
+               Err(e) => return Err(ParseError::Line(e, i)),
+            }
+         }
+         "max_item_bytes" => {
+            config.max_item_bytes = match parse_optional_usize(value) {
+               Ok(value) => value,
+               Err(e) => return Err(ParseError::Line(e, i)),
+            }
+         }
+         "max_read_bytes" => {
+            config.max_read_bytes = match parse_optional_usize(value) {
+               Ok(value) => value,
+               Err(e) => return Err(ParseError::Line(e, i)),
             }
          }
-         "show_tray_icon" => match pieces[1].trim() {
+         "show_tray_icon" => match value {
             "true" => {
                config.show_tray_icon = true;
             }
@@ -181,7 +942,22 @@ where
             }
             x => return Err(ParseError::Line(LineError::ExpectedBool(x.to_owned()), i)),
          },
-         "prevent_duplicate_push" => match pieces[1].trim() {
+         "tray_icon_path" => {
+            config.tray_icon_path = match value {
+               "none" | "" => None,
+               x => Some(PathBuf::from(x)),
+            }
+         }
+         "pop_consumes" => match value {
+            "true" => {
+               config.pop_consumes = true;
+            }
+            "false" => {
+               config.pop_consumes = false;
+            }
+            x => return Err(ParseError::Line(LineError::ExpectedBool(x.to_owned()), i)),
+         },
+         "prevent_duplicate_push" => match value {
             "true" => {
                config.prevent_duplicate_push = true;
             }
@@ -190,62 +966,636 @@ where
             }
             x => return Err(ParseError::Line(LineError::ExpectedBool(x.to_owned()), i)),
          },
-         "pop_keybinding" => {
-            config.pop_keybinding = match parse_hotkey(pieces[1].trim()) {
-               Ok(binding) => binding,
+         "duplicate_refreshes_position" => match value {
+            "true" => {
+               config.duplicate_refreshes_position = true;
+            }
+            "false" => {
+               config.duplicate_refreshes_position = false;
+            }
+            x => return Err(ParseError::Line(LineError::ExpectedBool(x.to_owned()), i)),
+         },
+         "dedup_mode" => match value {
+            "none" => {
+               config.dedup_mode = DedupMode::None;
+            }
+            "adjacent" => {
+               config.dedup_mode = DedupMode::Adjacent;
+            }
+            "global" => {
+               config.dedup_mode = DedupMode::Global;
+            }
+            x => return Err(ParseError::Line(LineError::UnknownDedupMode(x.to_owned()), i)),
+         },
+         "track_source" => match value {
+            "true" => {
+               config.track_source = true;
+            }
+            "false" => {
+               config.track_source = false;
+            }
+            x => return Err(ParseError::Line(LineError::ExpectedBool(x.to_owned()), i)),
+         },
+         "show_timestamps" => match value {
+            "true" => {
+               config.show_timestamps = true;
+            }
+            "false" => {
+               config.show_timestamps = false;
+            }
+            x => return Err(ParseError::Line(LineError::ExpectedBool(x.to_owned()), i)),
+         },
+         "show_entry_stats" => match value {
+            "true" => {
+               config.show_entry_stats = true;
+            }
+            "false" => {
+               config.show_entry_stats = false;
+            }
+            x => return Err(ParseError::Line(LineError::ExpectedBool(x.to_owned()), i)),
+         },
+         "multiline_preview_summary" => match value {
+            "true" => {
+               config.multiline_preview_summary = true;
+            }
+            "false" => {
+               config.multiline_preview_summary = false;
+            }
+            x => return Err(ParseError::Line(LineError::ExpectedBool(x.to_owned()), i)),
+         },
+         "ignore_whitespace_only" => match value {
+            "true" => {
+               config.ignore_whitespace_only = true;
+            }
+            "false" => {
+               config.ignore_whitespace_only = false;
+            }
+            x => return Err(ParseError::Line(LineError::ExpectedBool(x.to_owned()), i)),
+         },
+         "ignore_pattern" => {
+            config.ignore_pattern = match value {
+               "none" | "" => None,
+               _ => match IgnorePattern::new(raw_value) {
+                  Ok(pattern) => Some(pattern),
+                  Err(e) => return Err(ParseError::Line(LineError::InvalidPattern(raw_value.to_owned(), e), i)),
+               },
+            }
+         }
+         "skip_binary_like" => match value {
+            "true" => {
+               config.skip_binary_like = true;
+            }
+            "false" => {
+               config.skip_binary_like = false;
+            }
+            x => return Err(ParseError::Line(LineError::ExpectedBool(x.to_owned()), i)),
+         },
+         "respect_os_history" => match value {
+            "true" => {
+               config.respect_os_history = true;
+            }
+            "false" => {
+               config.respect_os_history = false;
+            }
+            x => return Err(ParseError::Line(LineError::ExpectedBool(x.to_owned()), i)),
+         },
+         "rdp_resilience" => match value {
+            "true" => {
+               config.rdp_resilience = true;
+            }
+            "false" => {
+               config.rdp_resilience = false;
+            }
+            x => return Err(ParseError::Line(LineError::ExpectedBool(x.to_owned()), i)),
+         },
+         "binary_control_percent" => {
+            config.binary_control_percent = match parse_optional_usize(value) {
+               Ok(value) => value,
                Err(e) => return Err(ParseError::Line(e, i)),
             }
          }
-         "clear_keybinding" => {
-            config.clear_keybinding = match parse_hotkey(pieces[1].trim()) {
-               Ok(binding) => binding,
+         "binary_token_length" => {
+            config.binary_token_length = match parse_optional_usize(value) {
+               Ok(value) => value,
                Err(e) => return Err(ParseError::Line(e, i)),
             }
          }
-         "swap_keybinding" => {
-            config.swap_keybinding = match parse_hotkey(pieces[1].trim()) {
-               Ok(binding) => binding,
+         "popup_monitor" => {
+            config.popup_monitor = match parse_optional_usize(value) {
+               Ok(value) => value,
                Err(e) => return Err(ParseError::Line(e, i)),
             }
          }
-         x => return Err(ParseError::Line(LineError::UnknownOption(x.to_owned()), i)),
-      }
-   }
-   Ok(config)
-}
-
-pub fn load_config() -> Result<Config, ParseError> {
-   let path_opt = dirs::config_dir();
-   if let Some(mut path) = path_opt {
-      path.push("ripclip");
-      // Maybe it already exists, maybe not.
-      // We ignore errors because it will be handled when we try to
-      // write/read the configuration
-      let _ = fs::create_dir(&path);
-      path.push("ripclip.conf");
-      if let Ok(file) = File::open(&path) {
-         let config = parse_config(BufReader::new(file))?;
-         info!("Read configuration from {:#?}", path);
-         Ok(config)
-      } else {
-         match File::create(&path) {
-            Ok(mut file) => {
-               if let Err(e) = file.write_all(DEFAULT_CONFIG) {
-                  warn!("Unable to write default configuration to {:#?}.\n Error: {}", path, e);
-               } else {
-                  info!("Wrote default configuration to {:#?}", path);
-               }
+         "capture_current_on_resume" => match value {
+            "true" => {
+               config.capture_current_on_resume = true;
             }
-            Err(e) => {
-               warn!("Unable to write default configuration to {:#?}.\n Error: {}", path, e);
+            "false" => {
+               config.capture_current_on_resume = false;
             }
-         }
-         Ok(Config::default())
+            x => return Err(ParseError::Line(LineError::ExpectedBool(x.to_owned()), i)),
+         },
+         "preserve_other_formats" => match value {
+            "true" => {
+               config.preserve_other_formats = true;
+            }
+            "false" => {
+               config.preserve_other_formats = false;
+            }
+            x => return Err(ParseError::Line(LineError::ExpectedBool(x.to_owned()), i)),
+         },
+         "window_type" => match value {
+            "message_only" => {
+               config.window_type = WindowType::MessageOnly;
+            }
+            "hidden" => {
+               config.window_type = WindowType::Hidden;
+            }
+            x => return Err(ParseError::Line(LineError::UnknownWindowType(x.to_owned()), i)),
+         },
+         "start_with_windows" => match value {
+            "true" => {
+               config.start_with_windows = true;
+            }
+            "false" => {
+               config.start_with_windows = false;
+            }
+            x => return Err(ParseError::Line(LineError::ExpectedBool(x.to_owned()), i)),
+         },
+         "clipboard_auto_clear_seconds" => {
+            config.clipboard_auto_clear_seconds = match parse_optional_usize(value) {
+               Ok(value) => value,
+               Err(e) => return Err(ParseError::Line(e, i)),
+            }
+         }
+         "enable_http" => match value {
+            "true" => {
+               config.enable_http = true;
+            }
+            "false" => {
+               config.enable_http = false;
+            }
+            x => return Err(ParseError::Line(LineError::ExpectedBool(x.to_owned()), i)),
+         },
+         "http_port" => {
+            config.http_port = match value.parse::<u16>() {
+               Ok(port) if port != 0 => port,
+               _ => return Err(ParseError::Line(LineError::ExpectedPort(value.to_owned()), i)),
+            }
+         }
+         "http_token" => {
+            config.http_token = match value {
+               "none" | "" => None,
+               _ => Some(raw_value.to_owned()),
+            }
+         }
+         "skip_stack_modifier" => {
+            config.skip_stack_modifier = match value {
+               "none" => None,
+               x => match x.parse() {
+                  Ok(modifier) => Some(modifier),
+                  Err(e) => return Err(ParseError::Line(e.into(), i)),
+               },
+            }
+         }
+         "max_pushes_per_second" => {
+            config.max_pushes_per_second = match parse_optional_usize(value) {
+               Ok(value) => value,
+               Err(e) => return Err(ParseError::Line(e, i)),
+            }
+         }
+         "history_order" => match value {
+            "recent" => {
+               config.history_order = HistoryOrder::Recent;
+            }
+            "frequent" => {
+               config.history_order = HistoryOrder::Frequent;
+            }
+            x => return Err(ParseError::Line(LineError::UnknownHistoryOrder(x.to_owned()), i)),
+         },
+         "indicate_full" => match value {
+            "true" => {
+               config.indicate_full = true;
+            }
+            "false" => {
+               config.indicate_full = false;
+            }
+            x => return Err(ParseError::Line(LineError::ExpectedBool(x.to_owned()), i)),
+         },
+         "tray_double_click" => match value {
+            "pop" => {
+               config.tray_double_click = TrayAction::Pop;
+            }
+            "swap" => {
+               config.tray_double_click = TrayAction::Swap;
+            }
+            "menu" => {
+               config.tray_double_click = TrayAction::Menu;
+            }
+            "nothing" => {
+               config.tray_double_click = TrayAction::Nothing;
+            }
+            x => return Err(ParseError::Line(LineError::UnknownTrayAction(x.to_owned()), i)),
+         },
+         "throttle_mode" => match value {
+            "coalesce" => {
+               config.throttle_mode = ThrottleMode::Coalesce;
+            }
+            "drop" => {
+               config.throttle_mode = ThrottleMode::Drop;
+            }
+            x => return Err(ParseError::Line(LineError::UnknownThrottleMode(x.to_owned()), i)),
+         },
+         "clipboard_retry_errors" => {
+            config.clipboard_retry_errors = match parse_error_codes(value) {
+               Ok(codes) => codes,
+               Err(e) => return Err(ParseError::Line(e, i)),
+            }
+         }
+         "capture_only_from" => {
+            config.capture_only_from = parse_list(value);
+         }
+         "ignore_processes" => {
+            config.ignore_processes = parse_list(value);
+         }
+         "pop_keybinding" => {
+            config.pop_keybinding = match parse_hotkey(value) {
+               Ok(binding) => binding,
+               Err(e) => return Err(ParseError::Line(e, i)),
+            }
+         }
+         "clear_keybinding" => {
+            config.clear_keybinding = match parse_hotkey(value) {
+               Ok(binding) => binding,
+               Err(e) => return Err(ParseError::Line(e, i)),
+            }
+         }
+         "swap_keybinding" => {
+            config.swap_keybinding = match parse_hotkey(value) {
+               Ok(binding) => binding,
+               Err(e) => return Err(ParseError::Line(e, i)),
+            }
+         }
+         "pingpong_keybinding" => {
+            config.pingpong_keybinding = match parse_hotkey(value) {
+               Ok(binding) => binding,
+               Err(e) => return Err(ParseError::Line(e, i)),
+            }
+         }
+         "dedupe_keybinding" => {
+            config.dedupe_keybinding = match parse_hotkey(value) {
+               Ok(binding) => binding,
+               Err(e) => return Err(ParseError::Line(e, i)),
+            }
+         }
+         "peek_keybinding" => {
+            config.peek_keybinding = match parse_hotkey(value) {
+               Ok(binding) => binding,
+               Err(e) => return Err(ParseError::Line(e, i)),
+            }
+         }
+         "pop_to_window_keybinding" => {
+            config.pop_to_window_keybinding = match parse_hotkey(value) {
+               Ok(binding) => binding,
+               Err(e) => return Err(ParseError::Line(e, i)),
+            }
+         }
+         "push_keybinding" => {
+            config.push_keybinding = match parse_hotkey(value) {
+               Ok(binding) => binding,
+               Err(e) => return Err(ParseError::Line(e, i)),
+            }
+         }
+         "undo_keybinding" => {
+            config.undo_keybinding = match parse_hotkey(value) {
+               Ok(binding) => binding,
+               Err(e) => return Err(ParseError::Line(e, i)),
+            }
+         }
+         "browse_keybinding" => {
+            config.browse_keybinding = match parse_hotkey(value) {
+               Ok(binding) => binding,
+               Err(e) => return Err(ParseError::Line(e, i)),
+            }
+         }
+         "pause_keybinding" => {
+            config.pause_keybinding = match parse_hotkey(value) {
+               Ok(binding) => binding,
+               Err(e) => return Err(ParseError::Line(e, i)),
+            }
+         }
+         "clear_but_top_keybinding" => {
+            config.clear_but_top_keybinding = match parse_hotkey(value) {
+               Ok(binding) => binding,
+               Err(e) => return Err(ParseError::Line(e, i)),
+            }
+         }
+         "pop_to_window_title" => {
+            config.pop_to_window_title = match value {
+               "none" | "" => None,
+               x => Some(x.to_owned()),
+            }
+         }
+         x => match select_keybinding_index(x) {
+            Some(index) => {
+               config.select_keybindings[index] = match parse_hotkey(value) {
+                  Ok(binding) => binding,
+                  Err(e) => return Err(ParseError::Line(e, i)),
+               }
+            }
+            None => return Err(ParseError::Line(LineError::UnknownOption(x.to_owned()), i)),
+         },
       }
-   } else {
-      warn!("Unable to determine configuration directory; Falling back to default");
-      Ok(Config::default())
    }
+   Ok(config)
+}
+
+fn portable_dir() -> Option<PathBuf> {
+   let mut path = std::env::current_exe().ok()?;
+   path.pop();
+   Some(path)
+}
+
+pub fn has_portable_marker() -> bool {
+   portable_dir().is_some_and(|dir| dir.join(PORTABLE_MARKER).exists())
+}
+
+/// In portable mode, the configuration lives next to the executable instead of in the user's configuration directory
+fn config_path(portable: bool) -> Option<PathBuf> {
+   if portable {
+      let mut path = portable_dir()?;
+      path.push("ripclip.conf");
+      return Some(path);
+   }
+   let mut path = dirs::config_dir()?;
+   path.push("ripclip");
+   path.push("ripclip.conf");
+   Some(path)
+}
+
+fn fallback_config_path() -> Option<PathBuf> {
+   let mut path = dirs::data_local_dir()?;
+   path.push("ripclip");
+   path.push("ripclip.conf");
+   Some(path)
+}
+
+/// Where the configuration at `path` is copied before it's replaced: the same name with `.bak` added
+pub fn backup_path(path: &Path) -> PathBuf {
+   let mut backup_path = path.as_os_str().to_owned();
+   backup_path.push(".bak");
+   PathBuf::from(backup_path)
+}
+
+/// The file `load_config` reads from, so that it can be watched for changes. This may not exist yet
+pub fn config_file_path(config_override: Option<PathBuf>, portable: bool) -> Option<PathBuf> {
+   if config_override.is_some() {
+      return config_override;
+   }
+   let path = config_path(portable)?;
+   if !path.exists() && !portable {
+      if let Some(fallback_path) = fallback_config_path().filter(|x| x.exists()) {
+         return Some(fallback_path);
+      }
+   }
+   Some(path)
+}
+
+/// Something went wrong loading the configuration that the user should know about,
+/// but that we were able to recover from by using the default configuration
+#[derive(Debug)]
+pub enum LoadWarning {
+   NoConfigDir,
+   NotWritable(PathBuf, io::Error),
+   UsingFallback(PathBuf),
+}
+
+impl fmt::Display for LoadWarning {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self {
+         LoadWarning::NoConfigDir => write!(
+            f,
+            "Unable to determine configuration directory. The default configuration is being used."
+         ),
+         LoadWarning::NotWritable(path, e) => write!(
+            f,
+            "Unable to write configuration to {}: {}. The default configuration is being used.",
+            path.display(),
+            e
+         ),
+         LoadWarning::UsingFallback(path) => write!(
+            f,
+            "The configuration directory isn't writable, so the configuration was written to {} instead.",
+            path.display()
+         ),
+      }
+   }
+}
+
+/// Returns `None` if there is no configuration file at the given path
+fn read_config(path: &Path) -> Result<Option<Config>, ParseError> {
+   match File::open(path) {
+      Ok(file) => {
+         let config = parse_config(BufReader::new(file))?;
+         info!("Read configuration from {:#?}", path);
+         Ok(Some(config))
+      }
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e.into()),
+   }
+}
+
+/// A path the user asked for is never created, so that a typo doesn't quietly leave them with the default configuration
+fn read_existing_config(path: &Path) -> Result<Config, ParseError> {
+   match read_config(path)? {
+      Some(config) => Ok(config),
+      None => Err(not_found(path).into()),
+   }
+}
+
+fn not_found(path: &Path) -> io::Error {
+   io::Error::new(
+      io::ErrorKind::NotFound,
+      format!("No configuration file at {}", path.display()),
+   )
+}
+
+fn write_default_config(path: &Path) -> io::Result<()> {
+   // Maybe it already exists, maybe not.
+   // We ignore errors because it will be handled when we try to
+   // write the configuration
+   let _ = fs::create_dir(path.parent().unwrap());
+   let mut file = File::create(path)?;
+   file.write_all(DEFAULT_CONFIG)?;
+   info!("Wrote default configuration to {:#?}", path);
+   Ok(())
+}
+
+/// The configuration file, with any keybindings overridden by environment variables.
+/// `path` overrides the default location, and unlike it must already exist
+pub fn load_config(path: Option<PathBuf>, portable: bool) -> Result<(Config, Option<LoadWarning>), ParseError> {
+   let (mut config, warning) = match path {
+      Some(path) => (read_existing_config(&path)?, None),
+      None => load_config_file(portable)?,
+   };
+   apply_keybinding_overrides(&mut config, |name| std::env::var(name).ok());
+   Ok((config, warning))
+}
+
+/// Overrides keybindings with variables like `RIPCLIP_POP_KEYBINDING=Control + Shift + C`, so that bindings can be
+/// scripted without touching the configuration file. Invalid values are logged and ignored
+fn apply_keybinding_overrides<F>(config: &mut Config, var: F)
+where
+   F: Fn(&str) -> Option<String>,
+{
+   for (option, keybinding) in config.keybindings_mut() {
+      let name = format!("RIPCLIP_{}", option.to_ascii_uppercase());
+      let value = match var(&name) {
+         Some(value) => value,
+         None => continue,
+      };
+      match parse_hotkey(value.to_ascii_lowercase().trim()) {
+         Ok(hotkey) => {
+            info!("Using {} from the environment", name);
+            *keybinding = hotkey;
+         }
+         Err(e) => warn!("Ignoring {}: {}", name, e),
+      }
+   }
+}
+
+fn load_config_file(portable: bool) -> Result<(Config, Option<LoadWarning>), ParseError> {
+   let path = match config_path(portable) {
+      Some(path) => path,
+      None => {
+         warn!("Unable to determine configuration directory; Falling back to default");
+         return Ok((Config::default(), Some(LoadWarning::NoConfigDir)));
+      }
+   };
+
+   if let Some(config) = read_config(&path)? {
+      return Ok((config, None));
+   }
+
+   // If the configuration directory isn't writable (e.g. a locked down machine),
+   // a previous run may have written the configuration to the fallback location instead.
+   // Portable mode should stay self-contained, so there is no fallback
+   let fallback_path = if portable { None } else { fallback_config_path() };
+   if let Some(fallback_path) = &fallback_path {
+      if let Some(config) = read_config(fallback_path)? {
+         return Ok((config, None));
+      }
+   }
+
+   let error = match write_default_config(&path) {
+      Ok(()) => return Ok((Config::default(), None)),
+      Err(e) => e,
+   };
+   warn!(
+      "Unable to write default configuration to {:#?}.\n Error: {}",
+      path, error
+   );
+
+   if let Some(fallback_path) = fallback_path {
+      match write_default_config(&fallback_path) {
+         Ok(()) => return Ok((Config::default(), Some(LoadWarning::UsingFallback(fallback_path)))),
+         Err(e) => {
+            warn!(
+               "Unable to write default configuration to {:#?}.\n Error: {}",
+               fallback_path, e
+            );
+         }
+      }
+   }
+
+   Ok((Config::default(), Some(LoadWarning::NotWritable(path, error))))
+}
+
+#[derive(Debug)]
+pub enum BundleError {
+   Io(io::Error),
+   NoConfigDir,
+   NotABundle,
+   InvalidConfig(ParseError),
+}
+
+impl From<io::Error> for BundleError {
+   fn from(e: io::Error) -> BundleError {
+      BundleError::Io(e)
+   }
+}
+
+impl fmt::Display for BundleError {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self {
+         BundleError::Io(e) => write!(f, "I/O Error: {}", e),
+         BundleError::NoConfigDir => write!(f, "Unable to determine configuration directory"),
+         BundleError::NotABundle => write!(f, "File is not a ripclip bundle"),
+         BundleError::InvalidConfig(e) => write!(f, "Bundle contains an invalid configuration. {}", e),
+      }
+   }
+}
+
+/// Returns the configuration portion of a bundle, or `None` if the headers are missing
+fn bundle_config_section(bundle: &str) -> Option<&str> {
+   let mut rest = bundle;
+   for expected in &[BUNDLE_HEADER, BUNDLE_CONFIG_SECTION] {
+      let (line, remaining) = match rest.find('\n') {
+         Some(i) => (&rest[..i], &rest[i + 1..]),
+         None => (rest, ""),
+      };
+      if line.trim() != *expected {
+         return None;
+      }
+      rest = remaining;
+   }
+   Some(rest)
+}
+
+/// Writes the current configuration to a single file that can be moved to another machine
+pub fn export_bundle(bundle_path: &Path, config_override: Option<PathBuf>, portable: bool) -> Result<(), BundleError> {
+   let is_default = config_override.is_none();
+   let path = match config_override {
+      Some(path) => path,
+      None => config_path(portable).ok_or(BundleError::NoConfigDir)?,
+   };
+   let config = match fs::read(&path) {
+      Ok(config) => config,
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound && is_default => DEFAULT_CONFIG.to_vec(),
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Err(not_found(&path).into()),
+      Err(e) => return Err(e.into()),
+   };
+   let mut file = File::create(bundle_path)?;
+   writeln!(file, "{}", BUNDLE_HEADER)?;
+   writeln!(file, "{}", BUNDLE_CONFIG_SECTION)?;
+   file.write_all(&config)?;
+   info!("Exported bundle to {:#?}", bundle_path);
+   Ok(())
+}
+
+/// Replaces the current configuration with the one in the bundle.
+/// The bundled configuration is validated first, and the existing configuration is
+/// backed up to its `backup_path`
+pub fn import_bundle(bundle_path: &Path, config_override: Option<PathBuf>, portable: bool) -> Result<(), BundleError> {
+   let bundle = fs::read_to_string(bundle_path)?;
+   let config = bundle_config_section(&bundle).ok_or(BundleError::NotABundle)?;
+   parse_config(config.as_bytes()).map_err(BundleError::InvalidConfig)?;
+   let path = match config_override {
+      Some(path) => path,
+      None => {
+         let path = config_path(portable).ok_or(BundleError::NoConfigDir)?;
+         let _ = fs::create_dir(path.parent().unwrap());
+         path
+      }
+   };
+   if path.exists() {
+      let backup_path = backup_path(&path);
+      fs::copy(&path, &backup_path)?;
+      info!("Backed up existing configuration to {:#?}", backup_path);
+   }
+   fs::write(&path, config)?;
+   info!("Imported bundle from {:#?}", bundle_path);
+   Ok(())
 }
 
 #[cfg(test)]
@@ -265,6 +1615,7 @@ mod test {
       assert_eq!(parsed_cfg.clear_keybinding, Some(Hotkey {
          modifiers: win::Modifiers::CONTROL | win::Modifiers::SHIFT,
          key: win::VirtualKey::C,
+         repeat: true,
       }));
    }
 
@@ -273,6 +1624,40 @@ mod test {
       assert_eq!(parse_config(DEFAULT_CONFIG).unwrap(), Config::default());
    }
 
+   #[test]
+   fn writes_default_config() {
+      let dir = std::env::temp_dir().join(format!("ripclip_test_{}", std::process::id()));
+      let path = dir.join("ripclip.conf");
+      write_default_config(&path).unwrap();
+      let written = read_config(&path);
+      let _ = fs::remove_dir_all(&dir);
+      assert_eq!(written.unwrap(), Some(Config::default()));
+   }
+
+   #[test]
+   fn writes_config_to_path() {
+      let path = std::env::temp_dir().join(format!("ripclip_write_{}.conf", std::process::id()));
+      let config = Config {
+         max_stack_size: None,
+         swap_keybinding: None,
+         ..Config::default()
+      };
+      config.write_to(&path).unwrap();
+      let written = read_config(&path);
+      let _ = fs::remove_file(&path);
+      assert_eq!(written.unwrap(), Some(config));
+   }
+
+   #[test]
+   fn config_path_override_must_exist() {
+      let path = std::env::temp_dir().join(format!("ripclip_missing_{}.conf", std::process::id()));
+      match load_config(Some(path.clone()), false) {
+         Err(ParseError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+         _ => panic!("expected a missing configuration to be an error"),
+      }
+      assert!(!path.exists());
+   }
+
    #[test]
    fn ignores_blank_lines() {
       let config_blank_lines: &[u8] = b"
@@ -287,4 +1672,471 @@ mod test {
       ";
       assert!(parse_config(config_blank_lines).is_ok());
    }
+
+   #[test]
+   fn ignores_comments() {
+      let config: &[u8] = b"
+         # max_stack_size = 5
+         #show_tray_icon = false
+      ";
+      assert_eq!(parse_config(config).unwrap(), Config::default());
+
+      let config: &[u8] = b"max_stack_size = 50 # my limit";
+      assert_eq!(parse_config(config).unwrap().max_stack_size, Some(50));
+   }
+
+   #[test]
+   fn splits_on_first_equals() {
+      let config: &[u8] = b"pop_to_window_title = a=b";
+      assert_eq!(
+         parse_config(config).unwrap().pop_to_window_title.as_deref(),
+         Some("a=b")
+      );
+
+      let config: &[u8] = b"some_future_option = a=b";
+      assert!(matches!(
+         parse_config(config),
+         Err(ParseError::Line(LineError::UnknownOption(option), 0)) if option == "some_future_option"
+      ));
+   }
+
+   #[test]
+   fn process_filters() {
+      let config: &[u8] = b"
+         capture_only_from = Code.exe, notepad.exe
+         ignore_processes = notepad.exe,keepass.exe
+      ";
+      let parsed_cfg = parse_config(config).unwrap();
+      assert_eq!(parsed_cfg.capture_only_from, vec!["code.exe", "notepad.exe"]);
+      assert!(parsed_cfg.should_capture_from(Some("code.exe")));
+      assert!(parsed_cfg.should_capture_from(Some("notepad.exe")));
+      assert!(!parsed_cfg.should_capture_from(Some("chrome.exe")));
+      assert!(!parsed_cfg.should_capture_from(None));
+
+      let config: &[u8] = b"ignore_processes = keepass.exe";
+      let parsed_cfg = parse_config(config).unwrap();
+      assert!(!parsed_cfg.should_capture_from(Some("keepass.exe")));
+      assert!(parsed_cfg.should_capture_from(Some("chrome.exe")));
+      assert!(parsed_cfg.should_capture_from(None));
+   }
+
+   #[test]
+   fn parses_repeat_flags() {
+      let config: &[u8] = b"
+         pop_keybinding = Control + Shift + C [repeat]
+         clear_keybinding = Control + Shift + X [NoRepeat]
+         swap_keybinding = Control + Shift + S
+      ";
+      let parsed_cfg = parse_config(config).unwrap();
+      let pop = parsed_cfg.pop_keybinding.unwrap();
+      assert!(pop.repeat);
+      assert_eq!(pop.key, win::VirtualKey::C);
+      assert_eq!(
+         pop.registration_modifiers(),
+         win::Modifiers::CONTROL | win::Modifiers::SHIFT
+      );
+      let clear = parsed_cfg.clear_keybinding.unwrap();
+      assert!(!clear.repeat);
+      assert_eq!(clear.key, win::VirtualKey::X);
+      assert!(clear.registration_modifiers().contains(win::Modifiers::NO_REPEAT));
+      assert!(parsed_cfg.swap_keybinding.unwrap().repeat);
+
+      let config: &[u8] = b"pop_keybinding = Control + C [sometimes]";
+      match parse_config(config) {
+         Err(ParseError::Line(LineError::UnknownHotkeyFlag(flag), 0)) => assert_eq!(flag, "[sometimes]"),
+         x => panic!("Expected an unknown flag error, got {:?}", x),
+      }
+   }
+
+   #[test]
+   fn canonical_modifier_order() {
+      for line in [
+         "pop_keybinding = Control + Alt + Shift + Win + K",
+         "pop_keybinding = Win + Shift + Alt + Control + K",
+         "pop_keybinding = shift + super + ctrl + alt + k",
+         "pop_keybinding = Alt + Control + Windows + Shift + K",
+      ]
+      .iter()
+      {
+         let hotkey = parse_config(line.as_bytes()).unwrap().pop_keybinding.unwrap();
+         assert_eq!(hotkey.to_string(), "Control + Alt + Shift + Win + K");
+      }
+      let config: &[u8] = b"pop_keybinding = Shift + Control + Page Up";
+      let hotkey = parse_config(config).unwrap().pop_keybinding.unwrap();
+      assert_eq!(hotkey.to_string(), "Control + Shift + Page Up");
+   }
+
+   #[test]
+   fn hotkeys_round_trip() {
+      let hotkeys = [
+         Hotkey {
+            key: win::VirtualKey::C,
+            modifiers: win::Modifiers::CONTROL | win::Modifiers::SHIFT,
+            repeat: true,
+         },
+         Hotkey {
+            key: win::VirtualKey::PageUp,
+            modifiers: win::Modifiers::ALT,
+            repeat: false,
+         },
+         Hotkey {
+            key: win::VirtualKey::F12,
+            modifiers: win::Modifiers::empty(),
+            repeat: true,
+         },
+         Hotkey {
+            key: win::VirtualKey::LeftBracket,
+            modifiers: win::Modifiers::CONTROL | win::Modifiers::ALT | win::Modifiers::SHIFT | win::Modifiers::WIN,
+            repeat: false,
+         },
+         Hotkey {
+            key: win::VirtualKey::MediaPlayPause,
+            modifiers: win::Modifiers::WIN,
+            repeat: true,
+         },
+      ];
+      for hotkey in hotkeys.iter() {
+         // parse_config lowercases each line before it gets here
+         let written = hotkey.to_string().to_lowercase();
+         assert_eq!(parse_hotkey(&written).unwrap().as_ref(), Some(hotkey), "{}", written);
+      }
+   }
+
+   #[test]
+   fn keybinding_overrides() {
+      let config: &[u8] = b"
+         pop_keybinding = Control + Shift + C
+         swap_keybinding = Control + Shift + S
+         clear_keybinding = Control + Shift + X
+      ";
+      let mut parsed_cfg = parse_config(config).unwrap();
+      apply_keybinding_overrides(&mut parsed_cfg, |name| match name {
+         "RIPCLIP_POP_KEYBINDING" => Some("Alt + P [norepeat]".to_owned()),
+         "RIPCLIP_SWAP_KEYBINDING" => Some("None".to_owned()),
+         "RIPCLIP_CLEAR_KEYBINDING" => Some("Control + Nonsense".to_owned()),
+         "RIPCLIP_DEDUPE_KEYBINDING" => Some("Win + D".to_owned()),
+         _ => None,
+      });
+      // The environment wins over the file
+      assert_eq!(parsed_cfg.pop_keybinding, Some(Hotkey {
+         key: win::VirtualKey::P,
+         modifiers: win::Modifiers::ALT,
+         repeat: false,
+      }));
+      assert_eq!(parsed_cfg.swap_keybinding, None);
+      assert_eq!(parsed_cfg.dedupe_keybinding, Some(Hotkey {
+         key: win::VirtualKey::D,
+         modifiers: win::Modifiers::WIN,
+         repeat: true,
+      }));
+      // Invalid or missing values leave the file's keybinding alone
+      assert_eq!(parsed_cfg.clear_keybinding, Some(Hotkey {
+         key: win::VirtualKey::X,
+         modifiers: win::Modifiers::CONTROL | win::Modifiers::SHIFT,
+         repeat: true,
+      }));
+      assert_eq!(parsed_cfg.pingpong_keybinding, None);
+   }
+
+   #[test]
+   fn taking_keybindings_leaves_the_rest() {
+      let config: &[u8] = b"
+         max_stack_size = 5
+         pop_keybinding = Control + Shift + C
+         swap_keybinding = Control + Shift + S
+      ";
+      let mut parsed_cfg = parse_config(config).unwrap();
+      let config: &[u8] = b"
+         max_stack_size = 10
+         pop_keybinding = None
+         select_3_keybinding = Alt + 3
+         pop_to_window_title = notepad
+      ";
+      parsed_cfg.take_keybindings(parse_config(config).unwrap());
+      assert_eq!(parsed_cfg.max_stack_size, Some(5));
+      assert_eq!(parsed_cfg.pop_keybinding, None);
+      assert_eq!(parsed_cfg.swap_keybinding, None);
+      assert!(parsed_cfg.select_keybindings[2].is_some());
+      assert_eq!(parsed_cfg.pop_to_window_title.as_deref(), Some("notepad"));
+   }
+
+   #[test]
+   fn saving_leaves_out_keybinding_overrides() {
+      let file: &[u8] = b"pop_keybinding = Control + Shift + C\nmax_stack_size = 5";
+      let file = parse_config(file).unwrap();
+      let config: &[u8] = b"
+         max_stack_size = 10
+         pop_keybinding = Alt + P
+         swap_keybinding = Alt + S
+         select_2_keybinding = Alt + 2
+      ";
+      let config = parse_config(config).unwrap();
+      let saved = config.to_saved_string(&file, |name| match name {
+         "RIPCLIP_POP_KEYBINDING" | "RIPCLIP_SELECT_2_KEYBINDING" => Some("overridden".to_owned()),
+         _ => None,
+      });
+      let saved = parse_config(saved.as_bytes()).unwrap();
+      assert_eq!(saved.pop_keybinding, file.pop_keybinding);
+      assert_eq!(saved.select_keybindings[1], None);
+      // Everything else is saved as it is in use
+      assert_eq!(saved.swap_keybinding, config.swap_keybinding);
+      assert_eq!(saved.max_stack_size, Some(10));
+   }
+
+   #[test]
+   fn config_string_round_trips() {
+      let config: &[u8] = b"
+         max_stack_size = None
+         clear_keybinding = Shift + Alt + Numpad 3 [norepeat]
+         capture_only_from = code.exe, notepad.exe
+         binary_token_length = 20
+         history_order = frequent
+         tray_double_click = pop
+         skip_stack_modifier = ctrl
+      ";
+      let parsed_cfg = parse_config(config).unwrap();
+      assert_eq!(parsed_cfg.history_order, HistoryOrder::Frequent);
+      assert_eq!(
+         parse_config(parsed_cfg.to_config_string().as_bytes()).unwrap(),
+         parsed_cfg
+      );
+      assert_eq!(
+         parse_config(Config::default().to_config_string().as_bytes()).unwrap(),
+         Config::default()
+      );
+   }
+
+   #[test]
+   fn pop_to_window_title() {
+      let config: &[u8] = b"pop_to_window_title =  Timesheet - Mozilla Firefox ";
+      let parsed_cfg = parse_config(config).unwrap();
+      assert_eq!(parsed_cfg.pop_to_window_title.as_deref(), Some("timesheet - mozilla firefox"));
+      assert_eq!(
+         parse_config(parsed_cfg.to_config_string().as_bytes()).unwrap(),
+         parsed_cfg
+      );
+      let config: &[u8] = b"pop_to_window_title = None";
+      assert!(parse_config(config).unwrap().pop_to_window_title.is_none());
+   }
+
+   #[test]
+   fn tray_icon_path() {
+      let config: &[u8] = b"tray_icon_path = C:\\Users\\Me\\Icons\\Clipboard.ico";
+      let parsed_cfg = parse_config(config).unwrap();
+      assert_eq!(
+         parsed_cfg.tray_icon_path,
+         Some(PathBuf::from("c:\\users\\me\\icons\\clipboard.ico"))
+      );
+      assert_eq!(
+         parse_config(parsed_cfg.to_config_string().as_bytes()).unwrap(),
+         parsed_cfg
+      );
+      let config: &[u8] = b"tray_icon_path = None";
+      assert!(parse_config(config).unwrap().tray_icon_path.is_none());
+   }
+
+   #[test]
+   fn select_keybindings() {
+      let config: &[u8] = b"select_3_keybinding = Control + Alt + 3";
+      let parsed_cfg = parse_config(config).unwrap();
+      assert_eq!(parsed_cfg.select_keybindings[2], Some(Hotkey {
+         key: win::VirtualKey::Three,
+         modifiers: win::Modifiers::CONTROL | win::Modifiers::ALT,
+         repeat: true,
+      }));
+      assert!(parsed_cfg.select_keybindings[0].is_none());
+      assert_eq!(
+         parse_config(parsed_cfg.to_config_string().as_bytes()).unwrap(),
+         parsed_cfg
+      );
+
+      for option in ["select_0_keybinding", "select_10_keybinding", "select_01_keybinding"].iter() {
+         let line = format!("{} = None", option);
+         assert!(matches!(
+            parse_config(line.as_bytes()),
+            Err(ParseError::Line(LineError::UnknownOption(_), 0))
+         ));
+      }
+   }
+
+   #[test]
+   fn duplicate_actions() {
+      assert_eq!(Config::default().duplicate_action(), DuplicateAction::Push);
+
+      let config: &[u8] = b"prevent_duplicate_push = true";
+      assert_eq!(parse_config(config).unwrap().duplicate_action(), DuplicateAction::Skip);
+
+      let config: &[u8] = b"
+         prevent_duplicate_push = true
+         duplicate_refreshes_position = true
+      ";
+      let parsed_cfg = parse_config(config).unwrap();
+      assert_eq!(parsed_cfg.duplicate_action(), DuplicateAction::Refresh);
+
+      let config: &[u8] = b"dedup_mode = adjacent";
+      assert_eq!(parse_config(config).unwrap().duplicate_action(), DuplicateAction::Skip);
+
+      // Global takes precedence over both of the older options
+      let config: &[u8] = b"
+         dedup_mode = global
+         prevent_duplicate_push = true
+         duplicate_refreshes_position = true
+      ";
+      let parsed_cfg = parse_config(config).unwrap();
+      assert_eq!(parsed_cfg.duplicate_action(), DuplicateAction::MoveToTop);
+      assert_eq!(
+         parse_config(parsed_cfg.to_config_string().as_bytes()).unwrap(),
+         parsed_cfg
+      );
+
+      let config: &[u8] = b"dedup_mode = everywhere";
+      assert!(matches!(
+         parse_config(config),
+         Err(ParseError::Line(LineError::UnknownDedupMode(_), 0))
+      ));
+
+      let config: &[u8] = b"duplicate_refreshes_position = true";
+      let parsed_cfg = parse_config(config).unwrap();
+      assert_eq!(parsed_cfg.duplicate_action(), DuplicateAction::Refresh);
+   }
+
+   #[test]
+   fn parses_numpad_operators() {
+      let keys = [
+         ("numpad multiply", 0x6A),
+         ("numpad *", 0x6A),
+         ("numpad plus", 0x6B),
+         ("numpad separator", 0x6C),
+         ("numpad minus", 0x6D),
+         ("numpad -", 0x6D),
+         ("numpad decimal", 0x6E),
+         ("numpad divide", 0x6F),
+         ("numpad /", 0x6F),
+      ];
+      for (name, code) in keys.iter() {
+         let hotkey = parse_hotkey(&format!("control + {}", name)).unwrap().unwrap();
+         assert_eq!(hotkey.key as u8, *code, "{}", name);
+      }
+      assert_eq!(win::VirtualKey::F1 as u8, 0x70);
+   }
+
+   #[test]
+   fn parses_punctuation_keys() {
+      let keys = [
+         (";", "semicolon", 0xBA),
+         ("=", "equals", 0xBB),
+         (",", "comma", 0xBC),
+         ("-", "minus", 0xBD),
+         (".", "period", 0xBE),
+         ("/", "slash", 0xBF),
+         ("`", "backtick", 0xC0),
+         ("[", "left bracket", 0xDB),
+         ("\\", "backslash", 0xDC),
+         ("]", "right bracket", 0xDD),
+         ("'", "quote", 0xDE),
+      ];
+      for (character, name, code) in keys.iter() {
+         let line = format!("pop_keybinding = control + {} [norepeat]", character);
+         let hotkey = parse_config(line.as_bytes()).unwrap().pop_keybinding.unwrap();
+         assert_eq!(hotkey.key as u8, *code, "{}", character);
+         assert!(!hotkey.repeat);
+         assert_eq!(hotkey.key.to_string().to_lowercase(), *name);
+         assert_eq!(parse_hotkey(name).unwrap().unwrap().key, hotkey.key);
+      }
+   }
+
+   #[test]
+   fn parses_media_keys() {
+      let keys = [
+         ("volume mute", 0xAD),
+         ("volume down", 0xAE),
+         ("volume up", 0xAF),
+         ("media next", 0xB0),
+         ("media previous", 0xB1),
+         ("media stop", 0xB2),
+         ("media play", 0xB3),
+      ];
+      for (name, code) in keys.iter() {
+         let hotkey = parse_hotkey(name).unwrap().unwrap();
+         assert_eq!(hotkey.key as u8, *code, "{}", name);
+         assert_eq!(hotkey.key.to_string().to_lowercase(), *name);
+      }
+   }
+
+   #[test]
+   fn reports_bad_int() {
+      let config: &[u8] = b"max_stack_size = 1,000";
+      match parse_config(config) {
+         Err(ParseError::Line(LineError::ExpectedInt(got, _), 0)) => assert_eq!(got, "1,000"),
+         other => panic!("Expected an integer error, got {:?}", other),
+      }
+   }
+
+   #[test]
+   fn errors_chain_to_their_source() {
+      let config: &[u8] = b"max_stack_size = ten";
+      let e: Box<dyn error::Error> = Box::new(parse_config(config).unwrap_err());
+      let line_error = e.source().unwrap();
+      assert!(line_error.to_string().contains("`ten`"));
+      assert!(line_error.source().unwrap().is::<ParseIntError>());
+   }
+
+   #[test]
+   fn parses_http_token() {
+      let config: &[u8] = b"http_token = Xy7-Secret";
+      let parsed_cfg = parse_config(config).unwrap();
+      assert_eq!(parsed_cfg.http_token.as_deref(), Some("Xy7-Secret"));
+      assert_eq!(
+         parse_config(parsed_cfg.to_config_string().as_bytes()).unwrap(),
+         parsed_cfg
+      );
+      let config: &[u8] = b"http_token = None";
+      assert!(parse_config(config).unwrap().http_token.is_none());
+   }
+
+   #[test]
+   fn parses_ignore_pattern() {
+      // Unlike everything else, the pattern keeps its case, so that escapes like \S and \d mean different things
+      let config: &[u8] = b"IGNORE_PATTERN = ^\\S{20,}$";
+      let parsed_cfg = parse_config(config).unwrap();
+      assert_eq!(parsed_cfg.ignore_pattern.as_ref().unwrap().to_string(), "^\\S{20,}$");
+      assert_eq!(
+         parse_config(parsed_cfg.to_config_string().as_bytes()).unwrap(),
+         parsed_cfg
+      );
+      let config: &[u8] = b"ignore_pattern = None";
+      assert!(parse_config(config).unwrap().ignore_pattern.is_none());
+
+      let config: &[u8] = b"max_stack_size = 5\nignore_pattern = [a-z";
+      match parse_config(config) {
+         Err(ParseError::Line(LineError::InvalidPattern(got, _), 1)) => assert_eq!(got, "[a-z"),
+         other => panic!("Expected a pattern error, got {:?}", other),
+      }
+   }
+
+   #[test]
+   fn parses_clipboard_retry_errors() {
+      let config: &[u8] = b"clipboard_retry_errors = 5, 1418";
+      assert_eq!(parse_config(config).unwrap().clipboard_retry_errors, vec![5, 1418]);
+      let config: &[u8] = b"clipboard_retry_errors = None";
+      assert!(parse_config(config).unwrap().clipboard_retry_errors.is_empty());
+      let config: &[u8] = b"clipboard_retry_errors = 5, busy";
+      match parse_config(config) {
+         Err(ParseError::Line(LineError::ExpectedInt(got, _), 0)) => assert_eq!(got, "busy"),
+         other => panic!("Expected an integer error, got {:?}", other),
+      }
+   }
+
+   #[test]
+   fn extracts_bundle_config() {
+      let bundle = "# ripclip bundle\n[config]\nmax_stack_size = 5\n";
+      assert_eq!(bundle_config_section(bundle), Some("max_stack_size = 5\n"));
+      assert_eq!(bundle_config_section("max_stack_size = 5\n"), None);
+   }
+
+   #[test]
+   fn backs_up_next_to_the_configuration() {
+      assert_eq!(backup_path(Path::new("ripclip.conf")), Path::new("ripclip.conf.bak"));
+      assert_eq!(backup_path(Path::new("profile.txt")), Path::new("profile.txt.bak"));
+   }
 }
@@ -0,0 +1,298 @@
+//! Localhost-only control server for scripts
+//!
+//! * `GET /stack` returns the stack as a JSON array of strings (or null for images), top first
+//! * `POST /push` pushes the request body (UTF-8 text), filtered and deduplicated the same as a copy
+//! * `POST /pop` and `POST /clear` do the same as the keybindings
+//!
+//! Every request must carry the configured token in an `X-Ripclip-Token` header. Web pages can reach localhost too,
+//! so requests from a browser (anything with an `Origin` header, or a `Host` that isn't us) are refused as well
+
+use crate::{post_app_message, AppMessage, AppMessageSender};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// So that a client that never finishes its request doesn't hold up everyone else
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Anything bigger than this is almost certainly not a clipboard entry someone meant to push
+const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+/// The request line and headers together. Ours are a few hundred bytes
+const MAX_HEAD_BYTES: u64 = 16 * 1024;
+/// A browser can't send a custom header to another origin without a CORS preflight, which this server never approves
+const TOKEN_HEADER: &str = "x-ripclip-token";
+
+/// The parts of a request's line and headers that the server looks at
+#[derive(Debug, Default, PartialEq)]
+struct RequestHead {
+   method: String,
+   path: String,
+   content_length: usize,
+   host: Option<String>,
+   has_origin: bool,
+   token: Option<String>,
+}
+
+/// Binds to 127.0.0.1 on the calling thread, so that a port conflict can be reported right away,
+/// then handles requests one at a time on a worker thread
+pub fn start(port: u16, token: String, sender: AppMessageSender) -> io::Result<()> {
+   let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+   info!("Control server listening on {}", listener.local_addr()?);
+   thread::spawn(move || {
+      for stream in listener.incoming() {
+         let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+               warn!("Failed to accept control connection: {}", e);
+               continue;
+            }
+         };
+         if let Err(e) = handle_connection(stream, port, &token, &sender) {
+            warn!("Failed to handle control request: {}", e);
+         }
+      }
+   });
+   Ok(())
+}
+
+fn handle_connection(mut stream: TcpStream, port: u16, token: &str, sender: &AppMessageSender) -> io::Result<()> {
+   // Binding to the loopback address should already guarantee this
+   let peer = stream.peer_addr()?;
+   if !peer.ip().is_loopback() {
+      warn!("Rejected control connection from {}", peer);
+      return Ok(());
+   }
+   stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+   let mut reader = BufReader::new(stream.try_clone()?);
+   let head = match read_head(&mut reader)? {
+      Some(head) => head,
+      None => return respond(&mut stream, "431 Request Header Fields Too Large", ""),
+   };
+   if !is_allowed(&head, port, token) {
+      warn!("Refused control request: {} {}", head.method, head.path);
+      return respond(&mut stream, "403 Forbidden", "");
+   }
+   if head.content_length > MAX_BODY_BYTES {
+      return respond(&mut stream, "413 Payload Too Large", "");
+   }
+   let mut body = vec![0; head.content_length];
+   reader.read_exact(&mut body)?;
+
+   trace!("Control request: {} {}", head.method, head.path);
+   match (head.method.as_str(), head.path.as_str()) {
+      ("GET", "/stack") => {
+         let (reply_sender, reply_receiver) = mpsc::channel();
+         post_app_message(sender, AppMessage::Stack(reply_sender));
+         match reply_receiver.recv_timeout(REQUEST_TIMEOUT) {
+            Ok(stack) => respond(&mut stream, "200 OK", &json_string_array(&stack)),
+            Err(_) => respond(&mut stream, "503 Service Unavailable", ""),
+         }
+      }
+      ("POST", "/push") => match String::from_utf8(body) {
+         Ok(text) => {
+            post_app_message(sender, AppMessage::Push(text));
+            respond(&mut stream, "204 No Content", "")
+         }
+         Err(_) => respond(&mut stream, "400 Bad Request", ""),
+      },
+      ("POST", "/pop") => {
+         post_app_message(sender, AppMessage::Pop);
+         respond(&mut stream, "204 No Content", "")
+      }
+      ("POST", "/clear") => {
+         post_app_message(sender, AppMessage::Clear);
+         respond(&mut stream, "204 No Content", "")
+      }
+      (_, "/stack") | (_, "/push") | (_, "/pop") | (_, "/clear") => respond(&mut stream, "405 Method Not Allowed", ""),
+      _ => respond(&mut stream, "404 Not Found", ""),
+   }
+}
+
+/// Reads the request line and headers, or returns None if they're longer than `MAX_HEAD_BYTES`
+fn read_head(reader: &mut impl BufRead) -> io::Result<Option<RequestHead>> {
+   let mut limited = reader.take(MAX_HEAD_BYTES);
+   let mut head = RequestHead::default();
+
+   let mut request_line = String::new();
+   if !read_line(&mut limited, &mut request_line)? {
+      return Ok(None);
+   }
+   let mut parts = request_line.split_whitespace();
+   head.method = parts.next().unwrap_or("").to_owned();
+   head.path = parts.next().unwrap_or("").to_owned();
+
+   loop {
+      let mut header = String::new();
+      if !read_line(&mut limited, &mut header)? {
+         return Ok(None);
+      }
+      if header.trim().is_empty() {
+         break;
+      }
+      if let Some((name, value)) = header.split_once(':') {
+         let name = name.trim().to_ascii_lowercase();
+         let value = value.trim();
+         match name.as_str() {
+            "content-length" => head.content_length = value.parse().unwrap_or(0),
+            "host" => head.host = Some(value.to_owned()),
+            "origin" => head.has_origin = true,
+            TOKEN_HEADER => head.token = Some(value.to_owned()),
+            _ => (),
+         }
+      }
+   }
+   Ok(Some(head))
+}
+
+/// Reads one line into `buf`. Returns false if the line was cut off by the limit
+fn read_line<R: BufRead>(limited: &mut io::Take<R>, buf: &mut String) -> io::Result<bool> {
+   limited.read_line(buf)?;
+   Ok(buf.ends_with('\n') || limited.limit() > 0)
+}
+
+/// Whether a request comes from a script that knows the token, rather than from a web page. Browsers send `Origin`
+/// with cross-site requests, and checking `Host` stops a page that rebinds its own domain to 127.0.0.1
+fn is_allowed(head: &RequestHead, port: u16, token: &str) -> bool {
+   if head.has_origin {
+      return false;
+   }
+   let host_is_us = head.host.as_deref().is_some_and(|host| {
+      host == format!("127.0.0.1:{}", port) || host.eq_ignore_ascii_case(&format!("localhost:{}", port))
+   });
+   let knows_token = head
+      .token
+      .as_deref()
+      .is_some_and(|x| tokens_match(x.as_bytes(), token.as_bytes()));
+   host_is_us && knows_token
+}
+
+/// Compares without stopping at the first difference, so that the time taken doesn't say how much of a guess was right
+fn tokens_match(a: &[u8], b: &[u8]) -> bool {
+   a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+   let content_type = if body.is_empty() {
+      ""
+   } else {
+      "Content-Type: application/json\r\n"
+   };
+   write!(
+      stream,
+      "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+      status,
+      content_type,
+      body.len(),
+      body
+   )?;
+   stream.flush()
+}
+
+fn json_string_array(strings: &[Option<String>]) -> String {
+   let mut json = String::from("[");
+   for (i, s) in strings.iter().enumerate() {
+      if i > 0 {
+         json.push(',');
+      }
+      let s = match s {
+         Some(s) => s,
+         None => {
+            json.push_str("null");
+            continue;
+         }
+      };
+      json.push('"');
+      for c in s.chars() {
+         match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+         }
+      }
+      json.push('"');
+   }
+   json.push(']');
+   json
+}
+
+#[cfg(test)]
+mod test {
+   use super::*;
+
+   #[test]
+   fn escapes_json() {
+      let strings = vec![
+         Some("plain".to_owned()),
+         Some("quote \" and \\ slash".to_owned()),
+         None,
+         Some("two\r\nlines\u{1}".to_owned()),
+      ];
+      assert_eq!(
+         json_string_array(&strings),
+         r#"["plain","quote \" and \\ slash",null,"two\r\nlines\u0001"]"#
+      );
+      assert_eq!(json_string_array(&[]), "[]");
+   }
+
+   fn head_of(request: &str) -> Option<RequestHead> {
+      read_head(&mut request.as_bytes()).unwrap()
+   }
+
+   #[test]
+   fn reads_head() {
+      let head = head_of(
+         "POST /push HTTP/1.1\r\nHost: 127.0.0.1:7171\r\nX-Ripclip-Token: abc\r\nContent-Length: 5\r\n\r\nhello",
+      )
+      .unwrap();
+      assert_eq!(head.method, "POST");
+      assert_eq!(head.path, "/push");
+      assert_eq!(head.content_length, 5);
+      assert_eq!(head.host.as_deref(), Some("127.0.0.1:7171"));
+      assert_eq!(head.token.as_deref(), Some("abc"));
+      assert!(!head.has_origin);
+
+      let long_header = format!(
+         "GET /stack HTTP/1.1\r\nX-Padding: {}\r\n\r\n",
+         "a".repeat(MAX_HEAD_BYTES as usize)
+      );
+      assert!(head_of(&long_header).is_none());
+   }
+
+   #[test]
+   fn only_allows_scripts_with_the_token() {
+      let allowed = RequestHead {
+         host: Some("localhost:7171".to_owned()),
+         token: Some("abc".to_owned()),
+         ..Default::default()
+      };
+      assert!(is_allowed(&allowed, 7171, "abc"));
+      assert!(!is_allowed(&allowed, 7171, "abd"));
+      assert!(!is_allowed(&allowed, 7171, "abcd"));
+      assert!(!is_allowed(&allowed, 8080, "abc"));
+      assert!(!is_allowed(
+         &RequestHead {
+            has_origin: true,
+            ..allowed
+         },
+         7171,
+         "abc"
+      ));
+      let rebound = RequestHead {
+         host: Some("evil.example:7171".to_owned()),
+         token: Some("abc".to_owned()),
+         ..Default::default()
+      };
+      assert!(!is_allowed(&rebound, 7171, "abc"));
+      let no_token = RequestHead {
+         host: Some("127.0.0.1:7171".to_owned()),
+         ..Default::default()
+      };
+      assert!(!is_allowed(&no_token, 7171, "abc"));
+   }
+}
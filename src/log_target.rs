@@ -0,0 +1,13 @@
+//! `log` targets for the parts of ripclip that are worth tracing on their own, e.g. `RUST_LOG=ripclip::push=trace`.
+//! Everything else logs under plain `ripclip`, and `RUST_LOG=ripclip=trace` still shows it all
+
+/// Capturing copies onto the stack
+pub const PUSH: &str = "ripclip::push";
+pub const POP: &str = "ripclip::pop";
+pub const SWAP: &str = "ripclip::swap";
+pub const CLEAR: &str = "ripclip::clear";
+/// Registering and toggling keybindings
+pub const HOTKEY: &str = "ripclip::hotkey";
+/// Opening, reading and writing the clipboard itself
+pub const CLIPBOARD: &str = "ripclip::clipboard";
+pub const CONFIG: &str = "ripclip::config";
@@ -4,125 +4,906 @@
 extern crate bitflags;
 extern crate dirs;
 extern crate pretty_env_logger;
+extern crate regex;
 #[macro_use]
 extern crate log;
 extern crate winapi;
 
+mod clipboard;
 mod config;
+#[cfg(feature = "http")]
+mod http;
+mod log_target;
+mod stack;
 mod win;
 
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
 
-const POP_MENU_ID: usize = 100;
-const SWAP_MENU_ID: usize = 101;
-const CLEAR_MENU_ID: usize = 102;
-const EXIT_MENU_ID: usize = 103;
-const RELOAD_MENU_ID: usize = 104;
+use stack::{ClipboardItem, ClipboardStack};
+
+/// A command from the tray menu
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MenuId {
+   Pop,
+   Swap,
+   Clear,
+   Exit,
+   Reload,
+   ShowBytes,
+   ShowConfig,
+   CopyDiagnostics,
+   Dedupe,
+   ReloadKeybindings,
+   SaveConfig,
+   TogglePause,
+   ClearButTop,
+   /// Turns the keybinding with the given hotkey id off or back on
+   ToggleHotkey(u16),
+   /// An action on the history entry at the given depth (distance from the top of the stack)
+   History(usize, HistoryAction),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HistoryAction {
+   Cut = 0,
+   Remove = 1,
+   SwapWithTop = 2,
+}
+
+// Each keybinding's toggle has this plus its hotkey id
+const TOGGLE_HOTKEY_MENU_ID_BASE: usize = 200;
+// Fixed actions have ids below this. Each entry in the history submenu gets a block of
+// HISTORY_ACTION_COUNT ids, starting from here
+const HISTORY_MENU_ID_BASE: usize = 1000;
+const HISTORY_ACTION_COUNT: usize = 3;
+
+impl From<MenuId> for usize {
+   fn from(id: MenuId) -> usize {
+      match id {
+         MenuId::Pop => 100,
+         MenuId::Swap => 101,
+         MenuId::Clear => 102,
+         MenuId::Exit => 103,
+         MenuId::Reload => 104,
+         MenuId::ShowBytes => 105,
+         MenuId::ShowConfig => 106,
+         MenuId::CopyDiagnostics => 107,
+         MenuId::Dedupe => 108,
+         MenuId::ReloadKeybindings => 109,
+         MenuId::SaveConfig => 110,
+         MenuId::TogglePause => 111,
+         MenuId::ClearButTop => 112,
+         MenuId::ToggleHotkey(id) => TOGGLE_HOTKEY_MENU_ID_BASE + usize::from(id),
+         MenuId::History(depth, action) => HISTORY_MENU_ID_BASE + depth * HISTORY_ACTION_COUNT + action as usize,
+      }
+   }
+}
+
+impl MenuId {
+   /// The inverse of `usize::from`, for the id received with `WM_COMMAND`
+   fn from_command(id: usize) -> Option<MenuId> {
+      Some(match id {
+         100 => MenuId::Pop,
+         101 => MenuId::Swap,
+         102 => MenuId::Clear,
+         103 => MenuId::Exit,
+         104 => MenuId::Reload,
+         105 => MenuId::ShowBytes,
+         106 => MenuId::ShowConfig,
+         107 => MenuId::CopyDiagnostics,
+         108 => MenuId::Dedupe,
+         109 => MenuId::ReloadKeybindings,
+         110 => MenuId::SaveConfig,
+         111 => MenuId::TogglePause,
+         112 => MenuId::ClearButTop,
+         id if id > TOGGLE_HOTKEY_MENU_ID_BASE && id < TOGGLE_HOTKEY_MENU_ID_BASE + 100 => {
+            MenuId::ToggleHotkey((id - TOGGLE_HOTKEY_MENU_ID_BASE) as u16)
+         }
+         id if id >= HISTORY_MENU_ID_BASE => {
+            let offset = id - HISTORY_MENU_ID_BASE;
+            let action = match offset % HISTORY_ACTION_COUNT {
+               0 => HistoryAction::Cut,
+               1 => HistoryAction::Remove,
+               _ => HistoryAction::SwapWithTop,
+            };
+            MenuId::History(offset / HISTORY_ACTION_COUNT, action)
+         }
+         _ => return None,
+      })
+   }
+}
+
+/// Keeps the submenu a manageable size
+const HISTORY_MENU_MAX_ENTRIES: usize = 50;
+/// Menu command ids are 16 bits, so deeper entries can't be given ids of their own. They aren't listed even when
+/// they'd sort into the first `HISTORY_MENU_MAX_ENTRIES` by use
+const HISTORY_MENU_MAX_DEPTH: usize = (u16::MAX as usize + 1 - HISTORY_MENU_ID_BASE) / HISTORY_ACTION_COUNT;
+/// In characters
+const HISTORY_PREVIEW_LENGTH: usize = 40;
+/// Once this many texts have use counts, counts for texts no longer on the stack are dropped
+const USE_COUNTS_MAX_ENTRIES: usize = 1000;
 
 const POP_HOTKEY_ID: u16 = 1;
 const SWAP_HOTKEY_ID: u16 = 2;
 const CLEAR_HOTKEY_ID: u16 = 3;
+const PINGPONG_HOTKEY_ID: u16 = 4;
+const DEDUPE_HOTKEY_ID: u16 = 5;
+const PEEK_HOTKEY_ID: u16 = 6;
+/// The select keybindings use this and the 8 ids after it, in order
+const FIRST_SELECT_HOTKEY_ID: u16 = 7;
+const POP_TO_WINDOW_HOTKEY_ID: u16 = 16;
+const PUSH_HOTKEY_ID: u16 = 17;
+const UNDO_HOTKEY_ID: u16 = 18;
+const BROWSE_HOTKEY_ID: u16 = 19;
+const PAUSE_HOTKEY_ID: u16 = 20;
+const CLEAR_BUT_TOP_HOTKEY_ID: u16 = 21;
+
+const AUTO_CLEAR_TIMER_ID: usize = 1;
+const CONFIG_RELOAD_TIMER_ID: usize = 2;
+const POP_TO_WINDOW_TIMER_ID: usize = 3;
+/// Editors often write a file more than once when saving it, so a reload waits for the changes to settle
+const CONFIG_RELOAD_DELAY: Duration = Duration::from_millis(300);
+
+/// Sent by the tray icon, with the event in the low word of the lParam
+const TRAY_CALLBACK_MESSAGE: u32 = 100;
+
+/// Posted to the main window when there are `AppMessage`s waiting
+const APP_MESSAGE: u32 = winapi::um::winuser::WM_APP;
+/// Posted to the main window when the configuration file changes
+const CONFIG_CHANGED_MESSAGE: u32 = winapi::um::winuser::WM_APP + 2;
+/// Posted to the browse window when an item is double-clicked
+const BROWSE_CHOOSE_MESSAGE: u32 = winapi::um::winuser::WM_APP + 3;
+/// Posted to the main window when the theme or light/dark mode changes
+const THEME_CHANGED_MESSAGE: u32 = winapi::um::winuser::WM_APP + 4;
+
+/// The size of the browse window, in pixels
+const BROWSE_WIDTH: i32 = 480;
+const BROWSE_HEIGHT: i32 = 360;
+
+/// How many lines of a hex dump fit comfortably in a message box
+const HEX_PREVIEW_LINES: usize = 32;
+
+/// How soon after one of our own writes an emptied clipboard is blamed on remote desktop redirection
+const RDP_GLITCH_WINDOW: Duration = Duration::from_secs(2);
+/// Minimum time between re-asserting the top of the stack, so that we don't fight legitimate clears
+const RDP_REASSERT_INTERVAL: Duration = Duration::from_secs(5);
+/// The window `max_pushes_per_second` applies to
+const THROTTLE_WINDOW: Duration = Duration::from_secs(1);
+/// How long popping into a window waits for the keybinding's modifiers to be let go before pasting
+const MODIFIER_RELEASE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long popping into a window leaves the pasted item in the clipboard before popping it. `SendInput` only queues
+/// Control + V, and the window reads the clipboard whenever it gets around to handling it
+const POP_TO_WINDOW_PASTE_DELAY: Duration = Duration::from_millis(500);
+/// How many times the tray icon is tried at startup. Started with Windows, ripclip can be running before the taskbar
+const STARTUP_TRAY_ICON_ATTEMPTS: u32 = 5;
+const TRAY_ICON_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+enum Command {
+   Run,
+   ExportBundle(PathBuf),
+   ImportBundle(PathBuf),
+   DumpConfig,
+}
+
+struct Args {
+   command: Command,
+   portable: bool,
+   /// Where to read the configuration from instead of the default location
+   config_path: Option<PathBuf>,
+}
+
+fn parse_args() -> Args {
+   let usage = || -> ! {
+      eprintln!("Usage: ripclip [--portable] [--config <file>] [--export-bundle <file> | --import-bundle <file> | --dump-config]");
+      std::process::exit(-1);
+   };
+
+   let mut parsed = Args {
+      command: Command::Run,
+      portable: config::has_portable_marker(),
+      config_path: std::env::var_os("RIPCLIP_CONFIG").map(PathBuf::from),
+   };
+   let mut args = std::env::args().skip(1);
+   while let Some(arg) = args.next() {
+      match arg.as_str() {
+         "--portable" => parsed.portable = true,
+         "--config" => parsed.config_path = Some(args.next().unwrap_or_else(|| usage()).into()),
+         "--export-bundle" => parsed.command = Command::ExportBundle(args.next().unwrap_or_else(|| usage()).into()),
+         "--import-bundle" => parsed.command = Command::ImportBundle(args.next().unwrap_or_else(|| usage()).into()),
+         "--dump-config" => parsed.command = Command::DumpConfig,
+         _ => usage(),
+      }
+   }
+   parsed
+}
+
+/// Actions that other threads can ask the main loop to perform. They are handled in order,
+/// alongside window messages, so nothing else needs to lock the stack
+#[allow(dead_code)] // Only the control server sends these, and nothing sends Reload yet
+enum AppMessage {
+   Push(String),
+   Pop,
+   Clear,
+   Reload,
+   /// Replies with the text of every item (None for images), top of the stack first
+   Stack(mpsc::Sender<Vec<Option<String>>>),
+}
+
+/// Hands `AppMessage`s to the main loop from any thread
+#[derive(Clone)]
+struct AppMessageSender {
+   sender: mpsc::Sender<AppMessage>,
+   target: win::MessageTarget,
+}
+
+#[cfg_attr(not(feature = "http"), allow(dead_code))] // Only the control server runs on another thread
+fn post_app_message(sender: &AppMessageSender, message: AppMessage) {
+   if sender.sender.send(message).is_err() {
+      // The main loop is gone, so there's nobody to handle it
+      return;
+   }
+   if let Err(e) = sender.target.post_message(APP_MESSAGE) {
+      warn!("Failed to notify the main loop of an app message: {}", e);
+   }
+}
+
+/// A change ripclip made to the clipboard itself
+#[derive(Clone, Copy)]
+struct OwnWrite {
+   sequence_number: u32,
+   time: Instant,
+}
+
+impl OwnWrite {
+   fn now() -> OwnWrite {
+      OwnWrite {
+         sequence_number: win::get_clipboard_sequence_number(),
+         time: Instant::now(),
+      }
+   }
+}
 
 fn main() {
    pretty_env_logger::init();
 
-   let mut config = match config::load_config() {
-      Ok(config) => config,
+   let args = parse_args();
+   let bundle_result = match &args.command {
+      Command::Run | Command::DumpConfig => None,
+      Command::ExportBundle(path) => Some(
+         config::export_bundle(path, args.config_path.clone(), args.portable)
+            .map(|()| format!("Exported the configuration to {}", path.display())),
+      ),
+      Command::ImportBundle(path) => Some(
+         config::import_bundle(path, args.config_path.clone(), args.portable)
+            .map(|()| format!("Imported the configuration from {}", path.display())),
+      ),
+   };
+   if let Some(result) = bundle_result {
+      // As with --dump-config, a message box stands in for the console if ripclip wasn't run from one
+      let console = win::attach_parent_console().is_ok();
+      match result {
+         Ok(text) if console => println!("{}", text),
+         Ok(text) => {
+            if let Err(e) = win::show_info("ripclip", &text) {
+               warn!(target: log_target::CONFIG, "Failed to show bundle result: {}", e);
+            }
+         }
+         Err(e) => {
+            if console {
+               eprintln!("{}", e);
+            } else if let Err(e) = win::show_error("ripclip", &e.to_string()) {
+               warn!(target: log_target::CONFIG, "Failed to show bundle error: {}", e);
+            }
+            std::process::exit(-1);
+         }
+      }
+      return;
+   }
+
+   let (mut config, config_warning) = match config::load_config(args.config_path.clone(), args.portable) {
+      Ok(loaded) => loaded,
       Err(e) => {
-         eprintln!("Failed to parse config: {}", e);
+         // There's no console to print to, so the error would otherwise go unseen
+         let text = format!("Failed to parse config: {}", e);
+         eprintln!("{}", text);
+         if let Err(e) = win::show_error("ripclip failed to start", &text) {
+            warn!(target: log_target::CONFIG, "Failed to show config error: {}", e);
+         }
          std::process::exit(-1);
       }
    };
 
-   let module = win::get_module_handle_ex().unwrap();
-   let class = win::register_class_ex(&module, Some(on_message), "ripclip_class").unwrap();
+   if let Command::DumpConfig = args.command {
+      let text = config.to_config_string();
+      match win::attach_parent_console() {
+         Ok(()) => {
+            print!("{}", text);
+            if let Some(warning) = config_warning {
+               eprintln!("{}", warning);
+            }
+         }
+         Err(e) => {
+            // Not run from a console (e.g. from a shortcut), so there's nowhere to print to
+            trace!(target: log_target::CONFIG, "Failed to attach to the parent console: {}", e);
+            let text = match config_warning {
+               Some(warning) => format!("{}\n{}", text, warning),
+               None => text,
+            };
+            if let Err(e) = win::show_message_box("ripclip - Configuration", &text) {
+               warn!(target: log_target::CONFIG, "Failed to show configuration: {}", e);
+            }
+         }
+      }
+      return;
+   }
+
+   let module = win::get_module_handle_ex().unwrap_or_else(|e| startup_failed("Failed to get the module handle", e));
+   let class = win::register_class_ex(&module, Some(on_message), "ripclip_class", win::ClassBackground::None)
+      .unwrap_or_else(|e| startup_failed("Failed to register the window class", e));
 
+   // Either way, the clipboard listener and hotkeys work the same. Without WS_VISIBLE, the hidden window is never shown,
+   // and WS_EX_TOOLWINDOW keeps it out of the taskbar and Alt+Tab
+   let (ex_style, parent) = match config.window_type {
+      config::WindowType::MessageOnly => (0x0000_0000, win::WindowParent::MessageOnly),
+      config::WindowType::Hidden => (winapi::um::winuser::WS_EX_TOOLWINDOW, win::WindowParent::NoParent),
+   };
    let window = win::create_window_ex(
-      0x0000_0000,
+      ex_style,
       &class,
       winapi::um::winuser::WS_MINIMIZE | winapi::um::winuser::WS_DISABLED,
       0,
       0,
       0,
       0,
-      &win::WindowParent::MessageOnly,
+      &parent,
    )
-   .unwrap();
-
-   let mut menu = win::create_popup_menu().unwrap();
-   menu.append_text(POP_MENU_ID, "Pop").unwrap();
-   menu.append_text(SWAP_MENU_ID, "Swap").unwrap();
-   menu.append_text(CLEAR_MENU_ID, "Clear").unwrap();
-   menu.append_line_break(1).unwrap();
-   menu.append_text(RELOAD_MENU_ID, "Reload Configuration").unwrap();
-   menu.append_text(EXIT_MENU_ID, "Exit").unwrap();
-
-   let mut clipboard_stack: VecDeque<win::ClipboardText> = if let Some(max_stack_size) = config.max_stack_size {
-      VecDeque::with_capacity(max_stack_size)
-   } else {
-      VecDeque::new()
+   .unwrap_or_else(|e| startup_failed("Failed to create the window", e));
+
+   let mut clipboard_stack = ClipboardStack::new(config.max_stack_size);
+   // The last change we made to the clipboard ourselves, so that we don't capture it
+   let mut last_write: Option<OwnWrite> = None;
+   // When we last restored the top of the stack after remote desktop emptied the clipboard
+   let mut last_rdp_reassert: Option<Instant> = None;
+   // Whether ping-pong is currently showing the second item of the stack, rather than the top
+   let mut pingpong_second = false;
+   // How far down the stack the next peek goes
+   let mut peek_cursor: usize = 0;
+   // What the last pop or clear removed, until something new is copied
+   let mut undo_buffer: Option<clipboard::Undo> = None;
+   // What popping into a window pasted, until it's popped once the window has had time to read the clipboard
+   let mut pending_window_pop: Option<win::ClipboardEntry> = None;
+   // When each push within the last second happened, for `max_pushes_per_second`
+   let mut recent_pushes: VecDeque<Instant> = VecDeque::new();
+   let mut throttling = false;
+   // How many times each text has been brought back to the top of the stack, for `history_order = frequent`.
+   // Keyed by content so that counts survive the item being removed and copied again
+   let mut use_counts: HashMap<win::ClipboardEntry, u32> = HashMap::new();
+   // Whether the tray icon is currently the `indicate_full` one, if it's known to be up to date
+   let mut showing_full = Some(false);
+   // The stack depth and paused state the tray tooltip shows, if it shows them
+   let mut tooltip_state: Option<(usize, bool)> = None;
+   // While paused, copies aren't pushed
+   let mut paused = false;
+
+   let (app_sender, app_receiver) = mpsc::channel();
+   let message_sender = AppMessageSender {
+      sender: app_sender,
+      target: window.message_target(),
    };
-   let mut managing_clipboard = false;
 
    let mut _trayicon = if config.show_tray_icon {
-      Some(win::add_tray_icon(&window, 0, 100, "ripclip", &module).unwrap())
+      add_tray_icon(&window, &module, &config, STARTUP_TRAY_ICON_ATTEMPTS)
    } else {
       None
    };
 
-   win::add_clipboard_format_listener(&window).unwrap();
+   if let Some(warning) = config_warning {
+      notify(&_trayicon, &warning.to_string());
+   }
+
+   win::add_clipboard_format_listener(&window)
+      .unwrap_or_else(|e| startup_failed("Failed to start listening for clipboard changes", e));
+
+   let mut hotkeys = HotkeyState::default();
+   set_keybindings(&config, &window, &mut hotkeys);
+   if let Some(path) = config::config_file_path(args.config_path.clone(), args.portable) {
+      watch_config(path, window.message_target());
+   }
+   let mut modifier_hook = hook_skip_stack_modifier(&config, &module, &_trayicon);
+   set_start_with_windows(&config, &_trayicon);
 
-   set_keybindings(&config, &window);
+   if config.enable_http {
+      start_control_server(&config, &message_sender, &_trayicon);
+   }
 
    loop {
+      // Every message handled so far has had its chance to change the size of the stack
+      update_full_indicator(&config, &module, &_trayicon, &clipboard_stack, &mut showing_full);
+      update_tooltip(&_trayicon, &clipboard_stack, paused, &mut tooltip_state);
       let message = win::get_message(Some(&window), 0, 0).unwrap();
       match message.message {
          winapi::um::winuser::WM_HOTKEY => match message.w_param as u16 {
             POP_HOTKEY_ID => {
-               pop(&window, &mut clipboard_stack, &mut managing_clipboard);
-            }
-            SWAP_HOTKEY_ID => {
-               swap(&window, &mut clipboard_stack, managing_clipboard);
+               let mut clipboard = WinClipboard::new(&window, &config, &mut last_write);
+               let result = if config.pop_consumes {
+                  clipboard::pop(&mut clipboard, &mut clipboard_stack, &mut undo_buffer)
+               } else {
+                  clipboard::paste_top(&mut clipboard, &mut clipboard_stack)
+               };
+               match result {
+                  Ok(()) => {
+                     record_use(&mut use_counts, &clipboard_stack);
+                     restart_auto_clear(&config, &window);
+                  }
+                  Err(e) => clipboard_failed(&_trayicon, e),
+               }
             }
+            SWAP_HOTKEY_ID => match clipboard::swap(
+               &mut WinClipboard::new(&window, &config, &mut last_write),
+               &mut clipboard_stack,
+            ) {
+               Ok(()) if clipboard_stack.managing_clipboard() => record_use(&mut use_counts, &clipboard_stack),
+               Ok(()) => (),
+               Err(e) => clipboard_failed(&_trayicon, e),
+            },
             CLEAR_HOTKEY_ID => {
-               clear(&window, &mut clipboard_stack, &mut managing_clipboard);
+               if let Err(e) = clipboard::clear(
+                  &mut WinClipboard::new(&window, &config, &mut last_write),
+                  &mut clipboard_stack,
+                  &mut undo_buffer,
+               ) {
+                  clipboard_failed(&_trayicon, e);
+               }
+            }
+            PINGPONG_HOTKEY_ID => {
+               ping_pong(
+                  &window,
+                  &config,
+                  &mut clipboard_stack,
+                  &mut pingpong_second,
+                  &mut last_write,
+               );
+            }
+            DEDUPE_HOTKEY_ID => {
+               dedupe_now(&_trayicon, &mut clipboard_stack);
+            }
+            PEEK_HOTKEY_ID => {
+               peek(
+                  &window,
+                  &config,
+                  &mut clipboard_stack,
+                  &mut peek_cursor,
+                  &mut last_write,
+               );
+            }
+            POP_TO_WINDOW_HOTKEY_ID => {
+               // The previous paste has to come off the stack first, or this would paste the same thing again
+               if let Some(pasted) = pending_window_pop.take() {
+                  if let Err(e) = win::kill_timer(&window, POP_TO_WINDOW_TIMER_ID) {
+                     warn!(target: log_target::POP, "Failed to stop pop to window timer: {}", e);
+                  }
+                  if finish_pop_to_window(
+                     &window,
+                     &config,
+                     &_trayicon,
+                     &mut clipboard_stack,
+                     &mut undo_buffer,
+                     &mut last_write,
+                     &pasted,
+                  ) {
+                     record_use(&mut use_counts, &clipboard_stack);
+                  }
+               }
+               let pasted = pop_to_window(&window, &config, &_trayicon, &mut clipboard_stack, &mut last_write);
+               if let Some(pasted) = pasted {
+                  let delay = POP_TO_WINDOW_PASTE_DELAY.as_millis() as u32;
+                  match win::set_timer(&window, POP_TO_WINDOW_TIMER_ID, delay) {
+                     Ok(()) => pending_window_pop = Some(pasted),
+                     Err(e) => warn!(target: log_target::POP, "Failed to start pop to window timer: {}", e),
+                  }
+               }
+            }
+            PUSH_HOTKEY_ID => {
+               if !win::is_clipboard_format_available(win::ClipboardFormat::UnicodeText) {
+                  trace!(target: log_target::PUSH, "Nothing to push, as the clipboard doesn't hold text");
+                  continue;
+               }
+               match clipboard::push(
+                  &mut WinClipboard::new(&window, &config, &mut last_write),
+                  &mut clipboard_stack,
+                  &config,
+               ) {
+                  Ok(()) => {
+                     // Undoing an older clear would replace the stack, losing this push
+                     undo_buffer = None;
+                     pingpong_second = false;
+                     peek_cursor = 0;
+                     restart_auto_clear(&config, &window);
+                  }
+                  Err(e) => clipboard_failed(&_trayicon, e),
+               }
+            }
+            UNDO_HOTKEY_ID => match clipboard::undo(
+               &mut WinClipboard::new(&window, &config, &mut last_write),
+               &mut clipboard_stack,
+               &mut undo_buffer,
+            ) {
+               Ok(()) => {
+                  pingpong_second = false;
+                  peek_cursor = 0;
+                  restart_auto_clear(&config, &window);
+               }
+               Err(e) => clipboard_failed(&_trayicon, e),
+            },
+            BROWSE_HOTKEY_ID => {
+               if let Err(e) = browse_history(
+                  &module,
+                  &window,
+                  &config,
+                  &mut clipboard_stack,
+                  &mut use_counts,
+                  &mut last_write,
+               ) {
+                  warn!("Failed to show the browse window: {}", e);
+               }
+            }
+            PAUSE_HOTKEY_ID => {
+               toggle_pause(&window, &config, &mut paused);
+            }
+            CLEAR_BUT_TOP_HOTKEY_ID => {
+               clipboard::clear_but_top(&mut clipboard_stack, &mut undo_buffer);
+            }
+            x if (FIRST_SELECT_HOTKEY_ID..FIRST_SELECT_HOTKEY_ID + 9).contains(&x) => {
+               let depth = usize::from(x - FIRST_SELECT_HOTKEY_ID);
+               select(&window, &config, &mut clipboard_stack, depth, &mut last_write);
             }
             x => {
-               warn!("Unknown hotkey {}", x);
+               warn!(target: log_target::HOTKEY, "Unknown hotkey {}", x);
             }
          },
          winapi::um::winuser::WM_CLIPBOARDUPDATE => {
-            trace!("Clipboard updated!");
-            if win::is_clipboard_format_available(win::ClipboardFormat::UnicodeText) {
-               trace!("Unicode text available");
-               win::remove_clipboard_format_listener(&window).unwrap();
-               let clipboard_text = {
-                  let clipboard = open_clipboard_with_backoff(&window).unwrap();
-                  let text_buf = clipboard.get_text().unwrap();
-                  let owned_clipboard = clipboard.empty().unwrap();
-                  owned_clipboard.set_text(text_buf.clone()).unwrap();
-                  text_buf
+            trace!(target: log_target::PUSH, "Clipboard updated!");
+            let sequence_number = win::get_clipboard_sequence_number();
+            if clipboard::is_own_write(last_write.map(|x| x.sequence_number), sequence_number) {
+               // Our own changes are delivered like anyone else's, but leave the sequence number where we recorded it.
+               // If anything changed the clipboard after us, the number has moved on and the update is handled
+               trace!(target: log_target::PUSH, "Ignoring clipboard update caused by ripclip");
+               continue;
+            }
+            if paused {
+               // The listener stays registered, so that there's nothing to set up again on resuming
+               trace!(target: log_target::PUSH, "Ignoring clipboard update while paused");
+               clipboard_stack.set_managing_clipboard(false);
+               continue;
+            }
+            if excluded_from_history(&window, &config) {
+               trace!(target: log_target::PUSH, "Ignoring clipboard update marked as not to be recorded");
+               clipboard_stack.set_managing_clipboard(false);
+               continue;
+            }
+            if config.rdp_resilience
+               && clipboard_stack.managing_clipboard()
+               && !clipboard_stack.is_empty()
+               && last_rdp_reassert.is_none_or(|x| x.elapsed() >= RDP_REASSERT_INTERVAL)
+               && emptied_after_own_write(last_write, sequence_number)
+            {
+               trace!(target: log_target::CLIPBOARD, "Clipboard was emptied right after we set it, re-asserting top of stack");
+               last_rdp_reassert = Some(Instant::now());
+               let top = clipboard_stack.peek().map(|x| x.entry.clone());
+               if let Err(e) = set_clipboard(&window, &config, top, &mut last_write) {
+                  warn!(target: log_target::CLIPBOARD, "Failed to re-assert top of stack: {}", e);
+               }
+               continue;
+            }
+            let has_text = win::is_clipboard_format_available(win::ClipboardFormat::UnicodeText);
+            if has_text || win::is_clipboard_format_available(win::ClipboardFormat::Dib) {
+               if has_text {
+                  trace!(target: log_target::PUSH, "Unicode text available");
+               } else {
+                  trace!(target: log_target::PUSH, "Image available");
+               }
+               if modifier_hook.as_ref().is_some_and(|x| x.is_held()) {
+                  trace!(target: log_target::PUSH, "Ignoring push because skip_stack_modifier is held");
+                  clipboard_stack.set_managing_clipboard(false);
+                  continue;
+               }
+               let needs_source =
+                  config.track_source || !config.capture_only_from.is_empty() || !config.ignore_processes.is_empty();
+               let source = if needs_source { clipboard_source_process() } else { None };
+               if !config.should_capture_from(source.as_deref()) {
+                  trace!(target: log_target::PUSH, "Ignoring push from {:?} due to process filters", source);
+                  clipboard_stack.set_managing_clipboard(false);
+                  continue;
+               }
+               let source = if config.track_source { source } else { None };
+               let clipboard = match open_clipboard_with_backoff(&window, &config.clipboard_retry_errors) {
+                  Ok(clipboard) => clipboard,
+                  Err(e) => {
+                     warn!(target: log_target::PUSH, "Failed to open clipboard to read the copy: {}", e);
+                     clipboard_stack.set_managing_clipboard(false);
+                     continue;
+                  }
+               };
+               let format = if has_text {
+                  win::ClipboardFormat::UnicodeText
+               } else {
+                  win::ClipboardFormat::Dib
                };
-               win::add_clipboard_format_listener(&window).unwrap();
-               if config.prevent_duplicate_push && Some(&clipboard_text) == clipboard_stack.back() {
-                  trace!("Ignoring push because it was a duplicate");
+               if !within_read_limit(&config, &clipboard, format as u32) {
+                  clipboard_stack.set_managing_clipboard(false);
+                  continue;
+               }
+               let entry = if has_text {
+                  match clipboard.get_text() {
+                     Ok(text) => win::ClipboardEntry::Text(text, read_html(&config, &clipboard)),
+                     Err(e) => {
+                        warn!(target: log_target::PUSH, "Failed to read text from clipboard: {}", e);
+                        clipboard_stack.set_managing_clipboard(false);
+                        continue;
+                     }
+                  }
+               } else {
+                  match clipboard.get_bitmap() {
+                     Ok(dib) => win::ClipboardEntry::Image(dib),
+                     Err(e) => {
+                        warn!(target: log_target::PUSH, "Failed to read image from clipboard: {}", e);
+                        clipboard_stack.set_managing_clipboard(false);
+                        continue;
+                     }
+                  }
+               };
+               // Checked before taking over the clipboard, so that an ignored copy is left exactly as it was copied
+               if let Some(reason) = ignore_reason(&config, &entry) {
+                  trace!(target: log_target::PUSH, "Ignoring push because {}", reason);
+                  clipboard_stack.set_managing_clipboard(false);
+                  continue;
+               }
+               match &entry {
+                  win::ClipboardEntry::Text(text, html) => {
+                     let taken_over = take_over_text(&config, clipboard, text, html.as_deref());
+                     // Even a failed take over may have emptied the clipboard, and the update that causes is ours
+                     last_write = Some(OwnWrite::now());
+                     if let Err(e) = taken_over {
+                        warn!(target: log_target::PUSH, "Failed to place the copy back in the clipboard: {}", e);
+                        clipboard_stack.set_managing_clipboard(false);
+                        continue;
+                     }
+                  }
+                  // Images are left on the clipboard as they are, along with the other formats Windows made from them
+                  win::ClipboardEntry::Image(_) => drop(clipboard),
+               }
+               if let Some(source) = &source {
+                  trace!(target: log_target::PUSH, "Clipboard contents came from {}", source);
+               }
+               let captured_at = if config.show_timestamps {
+                  Some(SystemTime::now())
                } else {
-                  if Some(clipboard_stack.len()) == config.max_stack_size {
-                     clipboard_stack.pop_front();
+                  None
+               };
+               let item = ClipboardItem {
+                  entry,
+                  source,
+                  captured_at,
+               };
+               let captured = clipboard::capture(&mut clipboard_stack, &config, item, || {
+                  let throttled = throttle_push(&config, &mut recent_pushes);
+                  if throttled && !throttling {
+                     trace!(target: log_target::PUSH, "Throttling pushes, as there have been too many in the last second");
+                  }
+                  throttling = throttled;
+                  throttled
+               });
+               match captured {
+                  clipboard::Capture::Skipped => (),
+                  clipboard::Capture::Dropped => continue,
+                  clipboard::Capture::Refreshed | clipboard::Capture::Pushed | clipboard::Capture::Replaced => {
+                     undo_buffer = None;
+                     pingpong_second = false;
+                     peek_cursor = 0;
                   }
-                  clipboard_stack.push_back(clipboard_text);
-                  trace!("Pushed clipboard contents onto stack");
                }
-               managing_clipboard = true;
+               restart_auto_clear(&config, &window);
+            } else if config.respect_os_history
+               && !clipboard_stack.is_empty()
+               && !emptied_after_own_write(last_write, sequence_number)
+               && win::count_clipboard_formats() == Ok(0)
+            {
+               // Windows doesn't announce the clipboard history being cleared, but clearing it from Win + V
+               // empties the clipboard too, so that's the closest thing to watch for. Remote desktop emptying
+               // the clipboard right after we set it (see rdp_resilience) doesn't count. Anything else emptying the
+               // clipboard looks the same, so the stack can be brought back with undo
+               undo_buffer = Some(clipboard::Undo::Clear(clipboard_stack.clear()));
+               pingpong_second = false;
+               peek_cursor = 0;
+               trace!(target: log_target::PUSH, "Clipboard was emptied by another application, cleared stack");
             } else {
-               managing_clipboard = false;
+               clipboard_stack.set_managing_clipboard(false);
             }
          }
          winapi::um::winuser::WM_CONTEXTMENU => {
-            let x = winapi::shared::windowsx::GET_X_LPARAM(message.w_param as isize);
-            let y = winapi::shared::windowsx::GET_Y_LPARAM(message.w_param as isize);
-            win::set_foreground_window(&window).unwrap();
-            win::draw_popup_menu(&menu, x, y, &window).unwrap();
+            show_menu(
+               &config,
+               &window,
+               &clipboard_stack,
+               &use_counts,
+               &hotkeys.disabled,
+               paused,
+               message.w_param,
+            );
+         }
+         // Single clicks don't do anything, so there's no need to wait out the double-click time to tell them apart
+         winapi::um::winuser::WM_LBUTTONDBLCLK => match config.tray_double_click {
+            config::TrayAction::Pop => match clipboard::pop(
+               &mut WinClipboard::new(&window, &config, &mut last_write),
+               &mut clipboard_stack,
+               &mut undo_buffer,
+            ) {
+               Ok(()) => {
+                  record_use(&mut use_counts, &clipboard_stack);
+                  restart_auto_clear(&config, &window);
+               }
+               Err(e) => clipboard_failed(&_trayicon, e),
+            },
+            config::TrayAction::Swap => match clipboard::swap(
+               &mut WinClipboard::new(&window, &config, &mut last_write),
+               &mut clipboard_stack,
+            ) {
+               Ok(()) if clipboard_stack.managing_clipboard() => record_use(&mut use_counts, &clipboard_stack),
+               Ok(()) => (),
+               Err(e) => clipboard_failed(&_trayicon, e),
+            },
+            config::TrayAction::Menu => {
+               show_menu(
+                  &config,
+                  &window,
+                  &clipboard_stack,
+                  &use_counts,
+                  &hotkeys.disabled,
+                  paused,
+                  message.w_param,
+               );
+            }
+            config::TrayAction::Nothing => (),
+         },
+         APP_MESSAGE => {
+            while let Ok(app_message) = app_receiver.try_recv() {
+               match app_message {
+                  AppMessage::Push(text) => {
+                     let mut item = ClipboardItem::new(clipboard_text(&text));
+                     if config.show_timestamps {
+                        item.captured_at = Some(SystemTime::now());
+                     }
+                     // Filtered like a copy, so that pushing can't get around ignore_pattern and the rest
+                     if let Some(reason) = ignore_reason(&config, &item.entry) {
+                        trace!(target: log_target::PUSH, "Ignoring text pushed from another thread because {}", reason);
+                     } else {
+                        let captured = clipboard::capture(&mut clipboard_stack, &config, item, || false);
+                        // Unlike a copy, the text isn't on the clipboard yet
+                        let top = clipboard_stack.peek().map(|x| x.entry.clone());
+                        if let Err(e) = set_clipboard(&window, &config, top, &mut last_write) {
+                           warn!(target: log_target::PUSH, "Failed to place pushed text in clipboard: {}", e);
+                           clipboard_stack.set_managing_clipboard(false);
+                        }
+                        if captured != clipboard::Capture::Skipped {
+                           undo_buffer = None;
+                           pingpong_second = false;
+                           peek_cursor = 0;
+                        }
+                        restart_auto_clear(&config, &window);
+                     }
+                  }
+                  AppMessage::Pop => match clipboard::pop(
+                     &mut WinClipboard::new(&window, &config, &mut last_write),
+                     &mut clipboard_stack,
+                     &mut undo_buffer,
+                  ) {
+                     Ok(()) => {
+                        record_use(&mut use_counts, &clipboard_stack);
+                        restart_auto_clear(&config, &window);
+                     }
+                     Err(e) => clipboard_failed(&_trayicon, e),
+                  },
+                  AppMessage::Clear => {
+                     if let Err(e) = clipboard::clear(
+                        &mut WinClipboard::new(&window, &config, &mut last_write),
+                        &mut clipboard_stack,
+                        &mut undo_buffer,
+                     ) {
+                        clipboard_failed(&_trayicon, e);
+                     }
+                  }
+                  AppMessage::Stack(reply) => {
+                     let texts = clipboard_stack
+                        .iter()
+                        .map(|(_, x)| x.entry.as_text().map(|text| text.to_string()))
+                        .collect();
+                     // The requester may have given up waiting
+                     let _ = reply.send(texts);
+                  }
+                  AppMessage::Reload => {
+                     reload_config(
+                        &args,
+                        &window,
+                        &module,
+                        &mut config,
+                        &mut _trayicon,
+                        &mut modifier_hook,
+                        &mut hotkeys,
+                     );
+                     clipboard_stack.set_max_size(config.max_stack_size);
+                  }
+               }
+            }
+         }
+         winapi::um::winuser::WM_TIMER if message.w_param == AUTO_CLEAR_TIMER_ID => {
+            if let Err(e) = win::kill_timer(&window, AUTO_CLEAR_TIMER_ID) {
+               warn!(target: log_target::CLIPBOARD, "Failed to stop auto-clear timer: {}", e);
+            }
+            // The timer may have been set before a reload turned auto-clear off. A copy that wasn't pushed (or
+            // anything else ripclip didn't place there) belongs to the user, and is left alone
+            if config.clipboard_auto_clear_seconds.is_some() && clipboard_stack.managing_clipboard() {
+               // Goes through set_clipboard so that emptying the clipboard isn't captured as a copy. Popping
+               // restores the top of the stack, since the clipboard no longer shows it
+               match set_clipboard(&window, &config, None, &mut last_write) {
+                  Ok(()) => {
+                     clipboard_stack.set_managing_clipboard(false);
+                     trace!(target: log_target::CLIPBOARD, "Auto-cleared clipboard");
+                  }
+                  Err(e) => warn!(target: log_target::CLIPBOARD, "Failed to auto-clear clipboard: {}", e),
+               }
+            }
+         }
+         winapi::um::winuser::WM_TIMER if message.w_param == POP_TO_WINDOW_TIMER_ID => {
+            if let Err(e) = win::kill_timer(&window, POP_TO_WINDOW_TIMER_ID) {
+               warn!(target: log_target::POP, "Failed to stop pop to window timer: {}", e);
+            }
+            if let Some(pasted) = pending_window_pop.take() {
+               if finish_pop_to_window(
+                  &window,
+                  &config,
+                  &_trayicon,
+                  &mut clipboard_stack,
+                  &mut undo_buffer,
+                  &mut last_write,
+                  &pasted,
+               ) {
+                  record_use(&mut use_counts, &clipboard_stack);
+               }
+            }
+         }
+         CONFIG_CHANGED_MESSAGE => {
+            // Restarting the timer on every change waits out editors that save in more than one write
+            let delay = CONFIG_RELOAD_DELAY.as_millis() as u32;
+            if let Err(e) = win::set_timer(&window, CONFIG_RELOAD_TIMER_ID, delay) {
+               warn!(target: log_target::CONFIG, "Failed to start config reload timer: {}", e);
+            }
+         }
+         winapi::um::winuser::WM_TIMER if message.w_param == CONFIG_RELOAD_TIMER_ID => {
+            if let Err(e) = win::kill_timer(&window, CONFIG_RELOAD_TIMER_ID) {
+               warn!(target: log_target::CONFIG, "Failed to stop config reload timer: {}", e);
+            }
+            trace!(target: log_target::CONFIG, "Configuration file changed, reloading");
+            reload_config(
+               &args,
+               &window,
+               &module,
+               &mut config,
+               &mut _trayicon,
+               &mut modifier_hook,
+               &mut hotkeys,
+            );
+            clipboard_stack.set_max_size(config.max_stack_size);
+         }
+         THEME_CHANGED_MESSAGE => {
+            trace!("Apps theme is now {:?}", win::apps_theme());
+            // The tray icon and tooltip are set again at the top of the loop, so the taskbar redraws them for the
+            // new theme. Menus are built each time they're shown, so they pick up the new theme on their own
+            showing_full = None;
+            tooltip_state = None;
          }
          winapi::um::winuser::WM_QUIT => {
             break;
@@ -130,47 +911,107 @@ fn main() {
          winapi::um::winuser::WM_COMMAND => {
             if message.w_param & 0xFFFF_FFFF_0000_0000 == 0 {
                // Menu event
-               match message.w_param & 0x0000_0000_FFFF_FFFF {
-                  POP_MENU_ID => {
-                     pop(&window, &mut clipboard_stack, &mut managing_clipboard);
+               match MenuId::from_command(message.w_param & 0x0000_0000_FFFF_FFFF) {
+                  Some(MenuId::Pop) => match clipboard::pop(
+                     &mut WinClipboard::new(&window, &config, &mut last_write),
+                     &mut clipboard_stack,
+                     &mut undo_buffer,
+                  ) {
+                     Ok(()) => {
+                        record_use(&mut use_counts, &clipboard_stack);
+                        restart_auto_clear(&config, &window);
+                     }
+                     Err(e) => clipboard_failed(&_trayicon, e),
+                  },
+                  Some(MenuId::Swap) => match clipboard::swap(
+                     &mut WinClipboard::new(&window, &config, &mut last_write),
+                     &mut clipboard_stack,
+                  ) {
+                     Ok(()) if clipboard_stack.managing_clipboard() => record_use(&mut use_counts, &clipboard_stack),
+                     Ok(()) => (),
+                     Err(e) => clipboard_failed(&_trayicon, e),
+                  },
+                  Some(MenuId::Clear) => {
+                     if let Err(e) = clipboard::clear(
+                        &mut WinClipboard::new(&window, &config, &mut last_write),
+                        &mut clipboard_stack,
+                        &mut undo_buffer,
+                     ) {
+                        clipboard_failed(&_trayicon, e);
+                     }
+                  }
+                  Some(MenuId::ClearButTop) => {
+                     clipboard::clear_but_top(&mut clipboard_stack, &mut undo_buffer);
+                  }
+                  Some(MenuId::ShowBytes) => {
+                     show_bytes(&window, &config, &mut clipboard_stack, &mut last_write);
                   }
-                  SWAP_MENU_ID => {
-                     swap(&window, &mut clipboard_stack, managing_clipboard);
+                  Some(MenuId::ShowConfig) => {
+                     show_config(&window, &config, &mut clipboard_stack, &mut last_write);
                   }
-                  CLEAR_MENU_ID => {
-                     clear(&window, &mut clipboard_stack, &mut managing_clipboard);
+                  Some(MenuId::CopyDiagnostics) => {
+                     let report = diagnostics_report(&config, &clipboard_stack, &hotkeys);
+                     match set_clipboard(&window, &config, Some(clipboard_text(&report)), &mut last_write) {
+                        Ok(()) => {
+                           clipboard_stack.set_managing_clipboard(false);
+                           trace!(target: log_target::CLIPBOARD, "Placed diagnostics in clipboard");
+                        }
+                        Err(e) => clipboard_failed(&_trayicon, e),
+                     }
+                  }
+                  Some(MenuId::ToggleHotkey(id)) => {
+                     toggle_hotkey(&config, &window, &_trayicon, &mut hotkeys, id);
                   }
-                  EXIT_MENU_ID => {
+                  Some(MenuId::Dedupe) => {
+                     dedupe_now(&_trayicon, &mut clipboard_stack);
+                  }
+                  Some(MenuId::Exit) => {
                      break;
                   }
-                  RELOAD_MENU_ID => {
-                     match config::load_config() {
-                        Ok(new_config) => {
-                           if config.pop_keybinding.is_some() {
-                              win::unregister_hotkey(Some(&window), POP_HOTKEY_ID).unwrap();
-                           }
-                           if config.swap_keybinding.is_some() {
-                              win::unregister_hotkey(Some(&window), SWAP_HOTKEY_ID).unwrap();
-                           }
-                           if config.clear_keybinding.is_some() {
-                              win::unregister_hotkey(Some(&window), CLEAR_HOTKEY_ID).unwrap();
-                           }
-                           config = new_config;
-                           set_keybindings(&config, &window);
-                           // It's important the destructor is run before we create the new tray icon,
-                           // and that we destroy the tray icon if the prior configuration had it enabled
-                           _trayicon = None;
-                           if config.show_tray_icon {
-                              _trayicon = Some(win::add_tray_icon(&window, 0, 100, "ripclip", &module).unwrap());
+                  Some(MenuId::Reload) => {
+                     reload_config(
+                        &args,
+                        &window,
+                        &module,
+                        &mut config,
+                        &mut _trayicon,
+                        &mut modifier_hook,
+                        &mut hotkeys,
+                     );
+                     clipboard_stack.set_max_size(config.max_stack_size);
+                  }
+                  Some(MenuId::ReloadKeybindings) => {
+                     reload_keybindings(&args, &window, &mut config, &mut hotkeys);
+                  }
+                  Some(MenuId::SaveConfig) => {
+                     save_config(&args, &config, &_trayicon);
+                  }
+                  Some(MenuId::TogglePause) => {
+                     toggle_pause(&window, &config, &mut paused);
+                  }
+                  Some(MenuId::History(depth, action)) => {
+                     if depth >= clipboard_stack.len() {
+                        trace!(target: log_target::POP, "History entry {} no longer exists", depth);
+                        continue;
+                     }
+                     match action {
+                        HistoryAction::Cut => {
+                           if let Some(entry) = cut_at(&window, &config, &mut clipboard_stack, depth, &mut last_write) {
+                              count_use(&mut use_counts, &entry, &clipboard_stack);
                            }
-                           trace!("Successfully reloaded configuration");
                         }
-                        Err(e) => {
-                           eprintln!("Failed to parse config: {}", e);
+                        HistoryAction::Remove => {
+                           remove_at(&window, &config, &mut clipboard_stack, depth, &mut last_write);
+                        }
+                        HistoryAction::SwapWithTop => {
+                           // The entry that was at depth is the top now
+                           if swap_at(&window, &config, &mut clipboard_stack, depth, 0, &mut last_write) {
+                              record_use(&mut use_counts, &clipboard_stack);
+                           }
                         }
-                     };
+                     }
                   }
-                  _ => {
+                  None => {
                      warn!("Unknown menu command");
                      continue;
                   }
@@ -184,114 +1025,1540 @@ fn main() {
    }
 }
 
-fn set_keybindings(config: &config::Config, window: &win::WindowHandle) {
-   if let Some(hotkey) = &config.pop_keybinding {
-      win::register_hotkey(Some(&window), POP_HOTKEY_ID, hotkey.modifiers, hotkey.key).unwrap();
+/// Lists the whole stack, newest first, in a window of its own until an item is chosen or the window loses focus.
+/// Enter or double-clicking moves the item to the top of the stack (and into the clipboard), Delete removes it, and
+/// Escape closes the window. Everything else waits until the window closes
+fn browse_history(
+   module: &win::ModuleHandle,
+   window: &win::WindowHandle,
+   config: &config::Config,
+   clipboard_stack: &mut ClipboardStack,
+   use_counts: &mut HashMap<win::ClipboardEntry, u32>,
+   last_write: &mut Option<OwnWrite>,
+) -> Result<(), win::ErrorCode> {
+   let class = win::register_class_ex(
+      module,
+      Some(on_browse_message),
+      "ripclip_browse_class",
+      win::ClassBackground::SystemColor(winapi::um::winuser::COLOR_WINDOW),
+   )?;
+   // Centered on the monitor with the mouse, which is most likely the one being looked at
+   let (x, y) = win::get_cursor_pos()?;
+   let area = win::monitor_from_point(x, y).work_area()?;
+   let browse_window = win::create_window_ex(
+      winapi::um::winuser::WS_EX_TOOLWINDOW
+         | winapi::um::winuser::WS_EX_TOPMOST
+         | winapi::um::winuser::WS_EX_COMPOSITED,
+      &class,
+      winapi::um::winuser::WS_POPUP | winapi::um::winuser::WS_BORDER | winapi::um::winuser::WS_VISIBLE,
+      area.left + (area.right - area.left - BROWSE_WIDTH) / 2,
+      area.top + (area.bottom - area.top - BROWSE_HEIGHT) / 2,
+      BROWSE_WIDTH,
+      BROWSE_HEIGHT,
+      &win::WindowParent::NoParent,
+   )?;
+   let list = win::create_list_box(&browse_window)?;
+   for (_, item) in clipboard_stack.iter() {
+      if list.add_string(&item_preview(item, config)).is_err() {
+         warn!("Ran out of room to list the stack");
+         break;
+      }
+   }
+   list.set_selected(0);
+   let _ = win::set_foreground_window(&browse_window);
+   list.focus();
+
+   loop {
+      let message = win::get_message(Some(&browse_window), 0, 0)?;
+      let key = message.w_param as i32;
+      match message.message {
+         winapi::um::winuser::WM_CLOSE => break,
+         winapi::um::winuser::WM_KEYDOWN if key == winapi::um::winuser::VK_ESCAPE => break,
+         BROWSE_CHOOSE_MESSAGE => (),
+         winapi::um::winuser::WM_KEYDOWN if key == winapi::um::winuser::VK_RETURN => (),
+         winapi::um::winuser::WM_KEYDOWN if key == winapi::um::winuser::VK_DELETE => {
+            if let Some(depth) = list.selected() {
+               remove_at(window, config, clipboard_stack, depth, last_write);
+               list.delete_string(depth);
+               if !clipboard_stack.is_empty() {
+                  list.set_selected(depth.min(clipboard_stack.len() - 1));
+               }
+            }
+            continue;
+         }
+         _ => {
+            win::dispatch_message(&message);
+            continue;
+         }
+      }
+      // An item was chosen
+      if let Some(depth) = list.selected() {
+         move_to_top_at(window, config, clipboard_stack, depth, last_write);
+         record_use(use_counts, clipboard_stack);
+      }
+      break;
+   }
+   Ok(())
+}
+
+/// Watches the configuration file's directory on its own thread, posting `CONFIG_CHANGED_MESSAGE` whenever the file
+/// changes. If the directory can't be watched, changes are only picked up by reloading from the menu
+fn watch_config(path: PathBuf, target: win::MessageTarget) {
+   let (directory, file_name) = match (path.parent(), path.file_name()) {
+      (Some(directory), Some(file_name)) => (directory.to_owned(), file_name.to_string_lossy().to_lowercase()),
+      _ => return,
+   };
+   let watcher = match win::DirectoryWatcher::open(&directory) {
+      Ok(watcher) => watcher,
+      Err(e) => {
+         warn!(target: log_target::CONFIG, "Failed to watch {:#?} for configuration changes: {}", directory, e);
+         return;
+      }
+   };
+   std::thread::spawn(move || loop {
+      let names = match watcher.wait_for_changes() {
+         Ok(names) => names,
+         Err(e) => {
+            warn!(target: log_target::CONFIG, "Stopped watching for configuration changes: {}", e);
+            return;
+         }
+      };
+      // With no names, there were too many changes to list, and any of them could have been the configuration
+      // If posting fails, the window is gone, so there's nothing left to reload
+      if (names.is_empty() || names.iter().any(|x| x.to_lowercase() == file_name))
+         && target.post_message(CONFIG_CHANGED_MESSAGE).is_err()
+      {
+         return;
+      }
+   });
+}
+
+/// Without a window there's nowhere for a notification to come from, so a message box is the only way to tell the user
+fn startup_failed(what: &str, e: win::ErrorCode) -> ! {
+   let text = format!("{}: {}", what, e);
+   error!("{}", text);
+   if let Err(e) = win::show_error("ripclip failed to start", &text) {
+      warn!("Failed to show startup error: {}", e);
    }
-   if let Some(hotkey) = &config.swap_keybinding {
-      win::register_hotkey(Some(&window), SWAP_HOTKEY_ID, hotkey.modifiers, hotkey.key).unwrap();
+   std::process::exit(-1);
+}
+
+/// Reads the configuration file again. If it can't be parsed, the user is told that the previous configuration is
+/// still in use
+fn load_new_config(args: &Args) -> Option<(config::Config, Option<config::LoadWarning>)> {
+   match config::load_config(args.config_path.clone(), args.portable) {
+      Ok(loaded) => Some(loaded),
+      Err(e) => {
+         let text = format!(
+            "Failed to parse config: {}\n\nThe previous configuration is still in use.",
+            e
+         );
+         warn!(target: log_target::CONFIG, "{}", text);
+         if let Err(e) = win::show_message_box("ripclip", &text) {
+            warn!(target: log_target::CONFIG, "Failed to show config error: {}", e);
+         }
+         None
+      }
    }
-   if let Some(hotkey) = &config.clear_keybinding {
-      win::register_hotkey(Some(&window), CLEAR_HOTKEY_ID, hotkey.modifiers, hotkey.key).unwrap();
+}
+
+/// Only re-registers the keybindings, leaving everything else (including the tray icon) alone
+fn reload_keybindings(args: &Args, window: &win::WindowHandle, config: &mut config::Config, hotkeys: &mut HotkeyState) {
+   if let Some((new_config, _)) = load_new_config(args) {
+      unset_keybindings(config, window, hotkeys);
+      config.take_keybindings(new_config);
+      set_keybindings(config, window, hotkeys);
+      trace!(target: log_target::CONFIG, "Successfully reloaded keybindings");
    }
 }
 
-fn pop(window: &win::WindowHandle, clipboard_stack: &mut VecDeque<win::ClipboardText>, managing_clipboard: &mut bool) {
-   if *managing_clipboard {
-      clipboard_stack.pop_back();
-      trace!("Popped element off clipboard stack")
+fn reload_config<'a>(
+   args: &Args,
+   window: &'a win::WindowHandle,
+   module: &win::ModuleHandle,
+   config: &mut config::Config,
+   trayicon: &mut Option<win::TrayIcon<'a>>,
+   modifier_hook: &mut Option<win::ModifierHook>,
+   hotkeys: &mut HotkeyState,
+) {
+   let (new_config, config_warning) = match load_new_config(args) {
+      Some(loaded) => loaded,
+      None => return,
+   };
+   unset_keybindings(config, window, hotkeys);
+   *config = new_config;
+   set_keybindings(config, window, hotkeys);
+   // An existing tray icon is kept rather than recreated, so that a failure to add it again
+   // can't leave the user without one
+   if !config.show_tray_icon {
+      *trayicon = None;
+   } else if trayicon.is_none() {
+      *trayicon = add_tray_icon(window, module, config, 1);
+   }
+   // Only one hook can be installed at a time
+   *modifier_hook = None;
+   *modifier_hook = hook_skip_stack_modifier(config, module, trayicon);
+   if let Some(warning) = config_warning {
+      notify(trayicon, &warning.to_string());
    }
-   *managing_clipboard = true;
+   trace!(target: log_target::CONFIG, "Successfully reloaded configuration");
+}
 
-   win::remove_clipboard_format_listener(window).unwrap();
-   {
-      let clipboard = open_clipboard_with_backoff(window).unwrap();
-      let owned_clipboard = clipboard.empty().unwrap();
-      if let Some(text) = clipboard_stack.back() {
-         owned_clipboard.set_text(text.clone()).unwrap();
-         trace!("Placed top of stack in clipboard");
-      } else {
-         trace!("Nothing on stack to place in clipboard");
+/// If every attempt fails, the user is told and ripclip carries on without a tray icon
+fn add_tray_icon<'a>(
+   window: &'a win::WindowHandle,
+   module: &win::ModuleHandle,
+   config: &config::Config,
+   attempts: u32,
+) -> Option<win::TrayIcon<'a>> {
+   let mut attempt = 1;
+   loop {
+      let result = win::add_tray_icon(
+         window,
+         0,
+         TRAY_CALLBACK_MESSAGE,
+         "ripclip",
+         module,
+         config.tray_icon_path.as_deref(),
+      );
+      match result {
+         Ok(icon) => return Some(icon),
+         Err(e) if attempt < attempts => {
+            warn!("Failed to add tray icon, trying again: {}", e);
+            attempt += 1;
+            std::thread::sleep(TRAY_ICON_RETRY_DELAY);
+         }
+         Err(e) => {
+            error!("Failed to add tray icon: {}", e);
+            // There's no tray icon to show a balloon from
+            let text = format!(
+               "Failed to add the tray icon: {}\n\nripclip is still running, and its keybindings still work. Restart ripclip to try again.",
+               e
+            );
+            if let Err(e) = win::show_error("ripclip", &text) {
+               warn!("Failed to show error: {}", e);
+            }
+            return None;
+         }
       }
    }
-   win::add_clipboard_format_listener(window).unwrap();
 }
 
-fn clear(
+/// `anchor` is the point the tray icon reported, packed as in the wParam of its callback
+fn show_menu(
+   config: &config::Config,
    window: &win::WindowHandle,
-   clipboard_stack: &mut VecDeque<win::ClipboardText>,
-   managing_clipboard: &mut bool,
+   clipboard_stack: &ClipboardStack,
+   use_counts: &HashMap<win::ClipboardEntry, u32>,
+   disabled_hotkeys: &HashSet<u16>,
+   paused: bool,
+   anchor: usize,
 ) {
-   clipboard_stack.clear();
-   win::remove_clipboard_format_listener(window).unwrap();
-   {
-      let clipboard = open_clipboard_with_backoff(window).unwrap();
-      clipboard.empty().unwrap();
+   let x = winapi::shared::windowsx::GET_X_LPARAM(anchor as isize);
+   let y = winapi::shared::windowsx::GET_Y_LPARAM(anchor as isize);
+   let (x, y) = popup_position(config, x, y);
+   // Built each time so that the history is up to date
+   let menu = match build_menu(config, clipboard_stack, use_counts, disabled_hotkeys, paused) {
+      Ok(menu) => menu,
+      Err(e) => {
+         warn!("Failed to build menu: {}", e);
+         return;
+      }
+   };
+   // Without this the menu doesn't close when the user clicks elsewhere, but it still works
+   if win::set_foreground_window(window).is_err() {
+      warn!("Failed to bring the window to the foreground for the menu");
+   }
+   if let Err(e) = win::draw_popup_menu(&menu, x, y, window) {
+      warn!("Failed to show menu: {}", e);
    }
-   win::add_clipboard_format_listener(window).unwrap();
-   *managing_clipboard = true;
-   trace!("Cleared stack");
 }
 
-fn swap(window: &win::WindowHandle, clipboard_stack: &mut VecDeque<win::ClipboardText>, managing_clipboard: bool) {
-   if !managing_clipboard {
-      trace!("Can't swap when the clipboard is not being managed by ripclip (clipboard contains non-text)");
-      return;
+fn build_menu(
+   config: &config::Config,
+   clipboard_stack: &ClipboardStack,
+   use_counts: &HashMap<win::ClipboardEntry, u32>,
+   disabled_hotkeys: &HashSet<u16>,
+   paused: bool,
+) -> Result<win::Menu, win::ErrorCode> {
+   let mut menu = win::create_popup_menu()?;
+   menu.append_text(MenuId::Pop.into(), "Pop")?;
+   menu.append_text(MenuId::Swap.into(), "Swap")?;
+   menu.append_text(MenuId::Clear.into(), "Clear")?;
+   menu.append_text(MenuId::ClearButTop.into(), "Clear All But Top")?;
+   menu.append_text(MenuId::ShowBytes.into(), "Show Bytes")?;
+   menu.append_text(MenuId::Dedupe.into(), "Deduplicate")?;
+   let pause_text = if paused {
+      "Resume Monitoring"
+   } else {
+      "Pause Monitoring"
+   };
+   menu.append_text(MenuId::TogglePause.into(), pause_text)?;
+   if !clipboard_stack.is_empty() {
+      let mut history_menu = win::create_popup_menu()?;
+      for (depth, item) in history_order(config.history_order, clipboard_stack, use_counts) {
+         let mut entry_menu = win::create_popup_menu()?;
+         entry_menu.append_text(MenuId::History(depth, HistoryAction::Cut).into(), "Cut")?;
+         entry_menu.append_text(MenuId::History(depth, HistoryAction::Remove).into(), "Remove")?;
+         if depth > 0 {
+            entry_menu.append_text(
+               MenuId::History(depth, HistoryAction::SwapWithTop).into(),
+               "Swap With Top",
+            )?;
+         }
+         history_menu.append_submenu(entry_menu, &history_preview(item, config))?;
+      }
+      menu.append_submenu(history_menu, "History")?;
+   }
+   // Unchecking a keybinding turns it off until it's checked again
+   let mut keybindings_menu = win::create_popup_menu()?;
+   let mut any_keybindings = false;
+   for (id, name, hotkey) in keybindings(config) {
+      if let Some(hotkey) = hotkey {
+         let text = format!("{} ({})", name, hotkey);
+         keybindings_menu.append_checkable(MenuId::ToggleHotkey(id).into(), &text, !disabled_hotkeys.contains(&id))?;
+         any_keybindings = true;
+      }
+   }
+   if any_keybindings {
+      menu.append_submenu(keybindings_menu, "Keybindings")?;
    }
+   menu.append_line_break(1)?;
+   menu.append_text(MenuId::ShowConfig.into(), "Show Configuration")?;
+   menu.append_text(MenuId::Reload.into(), "Reload Configuration")?;
+   menu.append_text(MenuId::ReloadKeybindings.into(), "Reload Keybindings")?;
+   menu.append_text(MenuId::SaveConfig.into(), "Save Configuration")?;
+   menu.append_text(MenuId::CopyDiagnostics.into(), "Copy Diagnostics")?;
+   menu.append_text(MenuId::Exit.into(), "Exit")?;
+   Ok(menu)
+}
 
-   if clipboard_stack.len() >= 2 {
-      let last_index = clipboard_stack.len() - 1;
-      clipboard_stack.swap(last_index, last_index - 1);
-      win::remove_clipboard_format_listener(window).unwrap();
-      {
-         let clipboard = open_clipboard_with_backoff(window).unwrap();
-         let owned_clipboard = clipboard.empty().unwrap();
-         owned_clipboard
-            .set_text(clipboard_stack.back().unwrap().clone())
-            .unwrap();
-      }
-      win::add_clipboard_format_listener(window).unwrap();
-      trace!("Swapped top 2 elements of stack");
-   } else {
-      trace!("Stack too small to swap");
+/// Entries for the History submenu, with their depth in the stack
+fn history_order<'a>(
+   order: config::HistoryOrder,
+   clipboard_stack: &'a ClipboardStack,
+   use_counts: &HashMap<win::ClipboardEntry, u32>,
+) -> Vec<(usize, &'a ClipboardItem)> {
+   let mut entries: Vec<(usize, &ClipboardItem)> = clipboard_stack.iter().take(HISTORY_MENU_MAX_DEPTH).collect();
+   if order == config::HistoryOrder::Frequent {
+      // Stable, so equally used items stay in recency order
+      entries.sort_by_key(|(_, item)| std::cmp::Reverse(use_counts.get(&item.entry).copied().unwrap_or(0)));
    }
+   entries.truncate(HISTORY_MENU_MAX_ENTRIES);
+   entries
 }
 
-fn open_clipboard_with_backoff(hwnd: &win::WindowHandle) -> Result<win::ClipboardHandle, win::ErrorCode> {
-   // On Windows, only one application may have the clipboard open at a time
-   // Some applications fight us for the clipboard (especially after an operation),
-   // and so to avoid crashing we try to access the clipboard several times in a short succession.
-   // If we still can't access the clipboard after a long time (couple of hundred ms),
-   // the clipboard stack will be in a confusing state for the user.
-   // Currently, we will panic in such scenarios, but in the future (TODO)
-   // we may give back control of the stack and use a notification to let the user
-   // know that there was an issue accessing the clipboard and try to recover.
-
-   use std::time::{Duration, Instant};
-   use rand::Rng;
-   use rand::rngs::OsRng;
-   use rand::distributions::uniform::Uniform;
+/// Counts a use of the top of the stack, after it was popped to, restored or promoted
+fn record_use(use_counts: &mut HashMap<win::ClipboardEntry, u32>, clipboard_stack: &ClipboardStack) {
+   if let Some(top) = clipboard_stack.peek() {
+      count_use(use_counts, &top.entry, clipboard_stack);
+   }
+}
 
-   let mut sleep_duration = Duration::from_millis(1);
-   let mut open_result = win::open_clipboard(hwnd);
-   let start_time = Instant::now();
-   while start_time.elapsed() <= Duration::from_millis(500) {
-      // Try to open clipboard
-      match open_result {
-         Err(win::ERROR_ACCESS_DENIED) => trace!("Clipboard is locked, backing off"),
-         _ => break,
+/// Counts a use of an entry placed in the clipboard, whether or not it's still on the stack
+fn count_use(
+   use_counts: &mut HashMap<win::ClipboardEntry, u32>,
+   entry: &win::ClipboardEntry,
+   clipboard_stack: &ClipboardStack,
+) {
+   *use_counts.entry(entry.clone()).or_insert(0) += 1;
+   if use_counts.len() > USE_COUNTS_MAX_ENTRIES {
+      // Forget whatever has left the stack, rather than growing forever
+      use_counts.retain(|entry, _| clipboard_stack.iter().any(|(_, x)| &x.entry == entry));
+   }
+}
+
+/// Keeps the menu on a single monitor and out from under the taskbar,
+/// or moves it to the configured monitor
+fn popup_position(config: &config::Config, x: i32, y: i32) -> (i32, i32) {
+   if let Some(n) = config.popup_monitor {
+      match win::enum_display_monitors() {
+         Ok(mut monitors) if n >= 1 && n <= monitors.len() => match monitors.swap_remove(n - 1).work_area() {
+            // The menu is aligned to the bottom right of the point
+            Ok(area) => return (area.right - 1, area.bottom - 1),
+            Err(e) => warn!("Failed to get the work area of monitor {}: {}", n, e),
+         },
+         Ok(monitors) => warn!("Monitor {} does not exist (there are {})", n, monitors.len()),
+         Err(e) => warn!("Failed to enumerate monitors: {}", e),
+      }
+   }
+
+   match win::monitor_from_point(x, y).work_area() {
+      Ok(area) => area.clamp(x, y),
+      Err(e) => {
+         warn!("Failed to get the work area of the monitor under the cursor: {}", e);
+         (x, y)
       }
-      std::thread::sleep(sleep_duration);
-      // "Decorrelated jitter"
-      let range = Uniform::new_inclusive(Duration::from_millis(1), sleep_duration * 3);
-      sleep_duration = std::cmp::min(Duration::from_millis(50), OsRng.sample(range));
-      open_result = win::open_clipboard(hwnd);
    }
-   open_result
 }
 
-unsafe extern "system" fn on_message(
-   handle: winapi::shared::windef::HWND,
+fn history_preview(item: &ClipboardItem, config: &config::Config) -> String {
+   // Menus treat & as a mnemonic
+   item_preview(item, config).replace('&', "&&")
+}
+
+fn item_preview(item: &ClipboardItem, config: &config::Config) -> String {
+   let preview = match &item.entry {
+      win::ClipboardEntry::Text(text, _) => {
+         let text = text.to_string();
+         let mut preview = text_preview(&text, config.multiline_preview_summary);
+         if config.show_entry_stats {
+            let _ = write!(preview, " {}", entry_stats(&text));
+         }
+         preview
+      }
+      win::ClipboardEntry::Image(_) => match item.entry.image_size() {
+         Some((width, height)) => format!("Image ({} × {})", width, height),
+         None => "Image".to_owned(),
+      },
+   };
+   // Menus treat tab as the start of the accelerator text, and list boxes don't expand it without a tab stop
+   let mut preview = preview.replace('\t', " ");
+   if let Some(source) = &item.source {
+      let _ = write!(preview, " — from {}", source);
+   }
+   if let Some(captured_at) = item.captured_at {
+      let _ = write!(preview, " ({})", format_relative_time(captured_at));
+   }
+   preview
+}
+
+/// With `multiline_summary`, multi-line text is previewed as its first line followed by
+/// the number of lines left out. Otherwise, line breaks are treated like spaces
+fn text_preview(text: &str, multiline_summary: bool) -> String {
+   let mut lines = text.lines();
+   let first_line = lines.next().unwrap_or("");
+   let more_lines = lines.count();
+   if multiline_summary && more_lines > 0 {
+      let mut preview = truncate_preview(first_line);
+      if !preview.ends_with('…') {
+         preview.push('…');
+      }
+      let _ = write!(preview, " (+{} more lines)", more_lines);
+      preview
+   } else {
+      truncate_preview(&text.lines().collect::<Vec<_>>().join(" "))
+   }
+}
+
+/// e.g. "(3 lines, 142 chars)". Characters are counted as the user sees them, so line breaks aren't included
+fn entry_stats(text: &str) -> String {
+   let lines = text.lines().count();
+   let chars: usize = text.lines().map(|line| line.chars().count()).sum();
+   format!(
+      "({} {}, {} {})",
+      lines,
+      if lines == 1 { "line" } else { "lines" },
+      chars,
+      if chars == 1 { "char" } else { "chars" }
+   )
+}
+
+/// Truncates to HISTORY_PREVIEW_LENGTH characters, adding an ellipsis if anything was cut off.
+/// Truncation is by character so that surrogate pairs are never split
+fn truncate_preview(text: &str) -> String {
+   let mut preview: String = text.chars().take(HISTORY_PREVIEW_LENGTH).collect();
+   if text.chars().nth(HISTORY_PREVIEW_LENGTH).is_some() {
+      preview.push('…');
+   }
+   preview
+}
+
+/// e.g. "5m ago"
+fn format_relative_time(time: SystemTime) -> String {
+   let seconds = match time.elapsed() {
+      Ok(elapsed) => elapsed.as_secs(),
+      // The system clock went backwards
+      Err(_) => 0,
+   };
+   if seconds < 60 {
+      "just now".to_owned()
+   } else if seconds < 60 * 60 {
+      format!("{}m ago", seconds / 60)
+   } else if seconds < 60 * 60 * 24 {
+      format!("{}h ago", seconds / (60 * 60))
+   } else {
+      format!("{}d ago", seconds / (60 * 60 * 24))
+   }
+}
+
+/// Some applications put base64 or other binary-ish data on the clipboard as text, which is useless on the stack
+fn looks_binary(text: &str, config: &config::Config) -> bool {
+   if let Some(max_percent) = config.binary_control_percent {
+      let total = text.chars().count();
+      let control = text
+         .chars()
+         .filter(|&c| (c.is_control() && c != '\t' && c != '\r' && c != '\n') || c == char::REPLACEMENT_CHARACTER)
+         .count();
+      if total > 0 && control * 100 > max_percent * total {
+         return true;
+      }
+   }
+   if let Some(max_length) = config.binary_token_length {
+      if text.split_whitespace().any(|token| token.chars().count() > max_length) {
+         return true;
+      }
+   }
+   false
+}
+
+/// Why a copy shouldn't be pushed, if one of the filters on its contents says so
+fn ignore_reason(config: &config::Config, entry: &win::ClipboardEntry) -> Option<&'static str> {
+   if config.max_item_bytes.is_some_and(|max| entry.size() > max) {
+      return Some("it's larger than max_item_bytes");
+   }
+   let text = match entry.as_text() {
+      Some(text) => text.to_string(),
+      None => return None,
+   };
+   if config.ignore_whitespace_only && text.trim().is_empty() {
+      return Some("it's empty or only whitespace");
+   }
+   if config
+      .ignore_pattern
+      .as_ref()
+      .is_some_and(|pattern| pattern.is_match(&text))
+   {
+      return Some("it matches ignore_pattern");
+   }
+   if config.skip_binary_like && looks_binary(&text, config) {
+      return Some("it looks like binary data");
+   }
+   None
+}
+
+/// Leaves just the captured text and its HTML on the clipboard, placed there by ripclip. With
+/// `preserve_other_formats`, everything else that was copied alongside them is put back too
+fn take_over_text(
+   config: &config::Config,
+   clipboard: win::ClipboardHandle,
+   text: &win::ClipboardText,
+   html: Option<&[u8]>,
+) -> Result<(), win::ErrorCode> {
+   let other_formats = if config.preserve_other_formats {
+      read_other_formats(config, &clipboard)
+   } else {
+      Vec::new()
+   };
+   let owned_clipboard = clipboard.empty()?;
+   owned_clipboard.set_text(text.clone())?;
+   if let Some(html) = html {
+      set_html(&owned_clipboard, html);
+   }
+   for (format, data) in other_formats {
+      if let Err(e) = owned_clipboard.set_global_data(format, &data) {
+         warn!(target: log_target::PUSH, "Failed to restore clipboard format {}: {}", format, e);
+      }
+   }
+   Ok(())
+}
+
+/// The HTML copied along with the text, if any, so that rich text keeps its formatting when it's popped
+fn read_html(config: &config::Config, clipboard: &win::ClipboardHandle) -> Option<Vec<u8>> {
+   let format = match win::html_format() {
+      Ok(format) => format,
+      Err(e) => {
+         warn!(target: log_target::CLIPBOARD, "Failed to register the HTML clipboard format: {}", e);
+         return None;
+      }
+   };
+   if !clipboard.formats().is_ok_and(|formats| formats.contains(&format)) {
+      return None;
+   }
+   if !within_read_limit(config, clipboard, format) {
+      return None;
+   }
+   match clipboard.get_data(format) {
+      Ok(html) => Some(html),
+      Err(e) => {
+         warn!(target: log_target::CLIPBOARD, "Failed to read HTML from clipboard: {}", e);
+         None
+      }
+   }
+}
+
+/// Must come after the text. Failing only loses the formatting, so it isn't fatal
+fn set_html(clipboard: &win::OwnedClipboardHandle, html: &[u8]) {
+   if let Err(e) = win::html_format().and_then(|format| clipboard.set_global_data(format, html)) {
+      warn!(target: log_target::CLIPBOARD, "Failed to place HTML in clipboard: {}", e);
+   }
+}
+
+/// Copies of everything on the clipboard besides the text and HTML, so that it can be put back after capturing the
+/// text. Formats that can't be copied as bytes, or are bigger than `max_read_bytes`, are left out
+fn read_other_formats(config: &config::Config, clipboard: &win::ClipboardHandle) -> Vec<(u32, Vec<u8>)> {
+   let formats = match clipboard.formats() {
+      Ok(formats) => formats,
+      Err(e) => {
+         warn!(target: log_target::CLIPBOARD, "Failed to list clipboard formats: {}", e);
+         return Vec::new();
+      }
+   };
+   let html_format = win::html_format().ok();
+   formats
+      .into_iter()
+      .filter(|&format| format != win::ClipboardFormat::UnicodeText as u32 && Some(format) != html_format)
+      .filter(|&format| win::is_global_memory_format(format))
+      .filter(|&format| within_read_limit(config, clipboard, format))
+      .filter_map(|format| match clipboard.get_data(format) {
+         Ok(data) => Some((format, data)),
+         Err(e) => {
+            trace!(target: log_target::CLIPBOARD, "Couldn't copy clipboard format {}: {}", format, e);
+            None
+         }
+      })
+      .collect()
+}
+
+/// Records a push, unless there have already been `max_pushes_per_second` in the last second.
+/// Returns true if the push should be throttled
+fn throttle_push(config: &config::Config, recent_pushes: &mut VecDeque<Instant>) -> bool {
+   let max_pushes = match config.max_pushes_per_second {
+      Some(max_pushes) => max_pushes,
+      None => return false,
+   };
+   while recent_pushes.front().is_some_and(|x| x.elapsed() >= THROTTLE_WINDOW) {
+      recent_pushes.pop_front();
+   }
+   if recent_pushes.len() >= max_pushes {
+      return true;
+   }
+   recent_pushes.push_back(Instant::now());
+   false
+}
+
+/// Whether a format is small enough to copy out of the clipboard, according to `max_read_bytes`. This is checked
+/// before reading, so that a huge (or malicious) clipboard is never copied into memory
+fn within_read_limit(config: &config::Config, clipboard: &win::ClipboardHandle, format: u32) -> bool {
+   let max_read_bytes = match config.max_read_bytes {
+      Some(max_read_bytes) => max_read_bytes,
+      None => return true,
+   };
+   match clipboard.data_size(format) {
+      Ok(size) if size > max_read_bytes => {
+         warn!(
+            target: log_target::CLIPBOARD,
+            "Not reading clipboard format {}, which is {} bytes (more than max_read_bytes)",
+            format, size
+         );
+         false
+      }
+      Ok(_) => true,
+      Err(e) => {
+         warn!(target: log_target::CLIPBOARD, "Failed to get the size of clipboard format {}: {}", format, e);
+         false
+      }
+   }
+}
+
+/// The name of the process that placed the current contents on the clipboard.
+/// Not every application sets a clipboard owner, in which case we assume the copy came from the foreground window
+fn clipboard_source_process() -> Option<String> {
+   let window = win::get_clipboard_owner().or_else(win::get_foreground_window)?;
+   match win::get_window_process_name(window) {
+      Ok(name) => Some(name.to_lowercase()),
+      Err(e) => {
+         trace!(target: log_target::PUSH, "Failed to determine clipboard owner process: {}", e);
+         None
+      }
+   }
+}
+
+/// (Re)starts the countdown to emptying the clipboard, if `clipboard_auto_clear_seconds` is set
+fn restart_auto_clear(config: &config::Config, window: &win::WindowHandle) {
+   if let Some(seconds) = config.clipboard_auto_clear_seconds {
+      let elapse = seconds.saturating_mul(1000).min(u32::MAX as usize) as u32;
+      if let Err(e) = win::set_timer(window, AUTO_CLEAR_TIMER_ID, elapse) {
+         warn!(target: log_target::CLIPBOARD, "Failed to start auto-clear timer: {}", e);
+      }
+   }
+}
+
+/// `tooltip_state` is the depth and paused state the tooltip currently shows
+fn update_tooltip(
+   trayicon: &Option<win::TrayIcon>,
+   clipboard_stack: &ClipboardStack,
+   paused: bool,
+   tooltip_state: &mut Option<(usize, bool)>,
+) {
+   let depth = clipboard_stack.len();
+   if *tooltip_state == Some((depth, paused)) {
+      return;
+   }
+   let trayicon = match trayicon {
+      Some(trayicon) => trayicon,
+      None => {
+         // If a tray icon is added later, it starts out without the depth
+         *tooltip_state = None;
+         return;
+      }
+   };
+   let mut tooltip = format!("ripclip — {} {}", depth, if depth == 1 { "item" } else { "items" });
+   if paused {
+      tooltip.push_str(" (paused)");
+   }
+   if let Err(e) = trayicon.set_tooltip(&tooltip) {
+      warn!("Failed to change tray tooltip: {}", e);
+      return;
+   }
+   *tooltip_state = Some((depth, paused));
+}
+
+fn update_full_indicator(
+   config: &config::Config,
+   module: &win::ModuleHandle,
+   trayicon: &Option<win::TrayIcon>,
+   clipboard_stack: &ClipboardStack,
+   showing_full: &mut Option<bool>,
+) {
+   let full = config.indicate_full && clipboard_stack.is_full();
+   if *showing_full == Some(full) {
+      return;
+   }
+   let trayicon = match trayicon {
+      Some(trayicon) => trayicon,
+      None => {
+         // If a tray icon is added later, it starts out with the normal icon
+         *showing_full = Some(false);
+         return;
+      }
+   };
+   let icon = match (full, &config.tray_icon_path) {
+      (true, _) => win::Icon::Warning,
+      (false, Some(path)) => win::Icon::File(path, module),
+      (false, None) => win::Icon::Application(module),
+   };
+   if let Err(e) = trayicon.set_icon(icon) {
+      warn!("Failed to change tray icon: {}", e);
+      return;
+   }
+   trace!("Changed tray icon, stack full: {}", full);
+   *showing_full = Some(full);
+}
+
+fn hook_skip_stack_modifier(
+   config: &config::Config,
+   module: &win::ModuleHandle,
+   trayicon: &Option<win::TrayIcon>,
+) -> Option<win::ModifierHook> {
+   let modifier = config.skip_stack_modifier?;
+   match win::hook_modifier(module, modifier) {
+      Ok(hook) => Some(hook),
+      Err(e) => {
+         notify(
+            trayicon,
+            &format!("Failed to watch skip_stack_modifier, so it will be ignored: {}", e),
+         );
+         None
+      }
+   }
+}
+
+#[cfg(feature = "http")]
+fn start_control_server(config: &config::Config, sender: &AppMessageSender, trayicon: &Option<win::TrayIcon>) {
+   let token = match &config.http_token {
+      Some(token) => token.clone(),
+      None => {
+         notify(
+            trayicon,
+            "enable_http is set, but the control server won't start without an http_token",
+         );
+         return;
+      }
+   };
+   if let Err(e) = http::start(config.http_port, token, sender.clone()) {
+      notify(
+         trayicon,
+         &format!("Failed to start the control server on port {}: {}", config.http_port, e),
+      );
+   }
+}
+
+#[cfg(not(feature = "http"))]
+fn start_control_server(_config: &config::Config, _sender: &AppMessageSender, trayicon: &Option<win::TrayIcon>) {
+   notify(
+      trayicon,
+      "enable_http is set, but this build of ripclip doesn't include the control server (the http feature)",
+   );
+}
+
+/// Shows a balloon from the tray icon, or just logs if the tray icon is disabled
+fn notify(trayicon: &Option<win::TrayIcon>, text: &str) {
+   warn!("{}", text);
+   if let Some(trayicon) = trayicon {
+      if let Err(e) = trayicon.show_balloon("ripclip", text) {
+         warn!("Failed to show notification: {}", e);
+      }
+   }
+}
+
+/// Every keybinding with its hotkey id and name, whether or not it's set
+fn keybindings(config: &config::Config) -> Vec<(u16, String, &Option<config::Hotkey>)> {
+   let mut keybindings = vec![
+      (POP_HOTKEY_ID, "Pop".to_owned(), &config.pop_keybinding),
+      (SWAP_HOTKEY_ID, "Swap".to_owned(), &config.swap_keybinding),
+      (CLEAR_HOTKEY_ID, "Clear".to_owned(), &config.clear_keybinding),
+      (PINGPONG_HOTKEY_ID, "Pingpong".to_owned(), &config.pingpong_keybinding),
+      (DEDUPE_HOTKEY_ID, "Dedupe".to_owned(), &config.dedupe_keybinding),
+      (PEEK_HOTKEY_ID, "Peek".to_owned(), &config.peek_keybinding),
+   ];
+   for (id, (i, hotkey)) in (FIRST_SELECT_HOTKEY_ID..).zip(config.select_keybindings.iter().enumerate()) {
+      keybindings.push((id, format!("Select {}", i + 1), hotkey));
+   }
+   keybindings.push((
+      POP_TO_WINDOW_HOTKEY_ID,
+      "Pop to window".to_owned(),
+      &config.pop_to_window_keybinding,
+   ));
+   keybindings.push((PUSH_HOTKEY_ID, "Push".to_owned(), &config.push_keybinding));
+   keybindings.push((UNDO_HOTKEY_ID, "Undo".to_owned(), &config.undo_keybinding));
+   keybindings.push((BROWSE_HOTKEY_ID, "Browse".to_owned(), &config.browse_keybinding));
+   keybindings.push((PAUSE_HOTKEY_ID, "Pause".to_owned(), &config.pause_keybinding));
+   keybindings.push((
+      CLEAR_BUT_TOP_HOTKEY_ID,
+      "Clear all but top".to_owned(),
+      &config.clear_but_top_keybinding,
+   ));
+   keybindings
+}
+
+#[derive(Default)]
+struct HotkeyState {
+   /// Keybindings turned off from the menu, by hotkey id
+   disabled: HashSet<u16>,
+   /// Keybindings that failed to register when they were last set, by hotkey id
+   failed: HashSet<u16>,
+}
+
+/// Keybindings turned off from the menu are left unregistered.
+/// A keybinding failing to register (usually because another application already has it) doesn't stop the others,
+/// and the user is told which ones failed
+fn set_keybindings(config: &config::Config, window: &win::WindowHandle, hotkeys: &mut HotkeyState) {
+   hotkeys.failed.clear();
+   let mut failures = Vec::new();
+   for (id, name, hotkey) in keybindings(config) {
+      if let Some(hotkey) = hotkey {
+         if hotkeys.disabled.contains(&id) {
+            continue;
+         }
+         if let Err(e) = win::register_hotkey(Some(window), id, hotkey.registration_modifiers(), hotkey.key) {
+            warn!(target: log_target::HOTKEY, "Failed to register the {} keybinding: {:?}", name, e);
+            hotkeys.failed.insert(id);
+            failures.push(format!("{} ({}): {:?}", name, hotkey, e));
+         }
+      }
+   }
+   if failures.is_empty() {
+      return;
+   }
+   let text = format!(
+      "These keybindings couldn't be registered, and won't work until ripclip is restarted or its configuration is \
+       reloaded. Another application may already be using them.\n\n{}",
+      failures.join("\n")
+   );
+   if let Err(e) = win::show_message_box("ripclip", &text) {
+      warn!(target: log_target::HOTKEY, "Failed to show keybinding error: {}", e);
+   }
+}
+
+fn unset_keybindings(config: &config::Config, window: &win::WindowHandle, hotkeys: &HotkeyState) {
+   for (id, _, hotkey) in keybindings(config) {
+      if hotkey.is_some() && !hotkeys.disabled.contains(&id) && !hotkeys.failed.contains(&id) {
+         if let Err(e) = win::unregister_hotkey(Some(window), id) {
+            trace!(target: log_target::HOTKEY, "Failed to unregister hotkey {}: {}", id, e);
+         }
+      }
+   }
+}
+
+/// Unregisters a keybinding until it's toggled again, or registers it again. The choice is kept across reloads
+fn toggle_hotkey(
+   config: &config::Config,
+   window: &win::WindowHandle,
+   trayicon: &Option<win::TrayIcon>,
+   hotkeys: &mut HotkeyState,
+   id: u16,
+) {
+   let (name, hotkey) = match keybindings(config).into_iter().find(|(x, _, _)| *x == id) {
+      Some((_, name, Some(hotkey))) => (name, hotkey),
+      _ => {
+         trace!(target: log_target::HOTKEY, "Hotkey {} isn't set, so it can't be toggled", id);
+         return;
+      }
+   };
+   if hotkeys.disabled.contains(&id) {
+      // Another application may have taken the hotkey while it was off
+      match win::register_hotkey(Some(window), id, hotkey.registration_modifiers(), hotkey.key) {
+         Ok(()) => {
+            hotkeys.disabled.remove(&id);
+            hotkeys.failed.remove(&id);
+            trace!(target: log_target::HOTKEY, "Turned the {} keybinding back on", name);
+         }
+         Err(e) => notify(
+            trayicon,
+            &format!("Failed to turn the {} keybinding back on: {}", name, e),
+         ),
+      }
+   } else {
+      // Fails if the keybinding never registered, which leaves it off all the same
+      if let Err(e) = win::unregister_hotkey(Some(window), id) {
+         trace!(target: log_target::HOTKEY, "Failed to unregister hotkey {}: {}", id, e);
+      }
+      hotkeys.disabled.insert(id);
+      hotkeys.failed.remove(&id);
+      trace!(target: log_target::HOTKEY, "Turned the {} keybinding off", name);
+   }
+}
+
+/// Pastes the top of the stack into the window matching `pop_to_window_title`. Returns what was pasted, which
+/// `finish_pop_to_window` pops once the window has had time to read the clipboard
+fn pop_to_window(
+   window: &win::WindowHandle,
+   config: &config::Config,
+   trayicon: &Option<win::TrayIcon>,
+   clipboard_stack: &mut ClipboardStack,
+   last_write: &mut Option<OwnWrite>,
+) -> Option<win::ClipboardEntry> {
+   let title = match &config.pop_to_window_title {
+      Some(title) => title,
+      None => {
+         notify(trayicon, "Set pop_to_window_title to choose the window to pop into");
+         return None;
+      }
+   };
+   // Checked before touching the clipboard, so that nothing changes if there's nowhere to paste
+   let target = match win::find_window_by_title(title) {
+      Some(target) => target,
+      None => {
+         notify(trayicon, &format!("No open window's title contains \"{}\"", title));
+         return None;
+      }
+   };
+   let top = match clipboard_stack.peek() {
+      Some(top) => top.entry.clone(),
+      None => {
+         trace!(target: log_target::POP, "Nothing on stack to pop into window");
+         return None;
+      }
+   };
+   // The clipboard may not show the top, e.g. after auto-clear
+   if !clipboard_stack.managing_clipboard() {
+      if let Err(e) = clipboard::paste_top(&mut WinClipboard::new(window, config, last_write), clipboard_stack) {
+         clipboard_failed(trayicon, e);
+         return None;
+      }
+   }
+   if win::activate_window(target).is_err() {
+      notify(
+         trayicon,
+         "Couldn't bring the window to the front, so nothing was popped",
+      );
+      return None;
+   }
+   // Otherwise the paste would be combined with the modifiers of the keybinding that triggered it
+   if !win::wait_for_modifiers_released(MODIFIER_RELEASE_TIMEOUT) {
+      trace!(target: log_target::POP, "Modifiers still held, pasting anyway");
+   }
+   if let Err(e) = win::send_paste() {
+      warn!(target: log_target::POP, "Failed to paste into window, so nothing was popped: {}", e);
+      return None;
+   }
+   trace!(target: log_target::POP, "Pasted into window matching {:?}", title);
+   Some(top)
+}
+
+/// Pops what `pop_to_window` pasted. If the clipboard or the top of the stack changed in the meantime, it's no longer
+/// clear what the paste used, so the stack is left alone.
+/// Returns true if anything was popped
+fn finish_pop_to_window(
+   window: &win::WindowHandle,
+   config: &config::Config,
+   trayicon: &Option<win::TrayIcon>,
+   clipboard_stack: &mut ClipboardStack,
+   undo_buffer: &mut Option<clipboard::Undo>,
+   last_write: &mut Option<OwnWrite>,
+   pasted: &win::ClipboardEntry,
+) -> bool {
+   let still_top = clipboard_stack.peek().map(|x| &x.entry) == Some(pasted);
+   if !clipboard_stack.managing_clipboard() || !still_top {
+      trace!(target: log_target::POP, "Clipboard changed after pasting into window, not popping");
+      return false;
+   }
+   match clipboard::pop(
+      &mut WinClipboard::new(window, config, last_write),
+      clipboard_stack,
+      undo_buffer,
+   ) {
+      Ok(()) => {
+         restart_auto_clear(config, window);
+         true
+      }
+      Err(e) => {
+         clipboard_failed(trayicon, e);
+         false
+      }
+   }
+}
+
+/// The stack is left as it was when this happens, so trying again is safe
+fn clipboard_failed(trayicon: &Option<win::TrayIcon>, e: win::ErrorCode) {
+   notify(trayicon, &format!("Couldn't access the clipboard, try again ({})", e));
+}
+
+fn dedupe_now(trayicon: &Option<win::TrayIcon>, clipboard_stack: &mut ClipboardStack) {
+   let removed = clipboard_stack.dedupe();
+   trace!(target: log_target::CLEAR, "Removed {} duplicate elements from stack", removed);
+   if let Some(trayicon) = trayicon {
+      let text = match removed {
+         1 => "Removed 1 duplicate".to_owned(),
+         n => format!("Removed {} duplicates", n),
+      };
+      if let Err(e) = trayicon.show_balloon("ripclip", &text) {
+         warn!("Failed to show notification: {}", e);
+      }
+   }
+}
+
+/// Stops or starts pushing copies. With `capture_current_on_resume`, resuming handles whatever is on the clipboard
+/// as a new copy
+fn toggle_pause(window: &win::WindowHandle, config: &config::Config, paused: &mut bool) {
+   *paused = !*paused;
+   if *paused {
+      trace!(target: log_target::PUSH, "Paused monitoring the clipboard");
+      return;
+   }
+   trace!(target: log_target::PUSH, "Resumed monitoring the clipboard");
+   if config.capture_current_on_resume {
+      // Going through the usual handling applies all the same filters as a copy
+      if let Err(e) = window
+         .message_target()
+         .post_message(winapi::um::winuser::WM_CLIPBOARDUPDATE)
+      {
+         warn!(target: log_target::PUSH, "Failed to capture the clipboard on resuming: {}", e);
+      }
+   }
+}
+
+/// Adds ripclip to, or removes it from, the programs started on login, to match `start_with_windows`
+fn set_start_with_windows(config: &config::Config, trayicon: &Option<win::TrayIcon>) {
+   let exe_path = match std::env::current_exe() {
+      Ok(path) => path,
+      Err(e) => {
+         warn!(target: log_target::CONFIG, "Failed to find the executable to start with Windows: {}", e);
+         return;
+      }
+   };
+   if let Err(e) = win::set_run_on_login(config.start_with_windows, &exe_path) {
+      notify(
+         trayicon,
+         &format!("Failed to change whether ripclip starts with Windows: {}", e),
+      );
+   }
+}
+
+/// Writes the configuration as it is now over the configuration file, once the user agrees to lose the comments in
+/// it. The old file is kept as a backup
+fn save_config(args: &Args, config: &config::Config, trayicon: &Option<win::TrayIcon>) {
+   let path = match config::config_file_path(args.config_path.clone(), args.portable) {
+      Some(path) => path,
+      None => {
+         notify(trayicon, "Couldn't find where to save the configuration");
+         return;
+      }
+   };
+   let backup_path = config::backup_path(&path);
+   let message = format!(
+      "Replace {} with the configuration as ripclip is using it? Comments in the file will be lost, so the current \
+       file will be kept as {}.",
+      path.display(),
+      backup_path.display()
+   );
+   match win::ask_yes_no("ripclip - Save Configuration", &message) {
+      Ok(true) => (),
+      Ok(false) => return,
+      Err(e) => {
+         warn!(target: log_target::CONFIG, "Failed to ask before saving the configuration: {}", e);
+         return;
+      }
+   }
+   if path.exists() {
+      if let Err(e) = std::fs::copy(&path, &backup_path) {
+         notify(
+            trayicon,
+            &format!("Didn't save configuration, as backing it up failed: {}", e),
+         );
+         return;
+      }
+   }
+   if let Err(e) = config.write_to(&path) {
+      notify(trayicon, &format!("Failed to save configuration: {}", e));
+   }
+}
+
+/// Alternates the clipboard between the top two items of the stack, without reordering it
+fn ping_pong(
+   window: &win::WindowHandle,
+   config: &config::Config,
+   clipboard_stack: &mut ClipboardStack,
+   pingpong_second: &mut bool,
+   last_write: &mut Option<OwnWrite>,
+) {
+   if clipboard_stack.len() < 2 {
+      trace!(target: log_target::POP, "Stack too small to ping-pong");
+      return;
+   }
+
+   let second = !*pingpong_second;
+   let depth = if second { 1 } else { 0 };
+   let entry = clipboard_stack.get(depth).map(|x| x.entry.clone());
+   if let Err(e) = set_clipboard(window, config, entry, last_write) {
+      warn!(target: log_target::POP, "Failed to ping-pong: {}", e);
+      return;
+   }
+   *pingpong_second = second;
+   // While the second item is showing, the top is still on the stack for a pop to restore
+   clipboard_stack.set_managing_clipboard(depth == 0);
+   trace!(target: log_target::POP, "Ping-ponged to element {} from the top of stack", depth + 1);
+}
+
+/// Places the item under the cursor in the clipboard and moves the cursor down, wrapping around at the bottom.
+/// Unlike `pop`, nothing is removed from the stack
+fn peek(
+   window: &win::WindowHandle,
+   config: &config::Config,
+   clipboard_stack: &mut ClipboardStack,
+   peek_cursor: &mut usize,
+   last_write: &mut Option<OwnWrite>,
+) {
+   if clipboard_stack.is_empty() {
+      trace!(target: log_target::POP, "Nothing on stack to peek at");
+      return;
+   }
+
+   // The stack may have shrunk since the last peek
+   let depth = *peek_cursor % clipboard_stack.len();
+   let entry = clipboard_stack.get(depth).map(|x| x.entry.clone());
+   if let Err(e) = set_clipboard(window, config, entry, last_write) {
+      warn!(target: log_target::POP, "Failed to peek: {}", e);
+      return;
+   }
+   // Popping while peeking further down should bring back the top rather than discard it
+   clipboard_stack.set_managing_clipboard(depth == 0);
+   *peek_cursor = (depth + 1) % clipboard_stack.len();
+   trace!(target: log_target::POP, "Peeked at element {} from the top of stack", depth);
+}
+
+/// Places the item at `depth` in the clipboard without removing anything
+fn select(
+   window: &win::WindowHandle,
+   config: &config::Config,
+   clipboard_stack: &mut ClipboardStack,
+   depth: usize,
+   last_write: &mut Option<OwnWrite>,
+) {
+   let entry = match clipboard_stack.get(depth) {
+      Some(item) => item.entry.clone(),
+      None => {
+         trace!(target: log_target::POP, "No element {} from the top of stack to select", depth);
+         return;
+      }
+   };
+   if let Err(e) = set_clipboard(window, config, Some(entry), last_write) {
+      warn!(target: log_target::POP, "Failed to select element {}: {}", depth, e);
+      return;
+   }
+   // Anything below the top isn't what a pop would discard
+   clipboard_stack.set_managing_clipboard(depth == 0);
+   trace!(target: log_target::POP, "Selected element {} from the top of stack", depth);
+}
+
+/// Places the entry in the clipboard (or empties the clipboard) without pushing it onto the stack.
+/// The resulting clipboard update is recognised as our own by `last_write`
+fn set_clipboard(
+   window: &win::WindowHandle,
+   config: &config::Config,
+   entry: Option<win::ClipboardEntry>,
+   last_write: &mut Option<OwnWrite>,
+) -> Result<(), win::ErrorCode> {
+   let sequence_number = win::get_clipboard_sequence_number();
+   let result = write_clipboard(window, config, entry);
+   // A write that failed partway may still have emptied the clipboard
+   if result.is_ok() || win::get_clipboard_sequence_number() != sequence_number {
+      *last_write = Some(OwnWrite::now());
+   }
+   result
+}
+
+fn write_clipboard(
+   window: &win::WindowHandle,
+   config: &config::Config,
+   entry: Option<win::ClipboardEntry>,
+) -> Result<(), win::ErrorCode> {
+   let clipboard = open_clipboard_with_backoff(window, &config.clipboard_retry_errors)?;
+   let owned_clipboard = clipboard.empty()?;
+   match entry {
+      Some(win::ClipboardEntry::Text(text, html)) => {
+         owned_clipboard.set_text(text)?;
+         if let Some(html) = html {
+            set_html(&owned_clipboard, &html);
+         }
+      }
+      Some(win::ClipboardEntry::Image(dib)) => owned_clipboard.set_bitmap(&dib)?,
+      None => (),
+   }
+   Ok(())
+}
+
+/// The system clipboard, for the stack operations in `clipboard`. Changes made through this aren't pushed
+struct WinClipboard<'a, 'w> {
+   window: &'a win::WindowHandle<'w>,
+   config: &'a config::Config,
+   last_write: &'a mut Option<OwnWrite>,
+}
+
+impl<'a, 'w> WinClipboard<'a, 'w> {
+   fn new(
+      window: &'a win::WindowHandle<'w>,
+      config: &'a config::Config,
+      last_write: &'a mut Option<OwnWrite>,
+   ) -> WinClipboard<'a, 'w> {
+      WinClipboard {
+         window,
+         config,
+         last_write,
+      }
+   }
+}
+
+impl clipboard::ClipboardBackend for WinClipboard<'_, '_> {
+   type Error = win::ErrorCode;
+
+   fn get_text(&mut self) -> Result<win::ClipboardText, win::ErrorCode> {
+      open_clipboard_with_backoff(self.window, &self.config.clipboard_retry_errors)?.get_text()
+   }
+
+   fn set(&mut self, entry: win::ClipboardEntry) -> Result<(), win::ErrorCode> {
+      set_clipboard(self.window, self.config, Some(entry), self.last_write)
+   }
+
+   fn empty(&mut self) -> Result<(), win::ErrorCode> {
+      set_clipboard(self.window, self.config, None, self.last_write)
+   }
+}
+
+/// Whether an update at `sequence_number` looks like remote desktop clipboard redirection wiping out
+/// what we just placed in the clipboard: it's the very next change after our own, it came quickly,
+/// and it left the clipboard with nothing on it
+fn emptied_after_own_write(last_write: Option<OwnWrite>, sequence_number: u32) -> bool {
+   let last_write = match last_write {
+      Some(last_write) => last_write,
+      None => return false,
+   };
+   last_write.sequence_number.wrapping_add(1) == sequence_number
+      && last_write.time.elapsed() <= RDP_GLITCH_WINDOW
+      && win::count_clipboard_formats() == Ok(0)
+}
+
+/// Whether the application that owns the clipboard asked for it not to be recorded, as password managers do.
+/// If we can't tell, we err on the side of not recording it
+fn excluded_from_history(window: &win::WindowHandle, config: &config::Config) -> bool {
+   if win::exclude_from_monitor_format().is_ok_and(win::is_registered_format_available) {
+      return true;
+   }
+   let can_include_format = match win::can_include_in_history_format() {
+      Ok(format) if win::is_registered_format_available(format) => format,
+      _ => return false,
+   };
+   let clipboard = match open_clipboard_with_backoff(window, &config.clipboard_retry_errors) {
+      Ok(clipboard) => clipboard,
+      Err(e) => {
+         warn!(target: log_target::CLIPBOARD, "Failed to check whether the clipboard may be recorded: {}", e);
+         return true;
+      }
+   };
+   match clipboard.get_data(can_include_format) {
+      Ok(data) => data.get(..4).is_none_or(|x| x == [0; 4]),
+      Err(e) => {
+         warn!(target: log_target::CLIPBOARD, "Failed to check whether the clipboard may be recorded: {}", e);
+         true
+      }
+   }
+}
+
+/// Removes the item at `depth` from the stack and places it in the clipboard. Returns what was cut, or None if
+/// nothing was
+fn cut_at(
+   window: &win::WindowHandle,
+   config: &config::Config,
+   clipboard_stack: &mut ClipboardStack,
+   depth: usize,
+   last_write: &mut Option<OwnWrite>,
+) -> Option<win::ClipboardEntry> {
+   let entry = match clipboard_stack.get(depth) {
+      Some(item) => item.entry.clone(),
+      None => {
+         trace!(target: log_target::POP, "No element at depth {} to cut", depth);
+         return None;
+      }
+   };
+   if let Err(e) = set_clipboard(window, config, Some(entry.clone()), last_write) {
+      warn!(target: log_target::POP, "Failed to cut element {}: {}", depth, e);
+      return None;
+   }
+   clipboard_stack.remove(depth);
+   // The clipboard now holds something that isn't on the stack
+   clipboard_stack.set_managing_clipboard(false);
+   trace!(target: log_target::POP, "Cut element {} of stack into clipboard", depth);
+   Some(entry)
+}
+
+/// Places the item at `depth` in the clipboard, and moves it to the top of the stack to match
+fn move_to_top_at(
+   window: &win::WindowHandle,
+   config: &config::Config,
+   clipboard_stack: &mut ClipboardStack,
+   depth: usize,
+   last_write: &mut Option<OwnWrite>,
+) {
+   let entry = match clipboard_stack.get(depth) {
+      Some(item) => item.entry.clone(),
+      None => {
+         trace!(target: log_target::POP, "No element at depth {} to move to the top", depth);
+         return;
+      }
+   };
+   if let Err(e) = set_clipboard(window, config, Some(entry), last_write) {
+      warn!(target: log_target::POP, "Failed to move element {} to the top: {}", depth, e);
+      return;
+   }
+   clipboard_stack.move_to_top(depth);
+   clipboard_stack.set_managing_clipboard(true);
+   trace!(target: log_target::POP, "Moved element {} of stack to the top", depth);
+}
+
+/// Discards the item at `depth` from the stack
+fn remove_at(
+   window: &win::WindowHandle,
+   config: &config::Config,
+   clipboard_stack: &mut ClipboardStack,
+   depth: usize,
+   last_write: &mut Option<OwnWrite>,
+) {
+   if clipboard_stack.remove(depth).is_none() {
+      trace!(target: log_target::POP, "No element at depth {} to remove", depth);
+      return;
+   }
+   trace!(target: log_target::POP, "Removed element {} of stack", depth);
+   // If the clipboard was showing the removed element, show the new top instead
+   if clipboard_stack.managing_clipboard() && depth == 0 {
+      let top = clipboard_stack.peek().map(|x| x.entry.clone());
+      if let Err(e) = set_clipboard(window, config, top, last_write) {
+         warn!(target: log_target::POP, "Failed to place the new top of stack in clipboard: {}", e);
+         // The clipboard still shows the old top
+         clipboard_stack.set_managing_clipboard(false);
+      }
+   }
+}
+
+/// Swaps any two items of the stack, updating the clipboard if the top of the stack changed. Returns false if
+/// nothing was swapped
+fn swap_at(
+   window: &win::WindowHandle,
+   config: &config::Config,
+   clipboard_stack: &mut ClipboardStack,
+   a: usize,
+   b: usize,
+   last_write: &mut Option<OwnWrite>,
+) -> bool {
+   if a == b {
+      return false;
+   }
+   if !clipboard_stack.swap(a, b) {
+      trace!(
+         target: log_target::SWAP,
+         "Can't swap elements {} and {} of a stack with {} elements",
+         a,
+         b,
+         clipboard_stack.len()
+      );
+      return false;
+   }
+
+   trace!(target: log_target::SWAP, "Swapped elements {} and {} of stack", a, b);
+   // If the clipboard isn't showing the top of the stack, it has nothing to do with the stack
+   if clipboard_stack.managing_clipboard() && (a == 0 || b == 0) {
+      let top = clipboard_stack.peek().map(|x| x.entry.clone());
+      if let Err(e) = set_clipboard(window, config, top, last_write) {
+         warn!(target: log_target::SWAP, "Failed to place the new top of stack in clipboard: {}", e);
+         // The clipboard still shows the old top
+         clipboard_stack.set_managing_clipboard(false);
+      }
+   }
+   true
+}
+
+fn show_bytes(
+   window: &win::WindowHandle,
+   config: &config::Config,
+   clipboard_stack: &mut ClipboardStack,
+   last_write: &mut Option<OwnWrite>,
+) {
+   let item = match clipboard_stack.peek() {
+      Some(item) => item,
+      None => {
+         trace!(target: log_target::CLIPBOARD, "Nothing on stack to show the bytes of");
+         return;
+      }
+   };
+
+   let dump = hex_dump(item.entry.as_bytes());
+   let total_lines = dump.lines().count();
+   let mut message: String = dump.lines().take(HEX_PREVIEW_LINES).collect::<Vec<_>>().join("\n");
+   if total_lines > HEX_PREVIEW_LINES {
+      let _ = write!(message, "\n... ({} more lines)", total_lines - HEX_PREVIEW_LINES);
+   }
+   message.push_str("\n\nCopy the full hex dump to the clipboard?");
+
+   match win::ask_yes_no("ripclip - Bytes", &message) {
+      Ok(true) => {
+         if let Err(e) = set_clipboard(window, config, Some(clipboard_text(&dump)), last_write) {
+            warn!(target: log_target::CLIPBOARD, "Failed to place hex dump in clipboard: {}", e);
+            return;
+         }
+         clipboard_stack.set_managing_clipboard(false);
+         trace!(target: log_target::CLIPBOARD, "Placed hex dump in clipboard");
+      }
+      Ok(false) => (),
+      Err(e) => {
+         warn!(target: log_target::CLIPBOARD, "Failed to show bytes: {}", e);
+      }
+   }
+}
+
+/// Shows the configuration as ripclip understands it, after defaults are filled in
+fn show_config(
+   window: &win::WindowHandle,
+   config: &config::Config,
+   clipboard_stack: &mut ClipboardStack,
+   last_write: &mut Option<OwnWrite>,
+) {
+   let config_string = config.to_config_string();
+   let message = format!("{}\nCopy the configuration to the clipboard?", config_string);
+
+   match win::ask_yes_no("ripclip - Configuration", &message) {
+      Ok(true) => {
+         let config_string = config_string.replace('\n', "\r\n");
+         if let Err(e) = set_clipboard(window, config, Some(clipboard_text(&config_string)), last_write) {
+            warn!(target: log_target::CLIPBOARD, "Failed to place configuration in clipboard: {}", e);
+            return;
+         }
+         clipboard_stack.set_managing_clipboard(false);
+         trace!(target: log_target::CLIPBOARD, "Placed configuration in clipboard");
+      }
+      Ok(false) => (),
+      Err(e) => {
+         warn!(target: log_target::CLIPBOARD, "Failed to show configuration: {}", e);
+      }
+   }
+}
+
+/// A short report for pasting into bug reports
+fn diagnostics_report(config: &config::Config, clipboard_stack: &ClipboardStack, hotkeys: &HotkeyState) -> String {
+   let mut report = String::new();
+   let _ = write!(report, "ripclip {}\r\n", env!("CARGO_PKG_VERSION"));
+   let _ = write!(
+      report,
+      "Stack: {} items, {} bytes\r\n",
+      clipboard_stack.len(),
+      clipboard_stack.iter().map(|(_, x)| x.entry.size()).sum::<usize>()
+   );
+   let _ = write!(
+      report,
+      "Managing clipboard: {}\r\n",
+      clipboard_stack.managing_clipboard()
+   );
+   for (id, name, hotkey) in keybindings(config) {
+      let name = name.to_lowercase();
+      match hotkey {
+         Some(hotkey) if hotkeys.disabled.contains(&id) => {
+            let _ = write!(report, "Hotkey {}: {} (turned off from the menu)\r\n", name, hotkey);
+         }
+         Some(hotkey) if hotkeys.failed.contains(&id) => {
+            let _ = write!(report, "Hotkey {}: {} (failed to register)\r\n", name, hotkey);
+         }
+         Some(hotkey) => {
+            let _ = write!(report, "Hotkey {}: {} (registered)\r\n", name, hotkey);
+         }
+         None => {
+            let _ = write!(report, "Hotkey {}: not set\r\n", name);
+         }
+      }
+   }
+   report.push_str("\r\nConfiguration:\r\n");
+   for line in config.to_config_string().lines() {
+      // Reports get shared, and the token guards the whole clipboard history
+      if config.http_token.is_some() && line.starts_with("http_token =") {
+         report.push_str("http_token = <redacted>\r\n");
+      } else {
+         let _ = write!(report, "{}\r\n", line);
+      }
+   }
+   report
+}
+
+fn clipboard_text(text: &str) -> win::ClipboardEntry {
+   win::ClipboardEntry::Text(win::ClipboardText::from_str(text), None)
+}
+
+fn hex_dump(bytes: &[u8]) -> String {
+   let mut dump = String::with_capacity(bytes.len() * 4);
+   for (i, chunk) in bytes.chunks(16).enumerate() {
+      let _ = write!(dump, "{:08X} ", i * 16);
+      for byte in chunk {
+         let _ = write!(dump, " {:02X}", byte);
+      }
+      dump.push_str("\r\n");
+   }
+   dump
+}
+
+/// Only errors in `retry_errors` (see `clipboard_retry_errors`) are retried, anything else is returned right away
+fn open_clipboard_with_backoff(
+   hwnd: &win::WindowHandle,
+   retry_errors: &[u32],
+) -> Result<win::ClipboardHandle, win::ErrorCode> {
+   // On Windows, only one application may have the clipboard open at a time
+   // Some applications fight us for the clipboard (especially after an operation),
+   // and so to avoid failing we try to access the clipboard several times in a short succession.
+   // If we still can't access the clipboard after a long time (couple of hundred ms), the error is
+   // returned, and callers leave the stack as it was so that the user can try again.
+
+   use rand::Rng;
+   use rand::rngs::OsRng;
+   use rand::distributions::uniform::Uniform;
+
+   let mut sleep_duration = Duration::from_millis(1);
+   let mut open_result = win::open_clipboard(hwnd);
+   let start_time = Instant::now();
+   while start_time.elapsed() <= Duration::from_millis(500) {
+      // Try to open clipboard
+      match open_result {
+         Err(ref e) if retry_errors.contains(&e.code()) => {
+            trace!(target: log_target::CLIPBOARD, "Clipboard is locked ({}), backing off", e)
+         }
+         _ => break,
+      }
+      std::thread::sleep(sleep_duration);
+      // "Decorrelated jitter"
+      let range = Uniform::new_inclusive(Duration::from_millis(1), sleep_duration * 3);
+      sleep_duration = std::cmp::min(Duration::from_millis(50), OsRng.sample(range));
+      open_result = win::open_clipboard(hwnd);
+   }
+   open_result
+}
+
+unsafe extern "system" fn on_message(
+   handle: winapi::shared::windef::HWND,
    umsg: u32,
    w_param: usize,
    l_param: isize,
@@ -303,5 +2570,56 @@ unsafe extern "system" fn on_message(
          warn!("Failed to post context menu event");
       }
    }
+   if umsg == TRAY_CALLBACK_MESSAGE
+      && (l_param_u & 0x0000_0000_0000_ffff) as u32 == winapi::um::winuser::WM_LBUTTONDBLCLK
+   {
+      let result = winapi::um::winuser::PostMessageW(handle, winapi::um::winuser::WM_LBUTTONDBLCLK, w_param, l_param);
+      if result == 0 {
+         warn!("Failed to post tray double-click event");
+      }
+   }
+   // Both are sent rather than posted, so they never reach the main loop on their own. Broadcasts only reach
+   // top-level windows, so this needs `window_type = hidden` (or a visible window). Switching between light and
+   // dark mode only comes as a `WM_SETTINGCHANGE`, whose string doesn't outlive this call
+   let theme_changed = umsg == winapi::um::winuser::WM_THEMECHANGED
+      || (umsg == winapi::um::winuser::WM_SETTINGCHANGE
+         && l_param != 0
+         && win::from_win_utf16(l_param as *const u16) == win::IMMERSIVE_COLOR_SET);
+   if theme_changed {
+      let result = winapi::um::winuser::PostMessageW(handle, THEME_CHANGED_MESSAGE, 0, 0);
+      if result == 0 {
+         warn!("Failed to post theme change event");
+      }
+   }
+   winapi::um::winuser::DefWindowProcW(handle, umsg, w_param, l_param)
+}
+
+/// Most of the browse window's messages go through the loop in `browse_history`. The ones Windows sends straight
+/// here that the loop needs to see are posted back to it
+unsafe extern "system" fn on_browse_message(
+   handle: winapi::shared::windef::HWND,
+   umsg: u32,
+   w_param: usize,
+   l_param: isize,
+) -> winapi::shared::minwindef::LRESULT {
+   let notification = winapi::shared::minwindef::HIWORD(w_param as u32);
+   let post = match umsg {
+      winapi::um::winuser::WM_COMMAND if notification == winapi::um::winuser::LBN_DBLCLK => Some(BROWSE_CHOOSE_MESSAGE),
+      winapi::um::winuser::WM_ACTIVATE
+         if winapi::shared::minwindef::LOWORD(w_param as u32) == winapi::um::winuser::WA_INACTIVE =>
+      {
+         Some(winapi::um::winuser::WM_CLOSE)
+      }
+      // The window is destroyed when the loop is done with it, not by DefWindowProc
+      winapi::um::winuser::WM_CLOSE => {
+         winapi::um::winuser::PostMessageW(handle, winapi::um::winuser::WM_CLOSE, 0, 0);
+         return 0;
+      }
+      _ => None,
+   };
+   if let Some(message) = post {
+      // Fails harmlessly once the window is being destroyed
+      winapi::um::winuser::PostMessageW(handle, message, 0, 0);
+   }
    winapi::um::winuser::DefWindowProcW(handle, umsg, w_param, l_param)
 }
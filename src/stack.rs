@@ -0,0 +1,355 @@
+use crate::config::DuplicateAction;
+use crate::win::ClipboardEntry;
+use std::collections::{vec_deque, VecDeque};
+use std::iter::{Enumerate, Rev};
+use std::time::SystemTime;
+
+#[derive(Clone)]
+pub struct ClipboardItem {
+   pub entry: ClipboardEntry,
+   /// Executable name of the application the item was copied from, if `track_source` is enabled
+   pub source: Option<String>,
+   /// When the item was copied, if `show_timestamps` is enabled
+   pub captured_at: Option<SystemTime>,
+}
+
+impl ClipboardItem {
+   pub fn new(entry: ClipboardEntry) -> ClipboardItem {
+      ClipboardItem {
+         entry,
+         source: None,
+         captured_at: None,
+      }
+   }
+}
+
+/// Everything copied so far, and whether the clipboard is showing the top of it. None of this touches the clipboard
+/// itself; operations that change what the clipboard should show return the new contents instead.
+///
+/// Items are addressed by depth, where the top of the stack (the most recent item) is depth 0
+pub struct ClipboardStack {
+   items: VecDeque<ClipboardItem>,
+   /// False when the clipboard holds something that isn't the top of the stack, like non-text or something ripclip
+   /// placed there without pushing it
+   managing_clipboard: bool,
+   max_size: Option<usize>,
+}
+
+pub type Iter<'a> = Enumerate<Rev<vec_deque::Iter<'a, ClipboardItem>>>;
+
+impl ClipboardStack {
+   pub fn new(max_size: Option<usize>) -> ClipboardStack {
+      ClipboardStack {
+         items: max_size.map_or_else(VecDeque::new, VecDeque::with_capacity),
+         managing_clipboard: false,
+         max_size,
+      }
+   }
+
+   /// Doesn't discard anything if the stack is already bigger. The excess is pushed out by the next push
+   pub fn set_max_size(&mut self, max_size: Option<usize>) {
+      self.max_size = max_size;
+   }
+
+   pub fn managing_clipboard(&self) -> bool {
+      self.managing_clipboard
+   }
+
+   pub fn set_managing_clipboard(&mut self, managing_clipboard: bool) {
+      self.managing_clipboard = managing_clipboard;
+   }
+
+   pub fn len(&self) -> usize {
+      self.items.len()
+   }
+
+   pub fn is_empty(&self) -> bool {
+      self.items.is_empty()
+   }
+
+   pub fn is_full(&self) -> bool {
+      self.max_size.is_some_and(|max_size| self.items.len() >= max_size)
+   }
+
+   /// `(depth, item)`, newest first
+   pub fn iter(&self) -> Iter<'_> {
+      self.items.iter().rev().enumerate()
+   }
+
+   pub fn peek(&self) -> Option<&ClipboardItem> {
+      self.items.back()
+   }
+
+   pub fn get(&self, depth: usize) -> Option<&ClipboardItem> {
+      let index = self.index(depth)?;
+      self.items.get(index)
+   }
+
+   /// Whether the entry is the same as the top of the stack
+   pub fn is_duplicate(&self, entry: &ClipboardEntry) -> bool {
+      self.peek().map(|x| &x.entry) == Some(entry)
+   }
+
+   /// Pushes an item that was just copied, pushing the oldest item out if the stack is full. Returns false if it
+   /// was a duplicate of the top of the stack that wasn't pushed. Either way, the clipboard shows the top of the stack
+   pub fn push(&mut self, item: ClipboardItem, on_duplicate: DuplicateAction) -> bool {
+      self.managing_clipboard = true;
+      if on_duplicate == DuplicateAction::MoveToTop {
+         // The fresh copy wins over any older ones, wherever they are
+         self.items.retain(|x| x.entry != item.entry);
+      }
+      if self.is_duplicate(&item.entry) {
+         match on_duplicate {
+            DuplicateAction::Skip => return false,
+            // Refreshing a duplicate is the same as removing it and pushing it again
+            DuplicateAction::Refresh => {
+               self.replace_top(item);
+               return true;
+            }
+            DuplicateAction::Push | DuplicateAction::MoveToTop => (),
+         }
+      }
+      while self.is_full() && !self.items.is_empty() {
+         self.items.pop_front();
+      }
+      self.items.push_back(item);
+      true
+   }
+
+   /// Replaces the top of the stack with an item that was just copied, or pushes it if the stack is empty
+   pub fn replace_top(&mut self, item: ClipboardItem) {
+      self.managing_clipboard = true;
+      match self.items.back_mut() {
+         Some(top) => *top = item,
+         None => self.items.push_back(item),
+      }
+   }
+
+   /// Discards the top of the stack, unless the clipboard isn't showing it (in which case popping just restores it).
+   /// Returns what the clipboard should show, which is nothing if the stack is now empty
+   pub fn pop(&mut self) -> Option<ClipboardEntry> {
+      if self.managing_clipboard {
+         self.items.pop_back();
+      }
+      self.managing_clipboard = true;
+      self.peek().map(|x| x.entry.clone())
+   }
+
+   /// Swaps the top two items. Returns the new top for the clipboard, or None if nothing changed because the
+   /// stack is too small or the clipboard isn't showing the top of it
+   pub fn swap_top(&mut self) -> Option<ClipboardEntry> {
+      if !self.managing_clipboard || self.items.len() < 2 {
+         return None;
+      }
+      let last_index = self.items.len() - 1;
+      self.items.swap(last_index, last_index - 1);
+      self.peek().map(|x| x.entry.clone())
+   }
+
+   /// Empties the stack, returning what was on it. The clipboard should be emptied as well
+   pub fn clear(&mut self) -> VecDeque<ClipboardItem> {
+      self.managing_clipboard = true;
+      std::mem::take(&mut self.items)
+   }
+
+   /// Empties the stack except for its top, returning what was removed. The clipboard is left alone, so whether it
+   /// shows the top doesn't change
+   pub fn clear_but_top(&mut self) -> VecDeque<ClipboardItem> {
+      let top = self.items.pop_back();
+      let cleared = std::mem::take(&mut self.items);
+      self.items.extend(top);
+      cleared
+   }
+
+   /// Puts back everything `clear` returned. The clipboard should show the top of it
+   pub fn restore(&mut self, items: VecDeque<ClipboardItem>) {
+      self.items = items;
+      self.managing_clipboard = true;
+   }
+
+   pub fn remove(&mut self, depth: usize) -> Option<ClipboardItem> {
+      let index = self.index(depth)?;
+      self.items.remove(index)
+   }
+
+   /// Moves the item at `depth` to the top of the stack. Returns false if there's no such item
+   pub fn move_to_top(&mut self, depth: usize) -> bool {
+      match self.remove(depth) {
+         Some(item) => {
+            self.items.push_back(item);
+            true
+         }
+         None => false,
+      }
+   }
+
+   /// Returns false if either item doesn't exist
+   pub fn swap(&mut self, depth_a: usize, depth_b: usize) -> bool {
+      match (self.index(depth_a), self.index(depth_b)) {
+         (Some(a), Some(b)) => {
+            self.items.swap(a, b);
+            true
+         }
+         _ => false,
+      }
+   }
+
+   /// Removes every item that has a more recent copy further up the stack. The top of the stack
+   /// is always kept, so the clipboard doesn't change. Returns how many items were removed
+   pub fn dedupe(&mut self) -> usize {
+      let before = self.items.len();
+      let mut unique: VecDeque<ClipboardItem> = VecDeque::with_capacity(before);
+      for item in self.items.drain(..).rev() {
+         if !unique.iter().any(|x| x.entry == item.entry) {
+            unique.push_front(item);
+         }
+      }
+      self.items = unique;
+      before - self.items.len()
+   }
+
+   fn index(&self, depth: usize) -> Option<usize> {
+      self.items.len().checked_sub(depth + 1)
+   }
+}
+
+#[cfg(test)]
+mod test {
+   use super::*;
+   use crate::win::ClipboardText;
+
+   fn text(s: &str) -> ClipboardEntry {
+      ClipboardEntry::Text(ClipboardText::from_str(s), None)
+   }
+
+   fn item(s: &str) -> ClipboardItem {
+      ClipboardItem::new(text(s))
+   }
+
+   fn contents(stack: &ClipboardStack) -> Vec<String> {
+      stack
+         .iter()
+         .map(|(_, x)| x.entry.as_text().unwrap().to_string())
+         .collect()
+   }
+
+   #[test]
+   fn skips_duplicates() {
+      let mut stack = ClipboardStack::new(None);
+      assert!(stack.push(item("a"), DuplicateAction::Skip));
+      assert!(!stack.push(item("a"), DuplicateAction::Skip));
+      assert!(stack.push(item("b"), DuplicateAction::Skip));
+      // Only the top of the stack counts
+      assert!(stack.push(item("a"), DuplicateAction::Skip));
+      assert_eq!(contents(&stack), vec!["a", "b", "a"]);
+
+      assert!(stack.push(item("a"), DuplicateAction::Refresh));
+      assert_eq!(stack.len(), 3);
+      assert!(stack.push(item("a"), DuplicateAction::Push));
+      assert_eq!(stack.len(), 4);
+   }
+
+   #[test]
+   fn moves_duplicates_to_top() {
+      let mut stack = ClipboardStack::new(None);
+      for s in ["a", "b", "a", "c"].iter() {
+         stack.push(item(s), DuplicateAction::Push);
+      }
+      assert!(stack.push(item("a"), DuplicateAction::MoveToTop));
+      assert_eq!(contents(&stack), vec!["a", "c", "b"]);
+      assert!(stack.push(item("a"), DuplicateAction::MoveToTop));
+      assert_eq!(contents(&stack), vec!["a", "c", "b"]);
+      assert!(stack.push(item("d"), DuplicateAction::MoveToTop));
+      assert_eq!(contents(&stack), vec!["d", "a", "c", "b"]);
+   }
+
+   #[test]
+   fn evicts_oldest_when_full() {
+      let mut stack = ClipboardStack::new(Some(3));
+      for s in ["a", "b", "c"].iter() {
+         stack.push(item(s), DuplicateAction::Push);
+      }
+      assert!(stack.is_full());
+      stack.push(item("d"), DuplicateAction::Push);
+      assert_eq!(contents(&stack), vec!["d", "c", "b"]);
+
+      stack.set_max_size(Some(2));
+      stack.push(item("e"), DuplicateAction::Push);
+      assert_eq!(contents(&stack), vec!["e", "d"]);
+   }
+
+   #[test]
+   fn swaps_only_with_two_items() {
+      let mut stack = ClipboardStack::new(None);
+      assert!(stack.swap_top().is_none());
+      stack.push(item("a"), DuplicateAction::Push);
+      assert!(stack.swap_top().is_none());
+      stack.push(item("b"), DuplicateAction::Push);
+      assert!(stack.swap_top() == Some(text("a")));
+      assert_eq!(contents(&stack), vec!["a", "b"]);
+
+      // The clipboard isn't showing the stack, so there's nothing to swap it with
+      stack.set_managing_clipboard(false);
+      assert!(stack.swap_top().is_none());
+   }
+
+   #[test]
+   fn moves_to_top() {
+      let mut stack = ClipboardStack::new(None);
+      stack.push(item("a"), DuplicateAction::Push);
+      stack.push(item("b"), DuplicateAction::Push);
+      stack.push(item("c"), DuplicateAction::Push);
+      assert!(stack.move_to_top(2));
+      assert_eq!(contents(&stack), vec!["a", "c", "b"]);
+      assert!(stack.move_to_top(0));
+      assert_eq!(contents(&stack), vec!["a", "c", "b"]);
+      assert!(!stack.move_to_top(3));
+   }
+
+   #[test]
+   fn pops_and_restores() {
+      let mut stack = ClipboardStack::new(None);
+      stack.push(item("a"), DuplicateAction::Push);
+      stack.push(item("b"), DuplicateAction::Push);
+      assert!(stack.pop() == Some(text("a")));
+      // Something else was copied, so popping puts the top back rather than discarding it
+      stack.set_managing_clipboard(false);
+      assert!(stack.pop() == Some(text("a")));
+      assert!(stack.pop().is_none());
+      assert!(stack.is_empty());
+
+      stack.push(item("c"), DuplicateAction::Push);
+      stack.clear();
+      assert!(stack.peek().is_none());
+   }
+
+   #[test]
+   fn clears_all_but_the_top() {
+      let mut stack = ClipboardStack::new(None);
+      assert!(stack.clear_but_top().is_empty());
+      for s in ["a", "b", "c"].iter() {
+         stack.push(item(s), DuplicateAction::Push);
+      }
+      let cleared = stack.clear_but_top();
+      assert_eq!(cleared.len(), 2);
+      assert_eq!(contents(&stack), vec!["c"]);
+      assert!(stack.clear_but_top().is_empty());
+      assert_eq!(contents(&stack), vec!["c"]);
+   }
+
+   #[test]
+   fn iterates_newest_first() {
+      let mut stack = ClipboardStack::new(None);
+      for s in ["a", "b", "c", "b"].iter() {
+         stack.push(item(s), DuplicateAction::Push);
+      }
+      let depths: Vec<usize> = stack.iter().map(|(depth, _)| depth).collect();
+      assert_eq!(depths, vec![0, 1, 2, 3]);
+      assert_eq!(contents(&stack), vec!["b", "c", "b", "a"]);
+      assert!(stack.get(2).map(|x| x.entry.clone()) == Some(text("b")));
+      assert!(stack.get(4).is_none());
+
+      assert_eq!(stack.dedupe(), 1);
+      assert_eq!(contents(&stack), vec!["b", "c", "a"]);
+   }
+}
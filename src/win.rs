@@ -3,10 +3,15 @@
 use std::iter;
 use std::marker::PhantomData;
 use std::mem::MaybeUninit;
+use std::os::windows::ffi::OsStrExt;
+use std::path::Path;
 use std::ptr::{self, NonNull};
+use std::slice;
 use std::str::FromStr;
 use std::string::FromUtf16Error;
-use std::{fmt, mem, num};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::{error, fmt, mem, num};
 use winapi;
 
 pub struct Menu {
@@ -49,11 +54,47 @@ impl Menu {
       Ok(())
    }
 
-   pub fn append_text(&mut self, id: usize, text: &str) -> Result<(), ErrorCode> {
+   /// The submenu is destroyed along with this menu. Like `append_text`, the text is copied
+   pub fn append_submenu(&mut self, submenu: Menu, text: &str) -> Result<(), ErrorCode> {
       let s = to_win_utf16(text);
       let result = unsafe {
-         winapi::um::winuser::AppendMenuW(self.inner.as_ptr(), winapi::um::winuser::MF_STRING, id, s.as_ptr())
+         winapi::um::winuser::AppendMenuW(
+            self.inner.as_ptr(),
+            winapi::um::winuser::MF_POPUP,
+            submenu.inner.as_ptr() as usize,
+            s.as_ptr(),
+         )
+      };
+
+      if result == 0 {
+         let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+         return Err(ErrorCode(code));
+      }
+
+      // Now owned by this menu
+      mem::forget(submenu);
+
+      Ok(())
+   }
+
+   /// AppendMenuW copies MF_STRING text into the menu, so the buffer only needs to outlive the call
+   pub fn append_text(&mut self, id: usize, text: &str) -> Result<(), ErrorCode> {
+      self.append_string(id, text, winapi::um::winuser::MF_STRING)
+   }
+
+   /// Text with a check mark next to it when `checked` is true
+   pub fn append_checkable(&mut self, id: usize, text: &str, checked: bool) -> Result<(), ErrorCode> {
+      let check = if checked {
+         winapi::um::winuser::MF_CHECKED
+      } else {
+         winapi::um::winuser::MF_UNCHECKED
       };
+      self.append_string(id, text, winapi::um::winuser::MF_STRING | check)
+   }
+
+   fn append_string(&mut self, id: usize, text: &str, flags: u32) -> Result<(), ErrorCode> {
+      let s = to_win_utf16(text);
+      let result = unsafe { winapi::um::winuser::AppendMenuW(self.inner.as_ptr(), flags, id, s.as_ptr()) };
 
       if result == 0 {
          let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
@@ -96,9 +137,151 @@ pub fn draw_popup_menu(menu: &Menu, x: i32, y: i32, hwnd: &WindowHandle) -> Resu
       return Err(ErrorCode(code));
    }
 
+   // The menu handles Escape itself, but without a message following it the menu
+   // can fail to dismiss when the user clicks outside of it (see KB135788)
+   unsafe {
+      winapi::um::winuser::PostMessageW(hwnd.inner.as_ptr(), winapi::um::winuser::WM_NULL, 0, 0);
+   }
+
+   Ok(())
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Rect {
+   pub left: i32,
+   pub top: i32,
+   pub right: i32,
+   pub bottom: i32,
+}
+
+impl Rect {
+   /// Moves the point inside of this rectangle, if it isn't already
+   pub fn clamp(&self, x: i32, y: i32) -> (i32, i32) {
+      (
+         x.max(self.left).min(self.right - 1),
+         y.max(self.top).min(self.bottom - 1),
+      )
+   }
+}
+
+pub struct MonitorHandle(NonNull<winapi::shared::windef::HMONITOR__>);
+
+/// The monitor containing the point, or the nearest one if the point is off-screen
+pub fn monitor_from_point(x: i32, y: i32) -> MonitorHandle {
+   let point = winapi::shared::windef::POINT { x, y };
+   let monitor = unsafe { winapi::um::winuser::MonitorFromPoint(point, winapi::um::winuser::MONITOR_DEFAULTTONEAREST) };
+
+   // MONITOR_DEFAULTTONEAREST always gives us a monitor
+   MonitorHandle(NonNull::new(monitor).unwrap())
+}
+
+/// All display monitors, in the order the system enumerates them
+pub fn enum_display_monitors() -> Result<Vec<MonitorHandle>, ErrorCode> {
+   unsafe extern "system" fn callback(
+      monitor: winapi::shared::windef::HMONITOR,
+      _hdc: winapi::shared::windef::HDC,
+      _rect: winapi::shared::windef::LPRECT,
+      data: winapi::shared::minwindef::LPARAM,
+   ) -> winapi::shared::minwindef::BOOL {
+      let monitors = &mut *(data as *mut Vec<MonitorHandle>);
+      if let Some(monitor) = NonNull::new(monitor) {
+         monitors.push(MonitorHandle(monitor));
+      }
+      1
+   }
+
+   let mut monitors: Vec<MonitorHandle> = Vec::new();
+   let result = unsafe {
+      winapi::um::winuser::EnumDisplayMonitors(
+         ptr::null_mut(),
+         ptr::null(),
+         Some(callback),
+         &mut monitors as *mut Vec<MonitorHandle> as winapi::shared::minwindef::LPARAM,
+      )
+   };
+
+   if result == 0 {
+      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+      return Err(ErrorCode(code));
+   }
+
+   Ok(monitors)
+}
+
+impl MonitorHandle {
+   /// The area of the monitor not covered by the taskbar or other docked toolbars
+   pub fn work_area(&self) -> Result<Rect, ErrorCode> {
+      let mut info: winapi::um::winuser::MONITORINFO = unsafe { mem::zeroed() };
+      info.cbSize = mem::size_of::<winapi::um::winuser::MONITORINFO>() as u32;
+      let result = unsafe { winapi::um::winuser::GetMonitorInfoW(self.0.as_ptr(), &mut info) };
+
+      if result == 0 {
+         let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+         return Err(ErrorCode(code));
+      }
+
+      Ok(Rect {
+         left: info.rcWork.left,
+         top: info.rcWork.top,
+         right: info.rcWork.right,
+         bottom: info.rcWork.bottom,
+      })
+   }
+}
+
+fn message_box(title: &str, text: &str, style: u32) -> Result<i32, ErrorCode> {
+   let title = to_win_utf16(title);
+   let text = to_win_utf16(text);
+   let result = unsafe { winapi::um::winuser::MessageBoxW(ptr::null_mut(), text.as_ptr(), title.as_ptr(), style) };
+
+   if result == 0 {
+      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+      return Err(ErrorCode(code));
+   }
+
+   Ok(result)
+}
+
+/// Blocks until the user dismisses it
+pub fn show_error(title: &str, text: &str) -> Result<(), ErrorCode> {
+   message_box(
+      title,
+      text,
+      winapi::um::winuser::MB_OK | winapi::um::winuser::MB_ICONERROR | winapi::um::winuser::MB_SETFOREGROUND,
+   )?;
    Ok(())
 }
 
+/// Like `show_error`, but with an information icon, for telling the user something went well
+pub fn show_info(title: &str, text: &str) -> Result<(), ErrorCode> {
+   message_box(
+      title,
+      text,
+      winapi::um::winuser::MB_OK | winapi::um::winuser::MB_ICONINFORMATION | winapi::um::winuser::MB_SETFOREGROUND,
+   )?;
+   Ok(())
+}
+
+/// Like `show_error`, but with a warning icon, for problems ripclip can carry on from
+pub fn show_message_box(title: &str, text: &str) -> Result<(), ErrorCode> {
+   message_box(
+      title,
+      text,
+      winapi::um::winuser::MB_OK | winapi::um::winuser::MB_ICONWARNING | winapi::um::winuser::MB_SETFOREGROUND,
+   )?;
+   Ok(())
+}
+
+/// Blocks until the user answers. Returns true if the user chose "Yes"
+pub fn ask_yes_no(title: &str, text: &str) -> Result<bool, ErrorCode> {
+   let result = message_box(
+      title,
+      text,
+      winapi::um::winuser::MB_YESNO | winapi::um::winuser::MB_SETFOREGROUND,
+   )?;
+   Ok(result == winapi::um::winuser::IDYES)
+}
+
 pub fn set_foreground_window(hwnd: &WindowHandle) -> Result<(), ()> {
    let result = unsafe { winapi::um::winuser::SetForegroundWindow(hwnd.inner.as_ptr()) };
 
@@ -114,6 +297,133 @@ pub struct WindowHandle<'a> {
    class: PhantomData<&'a ClassAtom<'a>>,
 }
 
+impl<'a> WindowHandle<'a> {
+   pub fn message_target(&self) -> MessageTarget {
+      MessageTarget(self.inner)
+   }
+
+   /// The width and height of the area inside the window's border
+   pub fn client_size(&self) -> Result<(i32, i32), ErrorCode> {
+      let mut rect: winapi::shared::windef::RECT = unsafe { mem::zeroed() };
+      let result = unsafe { winapi::um::winuser::GetClientRect(self.inner.as_ptr(), &mut rect) };
+
+      if result == 0 {
+         let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+         return Err(ErrorCode(code));
+      }
+
+      Ok((rect.right - rect.left, rect.bottom - rect.top))
+   }
+}
+
+/// A `LISTBOX` control. It belongs to its parent window, and is destroyed along with it
+pub struct ListBox<'a> {
+   inner: NonNull<winapi::shared::windef::HWND__>,
+   parent: PhantomData<&'a WindowHandle<'a>>,
+}
+
+/// Fills the parent's client area. The parent is sent `LBN_DBLCLK` when an item is double-clicked
+pub fn create_list_box<'a>(parent: &'a WindowHandle) -> Result<ListBox<'a>, ErrorCode> {
+   let (width, height) = parent.client_size()?;
+   let class_name = to_win_utf16("LISTBOX");
+   let handle = unsafe {
+      winapi::um::winuser::CreateWindowExW(
+         0,
+         class_name.as_ptr(),
+         ptr::null(),
+         winapi::um::winuser::WS_CHILD
+            | winapi::um::winuser::WS_VISIBLE
+            | winapi::um::winuser::WS_VSCROLL
+            | winapi::um::winuser::LBS_NOTIFY
+            | winapi::um::winuser::LBS_NOINTEGRALHEIGHT,
+         0,
+         0,
+         width,
+         height,
+         parent.inner.as_ptr(),
+         ptr::null_mut(),
+         ptr::null_mut(),
+         ptr::null_mut(),
+      )
+   };
+
+   if handle.is_null() {
+      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+      return Err(ErrorCode(code));
+   }
+
+   unsafe {
+      Ok(ListBox {
+         inner: NonNull::new_unchecked(handle),
+         parent: PhantomData,
+      })
+   }
+}
+
+impl<'a> ListBox<'a> {
+   fn send_message(&self, message: u32, w_param: usize, l_param: isize) -> isize {
+      unsafe { winapi::um::winuser::SendMessageW(self.inner.as_ptr(), message, w_param, l_param) }
+   }
+
+   /// Fails if the list box runs out of memory
+   pub fn add_string(&self, text: &str) -> Result<(), ()> {
+      let text = to_win_utf16(text);
+      let result = self.send_message(winapi::um::winuser::LB_ADDSTRING, 0, text.as_ptr() as isize);
+
+      if result < 0 {
+         return Err(());
+      }
+
+      Ok(())
+   }
+
+   pub fn delete_string(&self, index: usize) {
+      self.send_message(winapi::um::winuser::LB_DELETESTRING, index, 0);
+   }
+
+   pub fn selected(&self) -> Option<usize> {
+      let result = self.send_message(winapi::um::winuser::LB_GETCURSEL, 0, 0);
+      if result < 0 {
+         None
+      } else {
+         Some(result as usize)
+      }
+   }
+
+   /// Also scrolls the item into view
+   pub fn set_selected(&self, index: usize) {
+      self.send_message(winapi::um::winuser::LB_SETCURSEL, index, 0);
+   }
+
+   /// Sends keyboard input to the list box
+   pub fn focus(&self) {
+      unsafe {
+         winapi::um::winuser::SetFocus(self.inner.as_ptr());
+      }
+   }
+}
+
+/// Identifies a window to post messages to. Unlike a `WindowHandle`, this doesn't own the window,
+/// and can be sent to other threads. Posting to a window that has been destroyed fails harmlessly
+#[derive(Clone, Copy)]
+pub struct MessageTarget(NonNull<winapi::shared::windef::HWND__>);
+
+// PostMessageW may be called from any thread
+unsafe impl Send for MessageTarget {}
+
+impl MessageTarget {
+   pub fn post_message(&self, message: u32) -> Result<(), ErrorCode> {
+      let result = unsafe { winapi::um::winuser::PostMessageW(self.0.as_ptr(), message, 0, 0) };
+
+      if result == 0 {
+         let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+         return Err(ErrorCode(code));
+      }
+
+      Ok(())
+   }
+}
+
 impl<'a> Drop for WindowHandle<'a> {
    fn drop(&mut self) {
       destroy_window(self).unwrap();
@@ -138,9 +448,19 @@ pub struct ClassAtom<'a> {
    hmodule: &'a ModuleHandle,
 }
 
+/// The number of live `ClassAtom`s for each class we've registered. Registering a class that already exists
+/// gives back another `ClassAtom` for it, and only the last one to be dropped unregisters the class
+static CLASS_REFERENCES: Mutex<Vec<(u16, usize)>> = Mutex::new(Vec::new());
+
 impl<'a> Drop for ClassAtom<'a> {
    fn drop(&mut self) {
-      unregister_class(self).unwrap();
+      let mut references = CLASS_REFERENCES.lock().unwrap();
+      let index = references.iter().position(|(x, _)| *x == self.atom.get()).unwrap();
+      references[index].1 -= 1;
+      if references[index].1 == 0 {
+         references.remove(index);
+         unregister_class(self).unwrap();
+      }
    }
 }
 
@@ -164,26 +484,39 @@ fn unregister_class<'a>(class_atom: &mut ClassAtom<'a>) -> Result<(), ErrorCode>
 pub struct ErrorCode(u32);
 
 pub const ERROR_ACCESS_DENIED: ErrorCode = ErrorCode(5);
+const ERROR_INVALID_DATA: ErrorCode = ErrorCode(13);
+pub const ERROR_BUSY: ErrorCode = ErrorCode(170);
+const ERROR_CLASS_ALREADY_EXISTS: ErrorCode = ErrorCode(1410);
+pub const ERROR_TIMEOUT: ErrorCode = ErrorCode(1460);
 
 impl fmt::Display for ErrorCode {
    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-      write!(f, "{}", self.get_description().unwrap())
+      write!(f, "{}", self.get_description())
    }
 }
 
 impl fmt::Debug for ErrorCode {
    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-      write!(f, "{}: {}", self.0, self.get_description().unwrap())
+      write!(f, "{}: {}", self.0, self.get_description())
    }
 }
 
+impl error::Error for ErrorCode {}
+
 impl ErrorCode {
-   pub fn get_description(&self) -> Result<String, FromUtf16Error> {
+   /// The number from GetLastError
+   pub fn code(&self) -> u32 {
+      self.0
+   }
+
+   /// Never fails, since it is used to report other failures
+   pub fn get_description(&self) -> String {
       let mut buffer: Box<[u16]> = vec![0; 65535].into_boxed_slice();
 
+      // Without FORMAT_MESSAGE_IGNORE_INSERTS, messages with inserts (like %1) fail to format, since we have no arguments
       let size = unsafe {
          winapi::um::winbase::FormatMessageW(
-            0x0000_1000,
+            winapi::um::winbase::FORMAT_MESSAGE_FROM_SYSTEM | winapi::um::winbase::FORMAT_MESSAGE_IGNORE_INSERTS,
             ptr::null(),
             self.0,
             0,
@@ -194,12 +527,13 @@ impl ErrorCode {
       };
 
       if size == 0 {
-         unimplemented!()
+         // The code has no system message (it may have come from somewhere other than Win32)
+         return format!("Unknown error {:#x}", self.0);
       }
 
       let utf16_slice = &buffer[0..(size - 1) as usize];
 
-      String::from_utf16(utf16_slice)
+      String::from_utf16_lossy(utf16_slice)
    }
 }
 
@@ -231,6 +565,29 @@ impl FromStr for Modifiers {
    }
 }
 
+impl fmt::Display for Modifiers {
+   /// Set modifiers joined by " + ", in the order Control, Alt, Shift, Win
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      let names = [
+         (Modifiers::CONTROL, "Control"),
+         (Modifiers::ALT, "Alt"),
+         (Modifiers::SHIFT, "Shift"),
+         (Modifiers::WIN, "Win"),
+      ];
+      let mut first = true;
+      for (modifier, name) in names.iter() {
+         if self.contains(*modifier) {
+            if !first {
+               write!(f, " + ")?;
+            }
+            write!(f, "{}", name)?;
+            first = false;
+         }
+      }
+      Ok(())
+   }
+}
+
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum VirtualKey {
@@ -313,6 +670,12 @@ pub enum VirtualKey {
    NumpadSeven,
    NumpadEight,
    NumpadNine,
+   NumpadMultiply,
+   NumpadPlus,
+   NumpadSeparator,
+   NumpadMinus,
+   NumpadDecimal,
+   NumpadDivide,
    F1 = 0x70,
    F2,
    F3,
@@ -344,6 +707,26 @@ pub enum VirtualKey {
    RightControl,
    LeftAlt,
    RightAlt,
+   VolumeMute = 0xAD,
+   VolumeDown,
+   VolumeUp,
+   MediaNextTrack,
+   MediaPrevTrack,
+   MediaStop,
+   MediaPlayPause,
+   // The punctuation keys (VK_OEM_*) are named after what they type on a US keyboard, which is where they are on
+   // any keyboard, even if they type something else
+   Semicolon = 0xBA,
+   Equals,
+   Comma,
+   Minus,
+   Period,
+   Slash,
+   Backtick,
+   LeftBracket = 0xDB,
+   Backslash,
+   RightBracket,
+   Quote,
    Play = 0xFA,
    Zoom,
 }
@@ -436,6 +819,13 @@ impl FromStr for VirtualKey {
          "numpad seven" | "numpad 7" => VirtualKey::NumpadSeven,
          "numpad eight" | "numpad 8" => VirtualKey::NumpadEight,
          "numpad nine" | "numpad 9" => VirtualKey::NumpadNine,
+         "numpad multiply" | "numpad *" => VirtualKey::NumpadMultiply,
+         // There's no "numpad +", as + separates the keys of a hotkey
+         "numpad plus" | "numpad add" => VirtualKey::NumpadPlus,
+         "numpad separator" => VirtualKey::NumpadSeparator,
+         "numpad minus" | "numpad subtract" | "numpad -" => VirtualKey::NumpadMinus,
+         "numpad decimal" | "numpad ." => VirtualKey::NumpadDecimal,
+         "numpad divide" | "numpad /" => VirtualKey::NumpadDivide,
          "f1" => VirtualKey::F1,
          "f2" => VirtualKey::F2,
          "f3" => VirtualKey::F3,
@@ -467,6 +857,24 @@ impl FromStr for VirtualKey {
          "right control" => VirtualKey::RightControl,
          "left alt" => VirtualKey::LeftAlt,
          "right alt" => VirtualKey::RightAlt,
+         "volume mute" | "mute" => VirtualKey::VolumeMute,
+         "volume down" => VirtualKey::VolumeDown,
+         "volume up" => VirtualKey::VolumeUp,
+         "media next" | "next track" => VirtualKey::MediaNextTrack,
+         "media previous" | "media prev" | "previous track" => VirtualKey::MediaPrevTrack,
+         "media stop" => VirtualKey::MediaStop,
+         "media play" | "media play pause" | "play pause" => VirtualKey::MediaPlayPause,
+         "semicolon" | ";" => VirtualKey::Semicolon,
+         "equals" | "=" => VirtualKey::Equals,
+         "comma" | "," => VirtualKey::Comma,
+         "minus" | "-" => VirtualKey::Minus,
+         "period" | "." => VirtualKey::Period,
+         "slash" | "/" => VirtualKey::Slash,
+         "backtick" | "grave" | "`" => VirtualKey::Backtick,
+         "left bracket" | "[" => VirtualKey::LeftBracket,
+         "backslash" | "\\" => VirtualKey::Backslash,
+         "right bracket" | "]" => VirtualKey::RightBracket,
+         "quote" | "apostrophe" | "'" => VirtualKey::Quote,
          "play" => VirtualKey::Play,
          "zoom" => VirtualKey::Zoom,
          _ => return Err(ParseVirtualKeyError::UnknownKey(s.into())),
@@ -474,6 +882,151 @@ impl FromStr for VirtualKey {
    }
 }
 
+impl fmt::Display for VirtualKey {
+   /// The inverse of `from_str`
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      let name = match self {
+         VirtualKey::LeftClick => "Left Click",
+         VirtualKey::RightClick => "Right Click",
+         VirtualKey::Cancel => "Cancel",
+         VirtualKey::MiddleClick => "Middle Click",
+         VirtualKey::Backspace => "Backspace",
+         VirtualKey::Tab => "Tab",
+         VirtualKey::Enter => "Enter",
+         VirtualKey::Shift => "Shift",
+         VirtualKey::Control => "Control",
+         VirtualKey::Alt => "Alt",
+         VirtualKey::Pause => "Pause",
+         VirtualKey::CapsLock => "Caps Lock",
+         VirtualKey::Escape => "Escape",
+         VirtualKey::Space => "Space",
+         VirtualKey::PageUp => "Page Up",
+         VirtualKey::PageDown => "Page Down",
+         VirtualKey::End => "End",
+         VirtualKey::Home => "Home",
+         VirtualKey::Left => "Left",
+         VirtualKey::Up => "Up",
+         VirtualKey::Right => "Right",
+         VirtualKey::Down => "Down",
+         VirtualKey::Select => "Select",
+         VirtualKey::Print => "Print",
+         VirtualKey::Execute => "Execute",
+         VirtualKey::PrintScreen => "Print Screen",
+         VirtualKey::Insert => "Insert",
+         VirtualKey::Delete => "Delete",
+         VirtualKey::Help => "Help",
+         VirtualKey::Zero => "Zero",
+         VirtualKey::One => "One",
+         VirtualKey::Two => "Two",
+         VirtualKey::Three => "Three",
+         VirtualKey::Four => "Four",
+         VirtualKey::Five => "Five",
+         VirtualKey::Six => "Six",
+         VirtualKey::Seven => "Seven",
+         VirtualKey::Eight => "Eight",
+         VirtualKey::Nine => "Nine",
+         VirtualKey::A => "A",
+         VirtualKey::B => "B",
+         VirtualKey::C => "C",
+         VirtualKey::D => "D",
+         VirtualKey::E => "E",
+         VirtualKey::F => "F",
+         VirtualKey::G => "G",
+         VirtualKey::H => "H",
+         VirtualKey::I => "I",
+         VirtualKey::J => "J",
+         VirtualKey::K => "K",
+         VirtualKey::L => "L",
+         VirtualKey::M => "M",
+         VirtualKey::N => "N",
+         VirtualKey::O => "O",
+         VirtualKey::P => "P",
+         VirtualKey::Q => "Q",
+         VirtualKey::R => "R",
+         VirtualKey::S => "S",
+         VirtualKey::T => "T",
+         VirtualKey::U => "U",
+         VirtualKey::V => "V",
+         VirtualKey::W => "W",
+         VirtualKey::X => "X",
+         VirtualKey::Y => "Y",
+         VirtualKey::Z => "Z",
+         VirtualKey::LeftWindows => "Left Windows",
+         VirtualKey::RightWindows => "Right Windows",
+         VirtualKey::Applications => "Applications",
+         VirtualKey::Sleep => "Sleep",
+         VirtualKey::NumpadZero => "Numpad Zero",
+         VirtualKey::NumpadOne => "Numpad One",
+         VirtualKey::NumpadTwo => "Numpad Two",
+         VirtualKey::NumpadThree => "Numpad Three",
+         VirtualKey::NumpadFour => "Numpad Four",
+         VirtualKey::NumpadFive => "Numpad Five",
+         VirtualKey::NumpadSix => "Numpad Six",
+         VirtualKey::NumpadSeven => "Numpad Seven",
+         VirtualKey::NumpadEight => "Numpad Eight",
+         VirtualKey::NumpadNine => "Numpad Nine",
+         VirtualKey::NumpadMultiply => "Numpad Multiply",
+         VirtualKey::NumpadPlus => "Numpad Plus",
+         VirtualKey::NumpadSeparator => "Numpad Separator",
+         VirtualKey::NumpadMinus => "Numpad Minus",
+         VirtualKey::NumpadDecimal => "Numpad Decimal",
+         VirtualKey::NumpadDivide => "Numpad Divide",
+         VirtualKey::F1 => "F1",
+         VirtualKey::F2 => "F2",
+         VirtualKey::F3 => "F3",
+         VirtualKey::F4 => "F4",
+         VirtualKey::F5 => "F5",
+         VirtualKey::F6 => "F6",
+         VirtualKey::F7 => "F7",
+         VirtualKey::F8 => "F8",
+         VirtualKey::F9 => "F9",
+         VirtualKey::F10 => "F10",
+         VirtualKey::F11 => "F11",
+         VirtualKey::F12 => "F12",
+         VirtualKey::F13 => "F13",
+         VirtualKey::F14 => "F14",
+         VirtualKey::F15 => "F15",
+         VirtualKey::F16 => "F16",
+         VirtualKey::F17 => "F17",
+         VirtualKey::F18 => "F18",
+         VirtualKey::F19 => "F19",
+         VirtualKey::F20 => "F20",
+         VirtualKey::F21 => "F21",
+         VirtualKey::F22 => "F22",
+         VirtualKey::F23 => "F23",
+         VirtualKey::F24 => "F24",
+         VirtualKey::Numlock => "Numlock",
+         VirtualKey::LeftShift => "Left Shift",
+         VirtualKey::RightShift => "Right Shift",
+         VirtualKey::LeftControl => "Left Control",
+         VirtualKey::RightControl => "Right Control",
+         VirtualKey::LeftAlt => "Left Alt",
+         VirtualKey::RightAlt => "Right Alt",
+         VirtualKey::VolumeMute => "Volume Mute",
+         VirtualKey::VolumeDown => "Volume Down",
+         VirtualKey::VolumeUp => "Volume Up",
+         VirtualKey::MediaNextTrack => "Media Next",
+         VirtualKey::MediaPrevTrack => "Media Previous",
+         VirtualKey::MediaStop => "Media Stop",
+         VirtualKey::MediaPlayPause => "Media Play",
+         VirtualKey::Semicolon => "Semicolon",
+         VirtualKey::Equals => "Equals",
+         VirtualKey::Comma => "Comma",
+         VirtualKey::Minus => "Minus",
+         VirtualKey::Period => "Period",
+         VirtualKey::Slash => "Slash",
+         VirtualKey::Backtick => "Backtick",
+         VirtualKey::LeftBracket => "Left Bracket",
+         VirtualKey::Backslash => "Backslash",
+         VirtualKey::RightBracket => "Right Bracket",
+         VirtualKey::Quote => "Quote",
+         VirtualKey::Play => "Play",
+         VirtualKey::Zoom => "Zoom",
+      };
+      write!(f, "{}", name)
+   }
+}
+
 impl VirtualKey {
    pub fn is_modifier(self) -> bool {
       self == VirtualKey::Alt
@@ -529,113 +1082,83 @@ pub fn unregister_hotkey(hwnd: Option<&WindowHandle>, id: u16) -> Result<(), Err
    Ok(())
 }
 
-pub fn get_module_handle_ex() -> Result<ModuleHandle, ErrorCode> {
-   let mut module_handle: MaybeUninit<winapi::shared::minwindef::HMODULE> = MaybeUninit::uninit();
-
-   let result = unsafe { winapi::um::libloaderapi::GetModuleHandleExW(0, ptr::null(), module_handle.as_mut_ptr()) };
+/// `WM_TIMER` is posted to the window every `elapse` milliseconds until the timer is killed.
+/// Setting a timer with the id of an existing one restarts it
+pub fn set_timer(window: &WindowHandle, id: usize, elapse: u32) -> Result<(), ErrorCode> {
+   let result = unsafe { winapi::um::winuser::SetTimer(window.inner.as_ptr(), id, elapse, None) };
 
    if result == 0 {
       let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
       return Err(ErrorCode(code));
    }
 
-   unsafe { Ok(ModuleHandle(NonNull::new_unchecked(module_handle.assume_init()))) }
-}
-
-fn to_win_utf16(inp: &str) -> Vec<u16> {
-   inp.encode_utf16().chain(iter::once(0)).collect()
+   Ok(())
 }
 
-pub fn register_class_ex<'a>(
-   module_handle: &'a ModuleHandle,
-   message_fn: winapi::um::winuser::WNDPROC,
-   name: &str,
-) -> Result<ClassAtom<'a>, ErrorCode> {
-   let options = winapi::um::winuser::WNDCLASSEXW {
-      cbSize: mem::size_of::<winapi::um::winuser::WNDCLASSEXW>() as u32,
-      style: 0x0000_0000,
-      lpfnWndProc: message_fn,
-      cbClsExtra: 0,
-      cbWndExtra: 0,
-      hInstance: module_handle.0.as_ptr(),
-      hIcon: ptr::null_mut(),
-      hCursor: ptr::null_mut(),
-      hbrBackground: ptr::null_mut(),
-      lpszMenuName: ptr::null(),
-      lpszClassName: to_win_utf16(name).as_ptr(),
-      hIconSm: ptr::null_mut(),
-   };
-
-   let result = unsafe { winapi::um::winuser::RegisterClassExW(&options) };
+pub fn kill_timer(window: &WindowHandle, id: usize) -> Result<(), ErrorCode> {
+   let result = unsafe { winapi::um::winuser::KillTimer(window.inner.as_ptr(), id) };
 
    if result == 0 {
       let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
       return Err(ErrorCode(code));
    }
 
-   unsafe {
-      Ok(ClassAtom {
-         atom: num::NonZeroU16::new_unchecked(result),
-         hmodule: module_handle,
-      })
-   }
+   Ok(())
 }
 
-pub enum WindowParent<'a> {
-   _NoParent,
-   _SomeParent(&'a WindowHandle<'a>),
-   MessageOnly,
+/// Tracks whether a modifier is held with a low level keyboard hook, which sees every key press on the system.
+/// The hook only runs while the thread that installed it is pumping messages, and every key press waits for it.
+/// Only one can be installed at a time; it's removed when this is dropped
+pub struct ModifierHook {
+   inner: NonNull<winapi::shared::windef::HHOOK__>,
 }
 
-#[allow(clippy::too_many_arguments)] // We're roughly trying to mirror the Windows API, so the number of arguments is what it is
-pub fn create_window_ex<'a>(
-   ex_style: u32,
-   class_atom: &'a ClassAtom,
-   window_style: u32,
-   x: i32,
-   y: i32,
-   width: i32,
-   height: i32,
-   parent: &WindowParent,
-) -> Result<WindowHandle<'a>, ErrorCode> {
-   let parent_ptr = match parent {
-      WindowParent::_NoParent => ptr::null_mut(),
-      WindowParent::_SomeParent(handle) => handle.inner.as_ptr(),
-      WindowParent::MessageOnly => winapi::um::winuser::HWND_MESSAGE,
-   };
-
-   let handle = unsafe {
-      winapi::um::winuser::CreateWindowExW(
-         ex_style,
-         usize::from(class_atom.atom.get()) as *const u16,
-         ptr::null(),
-         window_style,
-         x,
-         y,
-         width,
-         height,
-         parent_ptr,
-         ptr::null_mut(),
-         ptr::null_mut(),
-         ptr::null_mut(),
+/// The `Modifiers` bits being watched, or 0 if no hook is installed
+static HOOKED_MODIFIER: AtomicU32 = AtomicU32::new(0);
+/// Bit 0 is set while the left key of the hooked modifier is down, bit 1 the right
+static HOOKED_MODIFIER_KEYS_DOWN: AtomicU32 = AtomicU32::new(0);
+
+/// `modifier` should be exactly one of `ALT`, `CONTROL`, `SHIFT` or `WIN`
+pub fn hook_modifier(module: &ModuleHandle, modifier: Modifiers) -> Result<ModifierHook, ErrorCode> {
+   debug_assert_eq!(HOOKED_MODIFIER.load(Ordering::SeqCst), 0);
+   HOOKED_MODIFIER.store(modifier.bits(), Ordering::SeqCst);
+   HOOKED_MODIFIER_KEYS_DOWN.store(0, Ordering::SeqCst);
+   let hook = unsafe {
+      winapi::um::winuser::SetWindowsHookExW(
+         winapi::um::winuser::WH_KEYBOARD_LL,
+         Some(low_level_keyboard_proc),
+         module.0.as_ptr(),
+         0,
       )
    };
 
-   if handle.is_null() {
-      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
-      return Err(ErrorCode(code));
+   match NonNull::new(hook) {
+      Some(inner) => Ok(ModifierHook { inner }),
+      None => {
+         let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+         HOOKED_MODIFIER.store(0, Ordering::SeqCst);
+         Err(ErrorCode(code))
+      }
    }
+}
 
-   unsafe {
-      Ok(WindowHandle {
-         inner: NonNull::new_unchecked(handle),
-         class: PhantomData,
-      })
+impl ModifierHook {
+   /// A modifier that was already down when the hook was installed isn't seen until it's pressed again
+   pub fn is_held(&self) -> bool {
+      HOOKED_MODIFIER_KEYS_DOWN.load(Ordering::SeqCst) != 0
    }
 }
 
-pub fn add_clipboard_format_listener(hwnd: &WindowHandle) -> Result<(), ErrorCode> {
-   let result = unsafe { winapi::um::winuser::AddClipboardFormatListener(hwnd.inner.as_ptr()) };
+impl Drop for ModifierHook {
+   fn drop(&mut self) {
+      unhook_modifier(self).unwrap();
+   }
+}
+
+fn unhook_modifier(hook: &mut ModifierHook) -> Result<(), ErrorCode> {
+   let result = unsafe { winapi::um::winuser::UnhookWindowsHookEx(hook.inner.as_ptr()) };
+   HOOKED_MODIFIER.store(0, Ordering::SeqCst);
+   HOOKED_MODIFIER_KEYS_DOWN.store(0, Ordering::SeqCst);
 
    if result == 0 {
       let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
@@ -645,15 +1168,303 @@ pub fn add_clipboard_format_listener(hwnd: &WindowHandle) -> Result<(), ErrorCod
    Ok(())
 }
 
-pub fn remove_clipboard_format_listener(hwnd: &WindowHandle) -> Result<(), ErrorCode> {
-   let success = unsafe {
-      let success_int = winapi::um::winuser::RemoveClipboardFormatListener(hwnd.inner.as_ptr());
-      success_int == 1
-   };
-
-   if !success {
-      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
-      return Err(ErrorCode(code));
+unsafe extern "system" fn low_level_keyboard_proc(
+   code: winapi::ctypes::c_int,
+   w_param: winapi::shared::minwindef::WPARAM,
+   l_param: winapi::shared::minwindef::LPARAM,
+) -> winapi::shared::minwindef::LRESULT {
+   // This runs for every key press on the system, so it has to be quick
+   if code == winapi::um::winuser::HC_ACTION {
+      let info = &*(l_param as *const winapi::um::winuser::KBDLLHOOKSTRUCT);
+      let modifier = Modifiers::from_bits_truncate(HOOKED_MODIFIER.load(Ordering::SeqCst));
+      let keys = match modifier {
+         Modifiers::ALT => Some((VirtualKey::LeftAlt, VirtualKey::RightAlt)),
+         Modifiers::CONTROL => Some((VirtualKey::LeftControl, VirtualKey::RightControl)),
+         Modifiers::SHIFT => Some((VirtualKey::LeftShift, VirtualKey::RightShift)),
+         Modifiers::WIN => Some((VirtualKey::LeftWindows, VirtualKey::RightWindows)),
+         _ => None,
+      };
+      let bit = match keys {
+         Some((left, _)) if info.vkCode == left as u32 => 1,
+         Some((_, right)) if info.vkCode == right as u32 => 2,
+         _ => 0,
+      };
+      if bit != 0 {
+         match w_param as u32 {
+            winapi::um::winuser::WM_KEYDOWN | winapi::um::winuser::WM_SYSKEYDOWN => {
+               HOOKED_MODIFIER_KEYS_DOWN.fetch_or(bit, Ordering::SeqCst);
+            }
+            winapi::um::winuser::WM_KEYUP | winapi::um::winuser::WM_SYSKEYUP => {
+               HOOKED_MODIFIER_KEYS_DOWN.fetch_and(!bit, Ordering::SeqCst);
+            }
+            _ => (),
+         }
+      }
+   }
+   winapi::um::winuser::CallNextHookEx(ptr::null_mut(), code, w_param, l_param)
+}
+
+pub fn get_module_handle_ex() -> Result<ModuleHandle, ErrorCode> {
+   let mut module_handle: MaybeUninit<winapi::shared::minwindef::HMODULE> = MaybeUninit::uninit();
+
+   let result = unsafe { winapi::um::libloaderapi::GetModuleHandleExW(0, ptr::null(), module_handle.as_mut_ptr()) };
+
+   if result == 0 {
+      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+      return Err(ErrorCode(code));
+   }
+
+   unsafe { Ok(ModuleHandle(NonNull::new_unchecked(module_handle.assume_init()))) }
+}
+
+/// Bind the result to a local before passing `as_ptr()` to Win32. `to_win_utf16(x).as_ptr()` dangles as soon as the
+/// statement ends
+fn to_win_utf16(inp: &str) -> Vec<u16> {
+   inp.encode_utf16().chain(iter::once(0)).collect()
+}
+
+/// Reads a null terminated UTF-16 string, such as one passed in the lParam of a message
+///
+/// # Safety
+/// `ptr` must point to a null terminated string
+pub unsafe fn from_win_utf16(ptr: *const u16) -> String {
+   let mut len = 0;
+   while *ptr.add(len) != 0 {
+      len += 1;
+   }
+   String::from_utf16_lossy(slice::from_raw_parts(ptr, len))
+}
+
+/// The `WM_SETTINGCHANGE` area that is changed when the user switches between light and dark mode
+pub const IMMERSIVE_COLOR_SET: &str = "ImmersiveColorSet";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Theme {
+   Light,
+   Dark,
+}
+
+/// The light/dark mode the user has chosen for apps. Versions of Windows without a dark mode are always light
+pub fn apps_theme() -> Theme {
+   let subkey = to_win_utf16("Software\\Microsoft\\Windows\\CurrentVersion\\Themes\\Personalize");
+   let value = to_win_utf16("AppsUseLightTheme");
+   let mut data: u32 = 1;
+   let mut size = mem::size_of::<u32>() as u32;
+   let result = unsafe {
+      winapi::um::winreg::RegGetValueW(
+         winapi::um::winreg::HKEY_CURRENT_USER,
+         subkey.as_ptr(),
+         value.as_ptr(),
+         winapi::um::winreg::RRF_RT_REG_DWORD,
+         ptr::null_mut(),
+         &mut data as *mut u32 as *mut winapi::ctypes::c_void,
+         &mut size,
+      )
+   };
+
+   // The value not existing (or any other error) means there is no dark mode to speak of
+   if result == 0 && data == 0 {
+      Theme::Dark
+   } else {
+      Theme::Light
+   }
+}
+
+const RUN_KEY: &str = "Software\\Microsoft\\Windows\\CurrentVersion\\Run";
+const RUN_VALUE: &str = "ripclip";
+
+/// Adds `exe_path` to (or removes ripclip from) the programs Windows starts when the current user logs in
+pub fn set_run_on_login(enable: bool, exe_path: &Path) -> Result<(), ErrorCode> {
+   let subkey = to_win_utf16(RUN_KEY);
+   let value = to_win_utf16(RUN_VALUE);
+   let mut key: winapi::shared::minwindef::HKEY = ptr::null_mut();
+   let result = unsafe {
+      winapi::um::winreg::RegOpenKeyExW(
+         winapi::um::winreg::HKEY_CURRENT_USER,
+         subkey.as_ptr(),
+         0,
+         winapi::um::winnt::KEY_SET_VALUE,
+         &mut key,
+      )
+   };
+   // The registry functions return their error rather than setting the last error
+   if result != 0 {
+      return Err(ErrorCode(result as u32));
+   }
+
+   let result = if enable {
+      // Quoted, as the path may contain spaces
+      let command: Vec<u16> = iter::once(u16::from(b'"'))
+         .chain(exe_path.as_os_str().encode_wide())
+         .chain("\"".encode_utf16())
+         .chain(iter::once(0))
+         .collect();
+      unsafe {
+         winapi::um::winreg::RegSetValueExW(
+            key,
+            value.as_ptr(),
+            0,
+            winapi::um::winnt::REG_SZ,
+            command.as_ptr() as *const u8,
+            (command.len() * mem::size_of::<u16>()) as u32,
+         )
+      }
+   } else {
+      match unsafe { winapi::um::winreg::RegDeleteValueW(key, value.as_ptr()) } as u32 {
+         // Already not there
+         winapi::shared::winerror::ERROR_FILE_NOT_FOUND => 0,
+         result => result as i32,
+      }
+   };
+   unsafe {
+      winapi::um::winreg::RegCloseKey(key);
+   }
+
+   if result != 0 {
+      return Err(ErrorCode(result as u32));
+   }
+   Ok(())
+}
+
+/// How the background of windows belonging to a class is erased
+pub enum ClassBackground {
+   /// Appropriate for windows that are never shown
+   None,
+   /// A `COLOR_*` system color index. Visible windows should use this, otherwise
+   /// they flash before their first paint
+   SystemColor(i32),
+}
+
+/// To further reduce flicker, visible windows should be created with `WS_EX_COMPOSITED`, which double buffers
+/// painting. The class style deliberately doesn't include `CS_HREDRAW`/`CS_VREDRAW`, which repaint the whole
+/// window on every resize.
+pub fn register_class_ex<'a>(
+   module_handle: &'a ModuleHandle,
+   message_fn: winapi::um::winuser::WNDPROC,
+   name: &str,
+   background: ClassBackground,
+) -> Result<ClassAtom<'a>, ErrorCode> {
+   let (background_brush, cursor) = match background {
+      ClassBackground::None => (ptr::null_mut(), ptr::null_mut()),
+      // System color brushes are specified as the color index plus one
+      ClassBackground::SystemColor(color) => {
+         let arrow = unsafe { winapi::um::winuser::LoadCursorW(ptr::null_mut(), winapi::um::winuser::IDC_ARROW) };
+         ((color + 1) as usize as winapi::shared::windef::HBRUSH, arrow)
+      }
+   };
+
+   let class_name = to_win_utf16(name);
+   let options = winapi::um::winuser::WNDCLASSEXW {
+      cbSize: mem::size_of::<winapi::um::winuser::WNDCLASSEXW>() as u32,
+      style: 0x0000_0000,
+      lpfnWndProc: message_fn,
+      cbClsExtra: 0,
+      cbWndExtra: 0,
+      hInstance: module_handle.0.as_ptr(),
+      hIcon: ptr::null_mut(),
+      hCursor: cursor,
+      hbrBackground: background_brush,
+      lpszMenuName: ptr::null(),
+      lpszClassName: class_name.as_ptr(),
+      hIconSm: ptr::null_mut(),
+   };
+
+   let mut atom = unsafe { winapi::um::winuser::RegisterClassExW(&options) };
+
+   if atom == 0 {
+      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+      if ErrorCode(code) != ERROR_CLASS_ALREADY_EXISTS {
+         return Err(ErrorCode(code));
+      }
+
+      // Reuse the existing class. It keeps the options it was originally registered with
+      let mut existing: winapi::um::winuser::WNDCLASSEXW = unsafe { mem::zeroed() };
+      existing.cbSize = mem::size_of::<winapi::um::winuser::WNDCLASSEXW>() as u32;
+      // Despite being documented as a BOOL, the result is the class atom
+      let result =
+         unsafe { winapi::um::winuser::GetClassInfoExW(module_handle.0.as_ptr(), class_name.as_ptr(), &mut existing) };
+
+      if result == 0 {
+         let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+         return Err(ErrorCode(code));
+      }
+
+      atom = result as u16;
+   }
+
+   let mut references = CLASS_REFERENCES.lock().unwrap();
+   match references.iter_mut().find(|(x, _)| *x == atom) {
+      Some((_, count)) => *count += 1,
+      None => references.push((atom, 1)),
+   }
+
+   unsafe {
+      Ok(ClassAtom {
+         atom: num::NonZeroU16::new_unchecked(atom),
+         hmodule: module_handle,
+      })
+   }
+}
+
+pub enum WindowParent<'a> {
+   NoParent,
+   _SomeParent(&'a WindowHandle<'a>),
+   MessageOnly,
+}
+
+#[allow(clippy::too_many_arguments)] // We're roughly trying to mirror the Windows API, so the number of arguments is what it is
+pub fn create_window_ex<'a>(
+   ex_style: u32,
+   class_atom: &'a ClassAtom,
+   window_style: u32,
+   x: i32,
+   y: i32,
+   width: i32,
+   height: i32,
+   parent: &WindowParent,
+) -> Result<WindowHandle<'a>, ErrorCode> {
+   let parent_ptr = match parent {
+      WindowParent::NoParent => ptr::null_mut(),
+      WindowParent::_SomeParent(handle) => handle.inner.as_ptr(),
+      WindowParent::MessageOnly => winapi::um::winuser::HWND_MESSAGE,
+   };
+
+   let handle = unsafe {
+      winapi::um::winuser::CreateWindowExW(
+         ex_style,
+         usize::from(class_atom.atom.get()) as *const u16,
+         ptr::null(),
+         window_style,
+         x,
+         y,
+         width,
+         height,
+         parent_ptr,
+         ptr::null_mut(),
+         ptr::null_mut(),
+         ptr::null_mut(),
+      )
+   };
+
+   if handle.is_null() {
+      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+      return Err(ErrorCode(code));
+   }
+
+   unsafe {
+      Ok(WindowHandle {
+         inner: NonNull::new_unchecked(handle),
+         class: PhantomData,
+      })
+   }
+}
+
+pub fn add_clipboard_format_listener(hwnd: &WindowHandle) -> Result<(), ErrorCode> {
+   let result = unsafe { winapi::um::winuser::AddClipboardFormatListener(hwnd.inner.as_ptr()) };
+
+   if result == 0 {
+      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+      return Err(ErrorCode(code));
    }
 
    Ok(())
@@ -662,6 +1473,9 @@ pub fn remove_clipboard_format_listener(hwnd: &WindowHandle) -> Result<(), Error
 #[repr(u32)]
 #[derive(Copy, Clone)]
 pub enum ClipboardFormat {
+   /// A `BITMAPINFO` header followed by the bitmap bits. Windows converts to and from GDI bitmaps as needed, so only
+   /// this is read and written
+   Dib = 8,
    UnicodeText = 13,
 }
 
@@ -669,41 +1483,272 @@ pub fn is_clipboard_format_available(format: ClipboardFormat) -> bool {
    unsafe { winapi::um::winuser::IsClipboardFormatAvailable(format as u32) != 0 }
 }
 
+/// Whether the data of a format is stored in global memory, and so can be copied as bytes.
+/// The exceptions are GDI objects (like `CF_BITMAP`) and formats the owner draws itself
+pub fn is_global_memory_format(format: u32) -> bool {
+   !matches!(
+      format,
+      winapi::um::winuser::CF_BITMAP
+         | winapi::um::winuser::CF_METAFILEPICT
+         | winapi::um::winuser::CF_PALETTE
+         | winapi::um::winuser::CF_ENHMETAFILE
+         | winapi::um::winuser::CF_OWNERDISPLAY
+         | winapi::um::winuser::CF_DSPBITMAP
+         | winapi::um::winuser::CF_DSPMETAFILEPICT
+         | winapi::um::winuser::CF_DSPENHMETAFILE
+   )
+}
+
+/// Formats registered so far, so that each name is only registered with the system once
+static REGISTERED_CLIPBOARD_FORMATS: Mutex<Vec<(String, u32)>> = Mutex::new(Vec::new());
+
+/// The format browsers and office applications use for rich text, alongside `UnicodeText`
+pub fn html_format() -> Result<u32, ErrorCode> {
+   register_clipboard_format("HTML Format")
+}
+
+/// Set (with any data) by password managers on a copy that clipboard monitors shouldn't look at
+pub fn exclude_from_monitor_format() -> Result<u32, ErrorCode> {
+   register_clipboard_format("ExcludeClipboardContentFromMonitorProcessing")
+}
+
+/// Holds a `DWORD` saying whether a copy may be recorded in clipboard history, where 0 means it may not
+pub fn can_include_in_history_format() -> Result<u32, ErrorCode> {
+   register_clipboard_format("CanIncludeInClipboardHistory")
+}
+
+/// Like `is_clipboard_format_available`, for formats that aren't in `ClipboardFormat`
+pub fn is_registered_format_available(format: u32) -> bool {
+   unsafe { winapi::um::winuser::IsClipboardFormatAvailable(format) != 0 }
+}
+
+/// Gets the id of a non-standard clipboard format (e.g. "HTML Format"), registering it if need be
+pub fn register_clipboard_format(name: &str) -> Result<u32, ErrorCode> {
+   let mut registered_formats = REGISTERED_CLIPBOARD_FORMATS.lock().unwrap();
+   if let Some((_, id)) = registered_formats.iter().find(|(x, _)| x == name) {
+      return Ok(*id);
+   }
+
+   let s = to_win_utf16(name);
+   let id = unsafe { winapi::um::winuser::RegisterClipboardFormatW(s.as_ptr()) };
+
+   if id == 0 {
+      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+      return Err(ErrorCode(code));
+   }
+
+   registered_formats.push((name.to_owned(), id));
+   Ok(id)
+}
+
+/// The clipboard is closed when this (or the `OwnedClipboardHandle` it becomes) is dropped.
+/// It must be dropped before the clipboard is opened again: Windows lets a window open the clipboard
+/// while it already has it open, but the handle from the first open is then closed out from under it
 pub struct ClipboardHandle {
    _inner: (),
 }
 
+/// Only checked in debug builds, to catch the clipboard being opened twice
+static CLIPBOARD_OPEN: AtomicBool = AtomicBool::new(false);
+
 pub struct OwnedClipboardHandle {
    _inner: (),
 }
 
-#[derive(Clone, PartialEq)]
+// Comparing and hashing the raw buffer is only right because `from_utf16` normalizes the null terminator
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct ClipboardText(Vec<u8>);
 
+impl ClipboardText {
+   /// All construction should go through here, so that the buffer is always
+   /// an even number of bytes ending in exactly one UTF-16 null terminator
+   pub fn from_utf16(text: &[u16]) -> ClipboardText {
+      let text_len = text.iter().rposition(|&c| c != 0).map_or(0, |i| i + 1);
+      let mut buffer: Vec<u8> = Vec::with_capacity((text_len + 1) * 2);
+      for unit in text[..text_len].iter().chain(iter::once(&0)) {
+         buffer.extend_from_slice(&unit.to_ne_bytes());
+      }
+      ClipboardText(buffer)
+   }
+
+   /// Reads `CF_UNICODETEXT` data. The allocation can be bigger than the text, so everything from the first null on
+   /// is slack (or garbage) and is dropped
+   pub fn from_clipboard_bytes(bytes: &[u8]) -> Result<ClipboardText, ErrorCode> {
+      if bytes.len() % 2 != 0 {
+         return Err(ERROR_INVALID_DATA);
+      }
+
+      let utf16: Vec<u16> = bytes
+         .chunks_exact(2)
+         .map(|c| u16::from_ne_bytes([c[0], c[1]]))
+         .take_while(|&c| c != 0)
+         .collect();
+      Ok(ClipboardText::from_utf16(&utf16))
+   }
+
+   #[allow(clippy::should_implement_trait)] // Can't fail, so FromStr's Result would only get in the way
+   pub fn from_str(text: &str) -> ClipboardText {
+      ClipboardText::from_utf16(&text.encode_utf16().collect::<Vec<_>>())
+   }
+
+   /// The text up to the first null. Fails if it isn't valid UTF-16 (such as an unpaired surrogate); `to_string`
+   /// decodes lossily instead
+   pub fn as_string(&self) -> Result<String, FromUtf16Error> {
+      String::from_utf16(&self.utf16())
+   }
+
+   /// The raw buffer, as it is placed on the clipboard (UTF-16, including the null terminator)
+   pub fn as_bytes(&self) -> &[u8] {
+      &self.0
+   }
+
+   fn utf16(&self) -> Vec<u16> {
+      self
+         .0
+         .chunks_exact(2)
+         .map(|c| u16::from_ne_bytes([c[0], c[1]]))
+         .take_while(|&c| c != 0)
+         .collect()
+   }
+}
+
+impl fmt::Display for ClipboardText {
+   /// The text up to the first null, with invalid UTF-16 replaced by the replacement character
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      let text = self
+         .as_string()
+         .unwrap_or_else(|_| String::from_utf16_lossy(&self.utf16()));
+      f.write_str(&text)
+   }
+}
+
+/// Something that can be on the clipboard and on the stack
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum ClipboardEntry {
+   /// Text, and the `HTML Format` data that was copied along with it (if any)
+   Text(ClipboardText, Option<Vec<u8>>),
+   /// The `Dib` data of the image
+   Image(Vec<u8>),
+}
+
+impl ClipboardEntry {
+   pub fn as_text(&self) -> Option<&ClipboardText> {
+      match self {
+         ClipboardEntry::Text(text, _) => Some(text),
+         ClipboardEntry::Image(_) => None,
+      }
+   }
+
+   /// The raw buffer of the text or image, as it is placed on the clipboard
+   pub fn as_bytes(&self) -> &[u8] {
+      match self {
+         ClipboardEntry::Text(text, _) => text.as_bytes(),
+         ClipboardEntry::Image(dib) => dib,
+      }
+   }
+
+   /// Bytes of memory used, including any HTML
+   pub fn size(&self) -> usize {
+      match self {
+         ClipboardEntry::Text(text, html) => text.as_bytes().len() + html.as_ref().map_or(0, Vec::len),
+         ClipboardEntry::Image(dib) => dib.len(),
+      }
+   }
+
+   /// Width and height of an image, read from its `BITMAPINFOHEADER`. None for text or a truncated header
+   pub fn image_size(&self) -> Option<(u32, u32)> {
+      let dib = match self {
+         ClipboardEntry::Text(..) => return None,
+         ClipboardEntry::Image(dib) => dib,
+      };
+      if dib.len() < 12 {
+         return None;
+      }
+      let width = i32::from_le_bytes([dib[4], dib[5], dib[6], dib[7]]);
+      // Negative for images stored top-down
+      let height = i32::from_le_bytes([dib[8], dib[9], dib[10], dib[11]]);
+      Some((width.unsigned_abs(), height.unsigned_abs()))
+   }
+}
+
 impl ClipboardHandle {
+   /// The length comes from the size of the memory holding the text rather than a search for the terminator, so
+   /// nothing past the end is read, even if the application that copied the text forgot the terminator
    pub fn get_text(&self) -> Result<ClipboardText, ErrorCode> {
-      let handle = unsafe { winapi::um::winuser::GetClipboardData(ClipboardFormat::UnicodeText as u32) };
+      let bytes = self.get_data(ClipboardFormat::UnicodeText as u32)?;
+      ClipboardText::from_clipboard_bytes(&bytes)
+   }
+
+   /// The size of the memory holding a format, without copying anything out of it. This can be bigger than the data
+   /// itself. Only meaningful for formats where `is_global_memory_format` is true
+   pub fn data_size(&self, format: u32) -> Result<usize, ErrorCode> {
+      let handle = unsafe { winapi::um::winuser::GetClipboardData(format) };
 
       if handle.is_null() {
          let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
          return Err(ErrorCode(code));
       }
 
-      unsafe {
-         let str_len = {
-            let mut index = 1;
-            while *((handle as *const u8).offset(index - 1)) != 0 || *((handle as *const u8).offset(index)) != 0 {
-               index += 2;
-            }
-            index as usize + 1
-         };
-         let mut buffer: Vec<u8> = Vec::with_capacity(str_len);
-         buffer.set_len(str_len);
+      let size = unsafe { winapi::um::winbase::GlobalSize(handle) };
 
-         ptr::copy(handle as *const u8, buffer.as_mut_ptr(), str_len);
+      if size == 0 {
+         let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+         return Err(ErrorCode(code));
+      }
 
-         Ok(ClipboardText(buffer))
+      Ok(size)
+   }
+
+   /// A copy of the image as a device-independent bitmap (a header followed by the pixels, without a file header)
+   pub fn get_bitmap(&self) -> Result<Vec<u8>, ErrorCode> {
+      self.get_data(ClipboardFormat::Dib as u32)
+   }
+
+   /// Every format on the clipboard, including those the system can synthesize from others
+   pub fn formats(&self) -> Result<Vec<u32>, ErrorCode> {
+      let mut formats = Vec::new();
+      let mut format = 0;
+      loop {
+         format = unsafe { winapi::um::winuser::EnumClipboardFormats(format) };
+         if format == 0 {
+            break;
+         }
+         formats.push(format);
+      }
+
+      // Zero is returned both at the end of the formats and on failure
+      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+      if code != 0 {
+         return Err(ErrorCode(code));
       }
+
+      Ok(formats)
+   }
+
+   /// A copy of the data of a format. Only valid for formats where `is_global_memory_format` is true
+   pub fn get_data(&self, format: u32) -> Result<Vec<u8>, ErrorCode> {
+      let handle = unsafe { winapi::um::winuser::GetClipboardData(format) };
+
+      if handle.is_null() {
+         let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+         return Err(ErrorCode(code));
+      }
+
+      let data = unsafe { winapi::um::winbase::GlobalLock(handle) };
+
+      if data.is_null() {
+         let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+         return Err(ErrorCode(code));
+      }
+
+      let bytes = unsafe {
+         let size = winapi::um::winbase::GlobalSize(handle);
+         let bytes = slice::from_raw_parts(data as *const u8, size).to_vec();
+         winapi::um::winbase::GlobalUnlock(handle);
+         bytes
+      };
+
+      Ok(bytes)
    }
 
    // Set clipboard content
@@ -722,6 +1767,7 @@ impl ClipboardHandle {
 }
 
 fn close_clipboard() -> Result<(), ErrorCode> {
+   CLIPBOARD_OPEN.store(false, Ordering::SeqCst);
    let result = unsafe { winapi::um::winuser::CloseClipboard() };
 
    if result == 0 {
@@ -739,22 +1785,50 @@ impl Drop for ClipboardHandle {
 }
 
 impl OwnedClipboardHandle {
-   pub fn set_text(&self, mut clipboard_text: ClipboardText) -> Result<(), ErrorCode> {
-      let result = unsafe {
-         winapi::um::winuser::SetClipboardData(
-            ClipboardFormat::UnicodeText as u32,
-            clipboard_text.0.as_mut_ptr() as *mut winapi::ctypes::c_void,
-         )
-      };
+   pub fn set_text(&self, clipboard_text: ClipboardText) -> Result<(), ErrorCode> {
+      self.set_global_data(ClipboardFormat::UnicodeText as u32, clipboard_text.as_bytes())
+   }
 
-      if result.is_null() {
+   /// Expects a device-independent bitmap, in the form `get_bitmap` returns
+   pub fn set_bitmap(&self, dib: &[u8]) -> Result<(), ErrorCode> {
+      self.set_global_data(ClipboardFormat::Dib as u32, dib)
+   }
+
+   /// Places a copy of the bytes on the clipboard. The clipboard requires its data to be in moveable global memory
+   /// (not memory from Rust's allocator), which it takes ownership of. Use this for any format stored in global
+   /// memory, no matter how large
+   pub fn set_global_data(&self, format: u32, bytes: &[u8]) -> Result<(), ErrorCode> {
+      let handle = unsafe { winapi::um::winbase::GlobalAlloc(winapi::um::winbase::GMEM_MOVEABLE, bytes.len()) };
+
+      if handle.is_null() {
          let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
          return Err(ErrorCode(code));
       }
 
-      // I'm not sure how (if) this buffer ever gets freed...
-      mem::forget(clipboard_text);
+      unsafe {
+         let data = winapi::um::winbase::GlobalLock(handle);
+         if data.is_null() {
+            let code = winapi::um::errhandlingapi::GetLastError();
+            winapi::um::winbase::GlobalFree(handle);
+            return Err(ErrorCode(code));
+         }
+         ptr::copy_nonoverlapping(bytes.as_ptr(), data as *mut u8, bytes.len());
+         winapi::um::winbase::GlobalUnlock(handle);
+      }
 
+      let result = unsafe { winapi::um::winuser::SetClipboardData(format, handle) };
+
+      if result.is_null() {
+         let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+         // We still own the memory if the clipboard didn't take it
+         unsafe {
+            winapi::um::winbase::GlobalFree(handle);
+         }
+         return Err(ErrorCode(code));
+      }
+
+      // The clipboard owns the memory now, and frees it the next time the clipboard is emptied.
+      // Freeing (or even locking) it here would free it twice
       Ok(())
    }
 }
@@ -765,6 +1839,212 @@ impl Drop for OwnedClipboardHandle {
    }
 }
 
+/// Lets ripclip print to the console it was run from, since as a GUI program it has none of its own.
+/// Fails if ripclip wasn't run from a console
+pub fn attach_parent_console() -> Result<(), ErrorCode> {
+   let result = unsafe { winapi::um::wincon::AttachConsole(winapi::um::wincon::ATTACH_PARENT_PROCESS) };
+
+   if result == 0 {
+      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+      return Err(ErrorCode(code));
+   }
+
+   Ok(())
+}
+
+/// The system increments this every time the contents of the clipboard change
+pub fn get_clipboard_sequence_number() -> u32 {
+   unsafe { winapi::um::winuser::GetClipboardSequenceNumber() }
+}
+
+/// The number of formats currently on the clipboard. Zero means the clipboard is empty
+pub fn count_clipboard_formats() -> Result<u32, ErrorCode> {
+   unsafe { winapi::um::errhandlingapi::SetLastError(0) };
+   let count = unsafe { winapi::um::winuser::CountClipboardFormats() };
+
+   if count == 0 {
+      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+      if code != 0 {
+         return Err(ErrorCode(code));
+      }
+   }
+
+   Ok(count as u32)
+}
+
+pub fn get_clipboard_owner() -> Option<NonNull<winapi::shared::windef::HWND__>> {
+   NonNull::new(unsafe { winapi::um::winuser::GetClipboardOwner() })
+}
+
+pub fn get_foreground_window() -> Option<NonNull<winapi::shared::windef::HWND__>> {
+   NonNull::new(unsafe { winapi::um::winuser::GetForegroundWindow() })
+}
+
+/// Gets the executable name (e.g. `chrome.exe`) of the process that created the given window
+pub fn get_window_process_name(hwnd: NonNull<winapi::shared::windef::HWND__>) -> Result<String, ErrorCode> {
+   let mut process_id: u32 = 0;
+   unsafe {
+      winapi::um::winuser::GetWindowThreadProcessId(hwnd.as_ptr(), &mut process_id);
+   }
+
+   if process_id == 0 {
+      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+      return Err(ErrorCode(code));
+   }
+
+   let process = unsafe {
+      winapi::um::processthreadsapi::OpenProcess(winapi::um::winnt::PROCESS_QUERY_LIMITED_INFORMATION, 0, process_id)
+   };
+
+   if process.is_null() {
+      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+      return Err(ErrorCode(code));
+   }
+
+   let mut buffer: [u16; winapi::shared::minwindef::MAX_PATH] = [0; winapi::shared::minwindef::MAX_PATH];
+   let mut size = buffer.len() as u32;
+   let result = unsafe { winapi::um::winbase::QueryFullProcessImageNameW(process, 0, buffer.as_mut_ptr(), &mut size) };
+   let query_error = if result == 0 {
+      Some(unsafe { winapi::um::errhandlingapi::GetLastError() })
+   } else {
+      None
+   };
+
+   unsafe {
+      winapi::um::handleapi::CloseHandle(process);
+   }
+
+   if let Some(code) = query_error {
+      return Err(ErrorCode(code));
+   }
+
+   let path = String::from_utf16_lossy(&buffer[..size as usize]);
+   Ok(path.rsplit('\\').next().unwrap_or(&path).to_owned())
+}
+
+/// The first visible top-level window whose title contains `title`, ignoring case
+pub fn find_window_by_title(title: &str) -> Option<NonNull<winapi::shared::windef::HWND__>> {
+   struct Search {
+      title: String,
+      found: Option<NonNull<winapi::shared::windef::HWND__>>,
+   }
+
+   unsafe extern "system" fn callback(
+      hwnd: winapi::shared::windef::HWND,
+      data: winapi::shared::minwindef::LPARAM,
+   ) -> winapi::shared::minwindef::BOOL {
+      let search = &mut *(data as *mut Search);
+      if winapi::um::winuser::IsWindowVisible(hwnd) == 0 {
+         return 1;
+      }
+      let mut buffer: [u16; 512] = [0; 512];
+      let len = winapi::um::winuser::GetWindowTextW(hwnd, buffer.as_mut_ptr(), buffer.len() as i32);
+      if len <= 0 {
+         return 1;
+      }
+      let window_title = String::from_utf16_lossy(&buffer[..len as usize]).to_lowercase();
+      if window_title.contains(&search.title) {
+         search.found = NonNull::new(hwnd);
+         // Stops the enumeration
+         return 0;
+      }
+      1
+   }
+
+   let mut search = Search {
+      title: title.to_lowercase(),
+      found: None,
+   };
+   // The result can't tell a failure apart from the callback stopping early, so it's ignored
+   unsafe {
+      winapi::um::winuser::EnumWindows(
+         Some(callback),
+         &mut search as *mut Search as winapi::shared::minwindef::LPARAM,
+      );
+   }
+   search.found
+}
+
+/// Restores the window if it's minimized and brings it to the front. Windows only allows this shortly after
+/// ripclip receives input, like a hotkey
+pub fn activate_window(hwnd: NonNull<winapi::shared::windef::HWND__>) -> Result<(), ()> {
+   unsafe {
+      if winapi::um::winuser::IsIconic(hwnd.as_ptr()) != 0 {
+         winapi::um::winuser::ShowWindow(hwnd.as_ptr(), winapi::um::winuser::SW_RESTORE);
+      }
+   }
+
+   let result = unsafe { winapi::um::winuser::SetForegroundWindow(hwnd.as_ptr()) };
+
+   if result == 0 {
+      return Err(());
+   }
+
+   Ok(())
+}
+
+/// Polls until none of the modifiers are held, so that keys sent afterwards aren't combined with them.
+/// Returns false if they're still held after `timeout`
+pub fn wait_for_modifiers_released(timeout: std::time::Duration) -> bool {
+   let modifiers = [
+      winapi::um::winuser::VK_CONTROL,
+      winapi::um::winuser::VK_MENU,
+      winapi::um::winuser::VK_SHIFT,
+      winapi::um::winuser::VK_LWIN,
+      winapi::um::winuser::VK_RWIN,
+   ];
+   let start = std::time::Instant::now();
+   loop {
+      // The high bit is set while the key is down
+      let held = modifiers
+         .iter()
+         .any(|&vk| unsafe { winapi::um::winuser::GetAsyncKeyState(vk) } as u16 & 0x8000 != 0);
+      if !held {
+         return true;
+      }
+      if start.elapsed() >= timeout {
+         return false;
+      }
+      std::thread::sleep(std::time::Duration::from_millis(10));
+   }
+}
+
+/// Presses Control + V in whichever window has the keyboard focus
+pub fn send_paste() -> Result<(), ErrorCode> {
+   fn key(vk: i32, up: bool) -> winapi::um::winuser::INPUT {
+      let mut input: winapi::um::winuser::INPUT = unsafe { mem::zeroed() };
+      input.type_ = winapi::um::winuser::INPUT_KEYBOARD;
+      unsafe {
+         let ki = input.u.ki_mut();
+         ki.wVk = vk as u16;
+         ki.dwFlags = if up { winapi::um::winuser::KEYEVENTF_KEYUP } else { 0 };
+      }
+      input
+   }
+
+   let mut inputs = [
+      key(winapi::um::winuser::VK_CONTROL, false),
+      key(VirtualKey::V as i32, false),
+      key(VirtualKey::V as i32, true),
+      key(winapi::um::winuser::VK_CONTROL, true),
+   ];
+   let sent = unsafe {
+      winapi::um::winuser::SendInput(
+         inputs.len() as u32,
+         inputs.as_mut_ptr(),
+         mem::size_of::<winapi::um::winuser::INPUT>() as i32,
+      )
+   };
+
+   // Zero means another thread blocked the input
+   if sent != inputs.len() as u32 {
+      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+      return Err(ErrorCode(code));
+   }
+
+   Ok(())
+}
+
 pub fn open_clipboard(hwnd: &WindowHandle) -> Result<ClipboardHandle, ErrorCode> {
    let result = unsafe { winapi::um::winuser::OpenClipboard(hwnd.inner.as_ptr()) };
 
@@ -773,6 +2053,9 @@ pub fn open_clipboard(hwnd: &WindowHandle) -> Result<ClipboardHandle, ErrorCode>
       return Err(ErrorCode(code));
    }
 
+   let already_open = CLIPBOARD_OPEN.swap(true, Ordering::SeqCst);
+   debug_assert!(!already_open, "Opened the clipboard while it was already open");
+
    Ok(ClipboardHandle { _inner: () })
 }
 
@@ -799,6 +2082,35 @@ impl From<winapi::um::winuser::MSG> for Message {
    }
 }
 
+/// For modal loops, which have to pass along the messages they don't handle themselves
+pub fn dispatch_message(message: &Message) {
+   let msg = winapi::um::winuser::MSG {
+      hwnd: message.hwnd.map_or(ptr::null_mut(), |x| x.as_ptr()),
+      message: message.message,
+      wParam: message.w_param,
+      lParam: message.l_param,
+      time: 0,
+      pt: winapi::shared::windef::POINT { x: 0, y: 0 },
+   };
+   unsafe {
+      // Turns key presses into the WM_CHAR messages that controls expect
+      winapi::um::winuser::TranslateMessage(&msg);
+      winapi::um::winuser::DispatchMessageW(&msg);
+   }
+}
+
+pub fn get_cursor_pos() -> Result<(i32, i32), ErrorCode> {
+   let mut point = winapi::shared::windef::POINT { x: 0, y: 0 };
+   let result = unsafe { winapi::um::winuser::GetCursorPos(&mut point) };
+
+   if result == 0 {
+      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+      return Err(ErrorCode(code));
+   }
+
+   Ok((point.x, point.y))
+}
+
 pub fn get_message(hwnd: Option<&WindowHandle>, min_value: u32, max_value: u32) -> Result<Message, ErrorCode> {
    let mut message: MaybeUninit<winapi::um::winuser::MSG> = MaybeUninit::uninit();
    let result = unsafe {
@@ -823,6 +2135,143 @@ pub struct TrayIcon<'a> {
    hwnd: &'a WindowHandle<'a>,
 }
 
+/// An image for the tray icon
+pub enum Icon<'a> {
+   /// The icon compiled into the executable
+   Application(&'a ModuleHandle),
+   /// An .ico file, or the icon compiled into the executable if the file can't be loaded
+   File(&'a Path, &'a ModuleHandle),
+   /// The system's warning icon
+   Warning,
+}
+
+/// Destroys the icon when dropped, if it needs destroying. The tray keeps its own copy of the icon
+struct LoadedIcon {
+   handle: winapi::shared::windef::HICON,
+   owned: bool,
+}
+
+impl Drop for LoadedIcon {
+   fn drop(&mut self) {
+      if self.owned {
+         unsafe {
+            winapi::um::winuser::DestroyIcon(self.handle);
+         }
+      }
+   }
+}
+
+fn load_icon(icon: Icon) -> Result<LoadedIcon, ErrorCode> {
+   let (handle, owned) = unsafe {
+      match icon {
+         Icon::Application(module) => (
+            winapi::um::winuser::LoadIconW(module.0.as_ptr(), 1usize as *const u16),
+            false,
+         ),
+         Icon::File(path, module) => {
+            let path: Vec<u16> = path.as_os_str().encode_wide().chain(iter::once(0)).collect();
+            // The tray shows small icons, so the file's small image is the one to pick
+            let handle = winapi::um::winuser::LoadImageW(
+               ptr::null_mut(),
+               path.as_ptr(),
+               winapi::um::winuser::IMAGE_ICON,
+               winapi::um::winuser::GetSystemMetrics(winapi::um::winuser::SM_CXSMICON),
+               winapi::um::winuser::GetSystemMetrics(winapi::um::winuser::SM_CYSMICON),
+               winapi::um::winuser::LR_LOADFROMFILE,
+            );
+            if handle.is_null() {
+               let code = winapi::um::errhandlingapi::GetLastError();
+               warn!(
+                  "Failed to load tray icon from file, using the default icon: {}",
+                  ErrorCode(code)
+               );
+               return load_icon(Icon::Application(module));
+            }
+            (handle as winapi::shared::windef::HICON, true)
+         }
+         Icon::Warning => (
+            winapi::um::winuser::LoadIconW(ptr::null_mut(), winapi::um::winuser::IDI_WARNING),
+            false,
+         ),
+      }
+   };
+
+   if handle.is_null() {
+      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+      return Err(ErrorCode(code));
+   }
+
+   Ok(LoadedIcon { handle, owned })
+}
+
+impl<'a> TrayIcon<'a> {
+   pub fn set_icon(&self, icon: Icon) -> Result<(), ErrorCode> {
+      let mut icon_options = self.notify_icon_data();
+      icon_options.uFlags = winapi::um::shellapi::NIF_ICON;
+      let icon = load_icon(icon)?;
+      icon_options.hIcon = icon.handle;
+
+      let result =
+         unsafe { winapi::um::shellapi::Shell_NotifyIconW(winapi::um::shellapi::NIM_MODIFY, &mut icon_options) };
+      if result == 0 {
+         let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+         return Err(ErrorCode(code));
+      }
+
+      Ok(())
+   }
+
+   /// Truncates the tooltip to 127 UTF-16 characters
+   pub fn set_tooltip(&self, tooltip: &str) -> Result<(), ErrorCode> {
+      let mut tooltip_options = self.notify_icon_data();
+      tooltip_options.uFlags = winapi::um::shellapi::NIF_TIP | winapi::um::shellapi::NIF_SHOWTIP;
+      copy_truncated_utf16(tooltip, &mut tooltip_options.szTip);
+
+      let result =
+         unsafe { winapi::um::shellapi::Shell_NotifyIconW(winapi::um::shellapi::NIM_MODIFY, &mut tooltip_options) };
+      if result == 0 {
+         let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+         return Err(ErrorCode(code));
+      }
+
+      Ok(())
+   }
+
+   /// Truncates the title and text to 63 and 255 UTF-16 characters respectively
+   pub fn show_balloon(&self, title: &str, text: &str) -> Result<(), ErrorCode> {
+      let mut balloon_options = self.notify_icon_data();
+      balloon_options.uFlags = winapi::um::shellapi::NIF_INFO;
+      balloon_options.dwInfoFlags = winapi::um::shellapi::NIIF_WARNING;
+      copy_truncated_utf16(title, &mut balloon_options.szInfoTitle);
+      copy_truncated_utf16(text, &mut balloon_options.szInfo);
+
+      let result =
+         unsafe { winapi::um::shellapi::Shell_NotifyIconW(winapi::um::shellapi::NIM_MODIFY, &mut balloon_options) };
+      if result == 0 {
+         let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+         return Err(ErrorCode(code));
+      }
+
+      Ok(())
+   }
+
+   fn notify_icon_data(&self) -> winapi::um::shellapi::NOTIFYICONDATAW {
+      let mut notify_icon_data: winapi::um::shellapi::NOTIFYICONDATAW = unsafe { mem::zeroed() };
+      notify_icon_data.cbSize = mem::size_of::<winapi::um::shellapi::NOTIFYICONDATAW>() as u32;
+      notify_icon_data.hWnd = self.hwnd.inner.as_ptr();
+      notify_icon_data.uID = self.id;
+      notify_icon_data
+   }
+}
+
+/// Copies as much of the string as fits while leaving room for a null terminator
+fn copy_truncated_utf16(s: &str, buffer: &mut [u16]) {
+   let max_len = buffer.len() - 1;
+   for (dest, utf16_char) in buffer.iter_mut().zip(s.encode_utf16().take(max_len)) {
+      *dest = utf16_char;
+   }
+}
+
 impl<'a> Drop for TrayIcon<'a> {
    fn drop(&mut self) {
       remove_tray_icon(self).unwrap();
@@ -866,19 +2315,19 @@ fn remove_tray_icon<'a>(tray_icon: &mut TrayIcon<'a>) -> Result<(), ErrorCode> {
 
 /// Truncates any tooltip ecxeeding 254 bytes (when encoded as UTF-16)
 /// (that can result in corrupted unicode... don't pass long strings!)
+/// Uses the icon at `icon_path` if there is one and it can be loaded, otherwise the icon compiled into the executable
 pub fn add_tray_icon<'a>(
    hwnd: &'a WindowHandle,
    id: u32,
    callback_id: u32,
    tooltip: &str,
    module_hande: &ModuleHandle,
+   icon_path: Option<&Path>,
 ) -> Result<TrayIcon<'a>, ErrorCode> {
-   let icon = unsafe { winapi::um::winuser::LoadIconW(module_hande.0.as_ptr(), 1usize as *const u16) };
-
-   if icon.is_null() {
-      let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
-      return Err(ErrorCode(code));
-   }
+   let icon = match icon_path {
+      Some(path) => load_icon(Icon::File(path, module_hande))?,
+      None => load_icon(Icon::Application(module_hande))?,
+   };
 
    let mut tooltip_text: [u16; 128] = [0; 128];
 
@@ -900,7 +2349,7 @@ pub fn add_tray_icon<'a>(
          | winapi::um::shellapi::NIF_TIP
          | winapi::um::shellapi::NIF_SHOWTIP,
       uCallbackMessage: callback_id,
-      hIcon: icon,
+      hIcon: icon.handle,
       szTip: tooltip_text,
       dwState: 0,
       dwStateMask: 0,
@@ -934,3 +2383,162 @@ pub fn add_tray_icon<'a>(
 
    Ok(TrayIcon { id, hwnd })
 }
+
+/// A directory opened to wait for changes in. Closed when dropped
+pub struct DirectoryWatcher(winapi::um::winnt::HANDLE);
+
+// Nothing else holds the handle, so the watcher can be handed to the thread that waits on it
+unsafe impl Send for DirectoryWatcher {}
+
+impl DirectoryWatcher {
+   pub fn open(path: &Path) -> Result<DirectoryWatcher, ErrorCode> {
+      let path: Vec<u16> = path.as_os_str().encode_wide().chain(iter::once(0)).collect();
+      let handle = unsafe {
+         winapi::um::fileapi::CreateFileW(
+            path.as_ptr(),
+            winapi::um::winnt::FILE_LIST_DIRECTORY,
+            winapi::um::winnt::FILE_SHARE_READ
+               | winapi::um::winnt::FILE_SHARE_WRITE
+               | winapi::um::winnt::FILE_SHARE_DELETE,
+            ptr::null_mut(),
+            winapi::um::fileapi::OPEN_EXISTING,
+            // Required to open a directory
+            winapi::um::winbase::FILE_FLAG_BACKUP_SEMANTICS,
+            ptr::null_mut(),
+         )
+      };
+
+      if handle == winapi::um::handleapi::INVALID_HANDLE_VALUE {
+         let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+         return Err(ErrorCode(code));
+      }
+
+      Ok(DirectoryWatcher(handle))
+   }
+
+   /// Blocks until files in the directory are created, renamed, deleted or written to, and returns their names.
+   /// The names are empty if there were too many changes to list
+   pub fn wait_for_changes(&self) -> Result<Vec<String>, ErrorCode> {
+      // u32s, since the buffer has to be DWORD aligned
+      let mut buffer = [0u32; 1024];
+      let mut bytes_returned: u32 = 0;
+      let result = unsafe {
+         winapi::um::winbase::ReadDirectoryChangesW(
+            self.0,
+            buffer.as_mut_ptr() as *mut _,
+            (buffer.len() * mem::size_of::<u32>()) as u32,
+            0,
+            winapi::um::winnt::FILE_NOTIFY_CHANGE_FILE_NAME
+               | winapi::um::winnt::FILE_NOTIFY_CHANGE_LAST_WRITE
+               | winapi::um::winnt::FILE_NOTIFY_CHANGE_SIZE,
+            &mut bytes_returned,
+            ptr::null_mut(),
+            None,
+         )
+      };
+
+      if result == 0 {
+         let code = unsafe { winapi::um::errhandlingapi::GetLastError() };
+         return Err(ErrorCode(code));
+      }
+
+      let mut names = Vec::new();
+      if bytes_returned == 0 {
+         return Ok(names);
+      }
+      let mut offset = 0;
+      loop {
+         let info = unsafe {
+            &*((buffer.as_ptr() as *const u8).add(offset) as *const winapi::um::winnt::FILE_NOTIFY_INFORMATION)
+         };
+         // FileNameLength is in bytes, and the name isn't null terminated
+         let name = unsafe { slice::from_raw_parts(info.FileName.as_ptr(), info.FileNameLength as usize / 2) };
+         names.push(String::from_utf16_lossy(name));
+         if info.NextEntryOffset == 0 {
+            break;
+         }
+         offset += info.NextEntryOffset as usize;
+      }
+      Ok(names)
+   }
+}
+
+impl Drop for DirectoryWatcher {
+   fn drop(&mut self) {
+      unsafe {
+         winapi::um::handleapi::CloseHandle(self.0);
+      }
+   }
+}
+
+#[cfg(test)]
+mod test {
+   use super::*;
+
+   #[test]
+   fn describes_errors() {
+      let description = ERROR_ACCESS_DENIED.get_description();
+      assert!(!description.trim().is_empty());
+      assert!(!description.starts_with("Unknown error"));
+
+      // ERROR_WRONG_DISK, whose message has inserts
+      let description = ErrorCode(34).get_description();
+      assert!(!description.trim().is_empty());
+      assert!(!description.starts_with("Unknown error"));
+
+      let unknown = ErrorCode(0xDEAD_BEEF);
+      assert_eq!(unknown.get_description(), "Unknown error 0xdeadbeef");
+      assert_eq!(unknown.to_string(), "Unknown error 0xdeadbeef");
+   }
+
+   #[test]
+   fn text_ignores_trailing_nulls() {
+      let text = ClipboardText::from_str("abc");
+      assert!(ClipboardText::from_str("abc\0") == text);
+      assert!(ClipboardText::from_str("abc\0\0") == text);
+      assert_eq!(text.as_bytes().len(), 8);
+      assert!(ClipboardText::from_str("abc ") != text);
+      // Nulls before the end are part of the text
+      assert!(ClipboardText::from_str("ab\0c") != text);
+      assert!(ClipboardText::from_utf16(&[0, 0]) == ClipboardText::from_str(""));
+   }
+
+   #[test]
+   fn clipboard_text_ends_at_the_first_null() {
+      let bytes: Vec<u8> = [0x61u16, 0x62, 0, 0x7a, 0, 0]
+         .iter()
+         .flat_map(|c| c.to_ne_bytes())
+         .collect();
+      let text = ClipboardText::from_clipboard_bytes(&bytes).unwrap();
+      assert!(text == ClipboardText::from_str("ab"));
+      assert!(ClipboardText::from_clipboard_bytes(&bytes[..5]).is_err());
+      assert!(ClipboardText::from_clipboard_bytes(&[]).unwrap() == ClipboardText::from_str(""));
+   }
+
+   #[test]
+   fn displays_text() {
+      for s in ["", "abc", "two\r\nlines", "caf\u{e9} \u{1f4cb}"].iter() {
+         assert_eq!(ClipboardText::from_str(s).to_string(), *s);
+         assert_eq!(ClipboardText::from_str(s).as_string().unwrap(), *s);
+      }
+      // Stops at the first null
+      assert_eq!(ClipboardText::from_str("ab\0c").to_string(), "ab");
+      // An unpaired surrogate
+      let text = ClipboardText::from_utf16(&[0x61, 0xD800, 0x62]);
+      assert_eq!(text.to_string(), "a\u{fffd}b");
+      assert!(text.as_string().is_err());
+   }
+
+   #[test]
+   fn reads_image_size() {
+      let mut header = vec![0; 40];
+      header[0..4].copy_from_slice(&40u32.to_le_bytes());
+      header[4..8].copy_from_slice(&640i32.to_le_bytes());
+      // Top-down
+      header[8..12].copy_from_slice(&(-480i32).to_le_bytes());
+      assert_eq!(ClipboardEntry::Image(header).image_size(), Some((640, 480)));
+      assert_eq!(ClipboardEntry::Image(vec![0; 8]).image_size(), None);
+      let text = ClipboardText::from_utf16(&[]);
+      assert_eq!(ClipboardEntry::Text(text, None).image_size(), None);
+   }
+}